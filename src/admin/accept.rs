@@ -25,7 +25,6 @@ use serde::{
 use serde_json::{
     json,
     Value,
-    Value::Null,
 };
 
 use std::{
@@ -41,7 +40,13 @@ use sled::Db;
 
 use crate::{
     admin::methods::execute_method,
-    balancer::format::incoming_to_value,
+    balancer::{
+        format::incoming_to_value,
+        response_errors::{
+            ErrorKind,
+            JsonRpcError,
+        },
+    },
     Rpc,
     Settings,
 };
@@ -60,6 +65,8 @@ struct Claims {
 ///
 /// Since we don't cache the admin request responses, this functions
 /// quite differently from the one you'll find in `blutgang/balancer/accept_http.rs`
+///
+/// Evaluates to a `Result<String, JsonRpcError>`.
 macro_rules! get_response {
     (
         $tx:expr,
@@ -70,27 +77,22 @@ macro_rules! get_response {
         $cache:expr,
     ) => {{
         // Execute the request and store it into rx
-        let mut rx = match execute_method(
+        match execute_method(
             $tx,
             $rpc_list_rwlock,
             $poverty_list_rwlock,
             Arc::clone(&$config),
             $cache.clone(),
-        ).await {
-            Ok(rx) => rx,
-            Err(err) => json!({
-                "id": Null,
-                "jsonrpc": "2.0",
-                "result": err.to_string(),
-            }),
-        };
-
-        // Set the id to whatever it was
-        rx["id"] = $id.into();
-
-        let rx_str = rx.to_string();
-
-        rx_str
+        )
+        .await
+        {
+            Ok(mut rx) => {
+                // Set the id to whatever it was
+                rx["id"] = $id;
+                Ok(rx.to_string())
+            }
+            Err(err) => Err(JsonRpcError::new(err.kind(), $id).with_data(err.to_string())),
+        }
     }};
 }
 
@@ -107,10 +109,16 @@ async fn forward_body(
     // We're doing this ID gymnastics because we're hashing the
     // whole request and we don't want the ID as it's arbitrary
     // and does not impact the request result.
-    let id = tx["id"].take().as_u64().unwrap_or(0);
+    let id = tx["id"].take();
 
     // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let rax = get_response!(tx, id, rpc_list_rwlock, poverty_list_rwlock, config, cache,);
+    let rax = match get_response!(tx, id, rpc_list_rwlock, poverty_list_rwlock, config, cache,) {
+        Ok(rax) => rax,
+        Err(err) => {
+            let error_status = config.read().unwrap().error_status;
+            return err.into_response(&error_status);
+        }
+    };
 
     // Convert rx to bytes and but it in a Buf
     let body = hyper::body::Bytes::from(rax);
@@ -139,7 +147,13 @@ pub async fn accept_admin_request(
         return accept_health_request(liveness_request_tx).await;
     }
 
-    let mut tx = incoming_to_value(tx).await.unwrap();
+    let mut tx = match incoming_to_value(tx).await {
+        Ok(tx) => tx,
+        Err(kind) => {
+            let error_status = config.read().unwrap().error_status;
+            return JsonRpcError::new(kind, Value::Null).into_response(&error_status);
+        }
+    };
 
     // If we have JWT enabled check that tx is valid
     if config.read().unwrap().admin.jwt {
@@ -154,10 +168,9 @@ pub async fn accept_admin_request(
             Ok(token) => token,
             Err(err) => {
                 println!("\x1b[31mJWT Auth error:\x1b[0m {}", err);
-                return Ok(hyper::Response::builder()
-                    .status(401)
-                    .body(Full::new(Bytes::from("Unauthorized or invalid token")))
-                    .unwrap());
+                let error_status = config.read().unwrap().error_status;
+                return JsonRpcError::new(ErrorKind::Unauthorized, tx["id"].take())
+                    .into_response(&error_status);
             }
         };
 
@@ -184,12 +197,15 @@ pub async fn accept_admin_request(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use http_body_util::BodyExt;
     use jsonwebtoken::DecodingKey;
 
     // Helper function to create a test Settings config
     fn create_test_settings() -> Arc<RwLock<Settings>> {
-        let mut config = Settings::default();
-        config.do_clear = true;
+        let mut config = Settings {
+            do_clear: true,
+            ..Default::default()
+        };
         config.admin.key = DecodingKey::from_secret(b"some-key");
         Arc::new(RwLock::new(config))
     }
@@ -231,4 +247,39 @@ mod tests {
 
         // Additional assertions can be added based on expected behavior
     }
+
+    #[tokio::test]
+    async fn test_forward_body_error() {
+        let settings = create_test_settings();
+        let cache = create_test_cache();
+        let rpc_list = Arc::new(RwLock::new(vec![]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        let tx = json!({
+            "id": "admin-1",
+            "jsonrpc": "2.0",
+            "method": "blutgang_does_not_exist",
+            "params": [],
+        });
+
+        let result = forward_body(tx, &rpc_list, &poverty_list, cache, settings)
+            .await
+            .unwrap();
+        assert_eq!(result.status(), 200);
+
+        let body = result.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "id": "admin-1",
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32601,
+                    "message": "Method not found",
+                    "data": "Requested method does not exist",
+                },
+            })
+        );
+    }
 }
@@ -1,5 +1,6 @@
 //! Admin specific errors
 
+use crate::balancer::response_errors::ErrorKind;
 use std::error::Error;
 
 #[derive(Debug)]
@@ -17,6 +18,24 @@ pub enum AdminError {
     InvalidResponse(String),
 }
 
+impl AdminError {
+    /// JSON-RPC error this gets reported as.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            AdminError::InvalidMethod => ErrorKind::MethodNotFound,
+            AdminError::InvalidParams
+            | AdminError::InvalidLen
+            | AdminError::ParseError
+            | AdminError::OutOfBounds => ErrorKind::InvalidParams,
+            AdminError::WriteProtectionEnabled => ErrorKind::ReadOnly,
+            AdminError::InvalidSecret => ErrorKind::Unauthorized,
+            AdminError::RwError | AdminError::Inaccessible | AdminError::InvalidResponse(_) => {
+                ErrorKind::InternalError
+            }
+        }
+    }
+}
+
 impl std::fmt::Display for AdminError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
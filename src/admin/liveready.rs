@@ -307,10 +307,6 @@ mod tests {
             .unwrap();
 
         // No assertion here as we're testing the sink's ability to simply discard incoming messages
-        assert!(
-            true,
-            "Successfully discarded updates without affecting the test flow"
-        );
     }
 
     #[tokio::test]
@@ -1,10 +1,19 @@
 use crate::{
     admin::error::AdminError,
+    balancer::selection::strategy::{
+        strategy_from_name,
+        strategy_names,
+    },
+    rpc::{
+        breaker::CircuitBreaker,
+        latency::LatencyMetric,
+    },
     Rpc,
     Settings,
 };
 
 use std::{
+    collections::BTreeMap,
     sync::{
         Arc,
         RwLock,
@@ -18,6 +27,7 @@ use serde_json::{
     Value::Null,
 };
 
+use chrono::Utc;
 use sled::Db;
 
 /// Extract the method, call the appropriate function and return the response
@@ -51,6 +61,10 @@ pub async fn execute_method(
             }
         }
         Some("blutgang_config") => admin_config(config),
+        Some("blutgang_tiers") => admin_tiers(rpc_list, poverty_list, config),
+        Some("blutgang_budgets") => admin_budgets(rpc_list, poverty_list),
+        Some("blutgang_cache_rules") => admin_cache_rules(config),
+        Some("blutgang_cache_stats") => admin_cache_stats(config),
         Some("blutgang_poverty_list") => admin_list_rpc(poverty_list),
         Some("blutgang_ttl") => admin_blutgang_ttl(config),
         Some("blutgang_health_check_ttl") => admin_blutgang_health_check_ttl(config),
@@ -68,32 +82,48 @@ pub async fn execute_method(
                 admin_blutgang_set_health_check_ttl(config, tx["params"].as_array())
             }
         }
+        Some("blutgang_selection_strategy") => admin_blutgang_selection_strategy(config),
+        Some("blutgang_set_selection_strategy") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_blutgang_set_selection_strategy(config, tx["params"].as_array())
+            }
+        }
         Some("blutgang_add_to_rpc_list") => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_add_rpc(rpc_list, tx["params"].as_array())
+                let rx = admin_add_rpc(rpc_list, tx["params"].as_array(), &config);
+                update_top_tier(rpc_list, poverty_list, &config);
+                rx
             }
         }
         Some("blutgang_add_to_poverty_list") => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_add_rpc(poverty_list, tx["params"].as_array())
+                let rx = admin_add_rpc(poverty_list, tx["params"].as_array(), &config);
+                update_top_tier(rpc_list, poverty_list, &config);
+                rx
             }
         }
         Some("blutgang_remove_from_rpc_list") => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_remove_rpc(rpc_list, tx["params"].as_array())
+                let rx = admin_remove_rpc(rpc_list, tx["params"].as_array());
+                update_top_tier(rpc_list, poverty_list, &config);
+                rx
             }
         }
         Some("blutgang_remove_from_poverty_list") => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_remove_rpc(poverty_list, tx["params"].as_array())
+                let rx = admin_remove_rpc(poverty_list, tx["params"].as_array());
+                update_top_tier(rpc_list, poverty_list, &config);
+                rx
             }
         }
         Some(_) => Err(AdminError::InvalidMethod),
@@ -101,6 +131,22 @@ pub async fn execute_method(
     }
 }
 
+/// Recompute the highest tier we have RPCs in after adding or removing one
+fn update_top_tier(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    config: &Arc<RwLock<Settings>>,
+) {
+    let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+    let poverty_list = poverty_list.read().unwrap_or_else(|e| e.into_inner());
+    config
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .rate_limit
+        .serving_tier
+        .set_top(rpc_list.iter().chain(poverty_list.iter()));
+}
+
 /// Quit Blutgang upon receiving this method
 /// We're returning a Null and allowing unreachable code so rustc doesnt cry
 #[allow(unreachable_code)]
@@ -148,6 +194,8 @@ fn admin_config(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
             },
             "ttl": guard.ttl,
             "health_check_ttl": guard.health_check_ttl,
+            "selection_strategy": guard.selection.name(),
+            "latency_metric": guard.latency_metric.name(),
         },
     });
 
@@ -160,26 +208,186 @@ fn admin_list_rpc(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> Result<Value, AdminError>
     // Read the RPC list, handling errors
     let rpc_list = rpc_list.read().map_err(|_| AdminError::Inaccessible)?;
 
-    // Prepare a formatted string for the RPC list
-    let mut rpc_list_str = String::new();
-    rpc_list_str.push('[');
+    // Build an entry for each RPC, so names and the like get escaped
+    let rpcs: Vec<Value> = rpc_list
+        .iter()
+        .map(|rpc| {
+            let latency = rpc.status.latency_stats();
+            json!({
+                "name": rpc.name.to_string(),
+                "max_consecutive": rpc.max_consecutive,
+                "last_error": rpc.status.last_error(),
+                "failures": rpc.status.failures(),
+                "last_failure": rpc.status.last_failure().map_or("none", |class| class.name()),
+                "breaker": rpc.status.breaker_state().to_string(),
+                "in_flight": rpc.in_flight(),
+                "tier": rpc.tier,
+                "spilled": rpc.status.spilled(),
+                "throttled": rpc.status.throttled(),
+                "hedged": rpc.status.hedged(),
+                "hedge_wins": rpc.status.hedge_wins(),
+                "latency_ns": {
+                    "mean": latency.mean.round() as u64,
+                    "p50": latency.p50.round() as u64,
+                    "p90": latency.p90.round() as u64,
+                    "p99": latency.p99.round() as u64,
+                },
+            })
+        })
+        .collect();
+
+    // The list is returned as a string, like it always was
+    let rpc_list_str = Value::Array(rpcs).to_string();
 
-    // Iterate over the RPC list and format each RPC
-    for rpc in rpc_list.iter() {
-        rpc_list_str.push_str(&format!(
-            "{{\"name\": \"{}\", \"max_consecutive\": {}, \"last_error\": {}}}",
-            rpc.name, rpc.max_consecutive, rpc.status.last_error
-        ));
+    // Create a JSON response
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": rpc_list_str,
+    });
+
+    Ok(rx)
+}
+
+/// Returns the RPCs in each tier, and the tier currently serving requests
+fn admin_tiers(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    config: Arc<RwLock<Settings>>,
+) -> Result<Value, AdminError> {
+    let serving_tier = config
+        .read()
+        .map_err(|_| AdminError::Inaccessible)?
+        .rate_limit
+        .serving_tier
+        .get();
+
+    let mut tiers: BTreeMap<u32, (Vec<String>, Vec<String>)> = BTreeMap::new();
+
+    for rpc in rpc_list
+        .read()
+        .map_err(|_| AdminError::Inaccessible)?
+        .iter()
+    {
+        tiers
+            .entry(rpc.tier)
+            .or_default()
+            .0
+            .push(rpc.name.to_string());
+    }
+    for rpc in poverty_list
+        .read()
+        .map_err(|_| AdminError::Inaccessible)?
+        .iter()
+    {
+        tiers
+            .entry(rpc.tier)
+            .or_default()
+            .1
+            .push(rpc.name.to_string());
     }
 
-    // Complete the formatted RPC list string
-    rpc_list_str.push(']');
+    let tiers: Vec<Value> = tiers
+        .into_iter()
+        .map(|(tier, (active, poverty))| {
+            json!({
+                "tier": tier,
+                "rpcs": active,
+                "poverty": poverty,
+            })
+        })
+        .collect();
 
-    // Create a JSON response
     let rx = json!({
         "id": Null,
         "jsonrpc": "2.0",
-        "result": rpc_list_str,
+        "result": {
+            "serving_tier": serving_tier,
+            "tiers": tiers,
+        },
+    });
+
+    Ok(rx)
+}
+
+/// Returns how much of their compute unit budget RPCs that have one have left.
+fn admin_budgets(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Result<Value, AdminError> {
+    let now = Utc::now();
+    let mut budgets = Vec::new();
+
+    for (list, poverty) in [(rpc_list, false), (poverty_list, true)] {
+        for rpc in list.read().map_err(|_| AdminError::Inaccessible)?.iter() {
+            if let Some(budget) = &rpc.budget {
+                budgets.push(json!({
+                    "name": budget.key,
+                    "url": rpc.name.to_string(),
+                    "poverty": poverty,
+                    "window": budget.window.name(),
+                    "limit": budget.limit,
+                    "used": budget.used(now),
+                    "remaining": budget.remaining(now),
+                    "resets_at": budget.window.end(now).to_rfc3339(),
+                }));
+            }
+        }
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": budgets,
+    });
+
+    Ok(rx)
+}
+
+/// Returns the rule deciding when each method gets cached.
+/// Methods not in here are never cached.
+fn admin_cache_rules(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
+    let cache_rules = config
+        .read()
+        .map_err(|_| AdminError::Inaccessible)?
+        .cache_rules
+        .to_value();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": cache_rules,
+    });
+
+    Ok(rx)
+}
+
+/// Returns hit and miss counts of the in-memory TTL and head caches.
+fn admin_cache_stats(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
+    let (ttl_cache, latest_cache) = {
+        let config_guard = config.read().map_err(|_| AdminError::Inaccessible)?;
+        (
+            config_guard.ttl_cache.clone(),
+            config_guard.latest_cache.clone(),
+        )
+    };
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "ttl": {
+                "entries": ttl_cache.entries(),
+                "hits": ttl_cache.hits(),
+                "misses": ttl_cache.misses(),
+            },
+            "latest": {
+                "block": latest_cache.block(),
+                "entries": latest_cache.entries(),
+                "hits": latest_cache.hits(),
+                "misses": latest_cache.misses(),
+            },
+        },
     });
 
     Ok(rx)
@@ -187,19 +395,22 @@ fn admin_list_rpc(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> Result<Value, AdminError>
 
 /// Pushes an RPC to the end of the list:
 /// - param[0] - RPC url
-/// - param[1] - max_consecutive
-/// - param[2] - ma_len
-/// - param[3] - ma_len
+/// - param[1] - ws_url, can be null
+/// - param[2] - max_consecutive
+/// - param[3] - max_per_second
+/// - param[4] - ma_len
+/// - param[5] - tier, optional
 fn admin_add_rpc(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     params: Option<&Vec<Value>>,
+    config: &Arc<RwLock<Settings>>,
 ) -> Result<Value, AdminError> {
     let params = match params {
         Some(params) => params,
         None => return Err(AdminError::InvalidParams),
     };
 
-    if params.len() != 5 {
+    if params.len() != 5 && params.len() != 6 {
         return Err(AdminError::InvalidLen);
     }
 
@@ -235,24 +446,37 @@ fn admin_add_rpc(
         .parse::<f64>()
         .unwrap_or(0.0);
 
-    if delta != 0 {
-        delta = 1_000_000 / delta;
-    }
+    let tier = match params.get(5) {
+        Some(tier) => {
+            match tier.to_string().replace('\"', "").parse::<u32>() {
+                Ok(tier) => tier,
+                Err(_) => return Err(AdminError::ParseError),
+            }
+        }
+        None => 0,
+    };
 
-    let mut rpc_list = rpc_list.write().map_err(|_| AdminError::Inaccessible)?;
+    delta = 1_000_000u64.checked_div(delta).unwrap_or(0);
 
-    rpc_list.push(Rpc::new(
+    let mut new_rpc = Rpc::new(
         rpc.to_string(),
         ws_url,
         max_consecutive,
         delta.into(),
         ma_len,
+    );
+    new_rpc.tier = tier;
+    new_rpc.status.set_breaker(CircuitBreaker::new(
+        config.read().map_err(|_| AdminError::Inaccessible)?.breaker,
     ));
 
+    let mut rpc_list = rpc_list.write().map_err(|_| AdminError::Inaccessible)?;
+    rpc_list.push(new_rpc);
+
     let rx = json!({
         "id": Null,
         "jsonrpc": "2.0",
-        "result": format!("RPC: {}, max_consecutive: {}, ma: {}", rpc, max_consecutive, ma_len),
+        "result": format!("RPC: {}, max_consecutive: {}, ma: {}, tier: {}", rpc, max_consecutive, ma_len, tier),
     });
 
     Ok(rx)
@@ -291,7 +515,7 @@ fn admin_remove_rpc(
     let rx = json!({
         "id": Null,
         "jsonrpc": "2.0",
-        "result": removed.name,
+        "result": removed.name.to_string(),
     });
 
     Ok(rx)
@@ -387,9 +611,77 @@ fn admin_blutgang_set_ttl(
     Ok(rx)
 }
 
+/// Returns the active selection strategy, and all the ones we can switch to
+fn admin_blutgang_selection_strategy(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
+    let guard = config.read().unwrap();
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "strategy": guard.selection.name(),
+            "latency_metric": guard.latency_metric.name(),
+            "available": strategy_names(),
+        },
+    });
+
+    Ok(rx)
+}
+
+/// Sets the selection strategy:
+/// - param[0] - name of the strategy
+/// - param[1] - latency metric it should use, optional
+fn admin_blutgang_set_selection_strategy(
+    config: Arc<RwLock<Settings>>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.is_empty() || params.len() > 2 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let mut guard = config.write().unwrap();
+
+    let latency_metric = match params.get(1) {
+        Some(metric) => {
+            match metric.as_str().and_then(LatencyMetric::from_name) {
+                Some(metric) => metric,
+                None => return Err(AdminError::InvalidParams),
+            }
+        }
+        None => guard.latency_metric,
+    };
+
+    let strategy = match params[0]
+        .as_str()
+        .and_then(|name| strategy_from_name(name, latency_metric))
+    {
+        Some(strategy) => strategy,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    guard.selection = strategy;
+    guard.latency_metric = latency_metric;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": guard.selection.name(),
+    });
+
+    Ok(rx)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rpc::budget::{
+        BudgetWindow,
+        ComputeBudget,
+    };
     use jsonwebtoken::DecodingKey;
 
     // Helper function to create a test RPC list
@@ -416,8 +708,10 @@ mod tests {
 
     // Helper function to create a test Settings config
     fn create_test_settings_config() -> Arc<RwLock<Settings>> {
-        let mut config = Settings::default();
-        config.do_clear = true;
+        let mut config = Settings {
+            do_clear: true,
+            ..Default::default()
+        };
         config.admin.key = DecodingKey::from_secret(b"some-key");
         Arc::new(RwLock::new(config))
     }
@@ -434,11 +728,13 @@ mod tests {
         // Arrange
         let cache = create_test_cache();
         let tx = json!({ "id":1,"method": "blutgang_rpc_list" });
+        let rpc_list = create_test_rpc_list();
+        rpc_list.read().unwrap()[0].update_latency(1500.0);
 
         // Act
         let result = execute_method(
             tx,
-            &create_test_rpc_list(),
+            &rpc_list,
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache,
@@ -446,7 +742,12 @@ mod tests {
         .await;
 
         // Assert
-        assert!(result.is_ok());
+        let result: Value =
+            serde_json::from_str(result.unwrap()["result"].as_str().unwrap()).unwrap();
+        assert_eq!(result[0]["latency_ns"]["mean"], 1500);
+        assert_eq!(result[0]["latency_ns"]["p99"], 1500);
+        assert_eq!(result[0]["last_failure"], "none");
+        assert_eq!(result[0]["tier"], 0);
     }
 
     #[tokio::test]
@@ -593,6 +894,188 @@ mod tests {
         assert!(rpc_list.read().unwrap().len() == len + 1);
     }
 
+    #[tokio::test]
+    async fn test_execute_method_blutgang_tiers() {
+        // Arrange
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let tx = json!({ "id":1,"method": "blutgang_add_to_rpc_list", "params": ["http://backup.com", Null, 5, 10, 0.5, 1] });
+        execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            create_test_cache(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(rpc_list.read().unwrap()[1].tier, 1);
+
+        // Act
+        let tx = json!({ "id":1,"method": "blutgang_tiers" });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            create_test_cache(),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        let tiers = &result["result"]["tiers"];
+        assert_eq!(tiers[0]["tier"], 0);
+        assert_eq!(tiers[0]["rpcs"], json!(["http://example.com/"]));
+        assert_eq!(tiers[0]["poverty"], json!(["http://poverty.com/"]));
+        assert_eq!(tiers[1]["tier"], 1);
+        assert_eq!(tiers[1]["rpcs"], json!(["http://backup.com/"]));
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_top_tier() {
+        // Arrange
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let config = create_test_settings_config();
+        let top_tier = || config.read().unwrap().rate_limit.serving_tier.top();
+
+        // Act and assert
+        let tx = json!({ "id":1,"method": "blutgang_add_to_rpc_list", "params": ["http://backup.com", Null, 5, 10, 0.5, 2] });
+        execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            config.clone(),
+            create_test_cache(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(top_tier(), 2);
+
+        // RPCs in poverty count too
+        let tx = json!({ "id":1,"method": "blutgang_add_to_poverty_list", "params": ["http://example.com", Null, 5, 10, 0.5, 1] });
+        execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            config.clone(),
+            create_test_cache(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(top_tier(), 1);
+
+        let tx = json!({ "id":1,"method": "blutgang_remove_from_poverty_list", "params": [0] });
+        execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            config.clone(),
+            create_test_cache(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(top_tier(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_budgets() {
+        // Arrange
+        let rpc_list = create_test_rpc_list();
+        let budget = Arc::new(ComputeBudget::new(
+            "example".to_string(),
+            100,
+            BudgetWindow::Daily,
+            10,
+            Default::default(),
+        ));
+        rpc_list.write().unwrap()[0].budget = Some(budget);
+        rpc_list.read().unwrap()[0].charge("eth_call");
+
+        // Act
+        let tx = json!({ "id":1,"method": "blutgang_budgets" });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            create_test_cache(),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        let budgets = result["result"].as_array().unwrap();
+        assert_eq!(budgets.len(), 1);
+        assert_eq!(budgets[0]["name"], "example");
+        assert_eq!(budgets[0]["window"], "daily");
+        assert_eq!(budgets[0]["used"], 10);
+        assert_eq!(budgets[0]["remaining"], 90);
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_cache_rules() {
+        // Act
+        let tx = json!({ "id":1,"method": "blutgang_cache_rules" });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            create_test_cache(),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        let rules = &result["result"];
+        assert_eq!(
+            rules["eth_getBlockByNumber"],
+            json!({
+                "cache": true,
+                "block_resolved": true,
+                "non_null": true,
+                "no_error": true,
+                "ttl_ms": null,
+            })
+        );
+        assert!(rules.get("eth_blockNumber").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_cache_stats() {
+        // Arrange
+        let config = create_test_settings_config();
+        config
+            .read()
+            .unwrap()
+            .ttl_cache
+            .get(&blake3::hash(b"eth_gasPrice"), Instant::now());
+
+        // Act
+        let tx = json!({ "id":1,"method": "blutgang_cache_stats" });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            config,
+            create_test_cache(),
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        assert_eq!(
+            result["result"]["ttl"],
+            json!({"entries": 0, "hits": 0, "misses": 1})
+        );
+        assert_eq!(
+            result["result"]["latest"],
+            json!({"block": 0, "entries": 0, "hits": 0, "misses": 0})
+        );
+    }
+
     #[tokio::test]
     async fn test_execute_method_add_to_rpc_list_no_ws() {
         // Arrange
@@ -753,4 +1236,72 @@ mod tests {
         // Assert
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_set_selection_strategy() {
+        // Arrange
+        let cache = create_test_cache();
+        let tx =
+            json!({ "id":1,"method": "blutgang_set_selection_strategy", "params": ["random"] });
+
+        let config = create_test_settings_config();
+        assert_eq!(
+            config.read().unwrap().selection.name(),
+            "weighted_round_robin"
+        );
+
+        // Act
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            cache.clone(),
+        )
+        .await;
+
+        // Assert
+        assert!(result.is_ok());
+        assert_eq!(config.read().unwrap().selection.name(), "random");
+
+        // Unknown strategies are rejected
+        let tx = json!({ "id":1,"method": "blutgang_set_selection_strategy", "params": ["nope"] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            cache.clone(),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(config.read().unwrap().selection.name(), "random");
+
+        let tx = json!({ "id":1,"method": "blutgang_selection_strategy" });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            cache,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["result"]["strategy"], "random");
+        assert_eq!(result["result"]["latency_metric"], "mean");
+
+        // Switch the latency metric along with the strategy
+        let tx = json!({ "id":1,"method": "blutgang_set_selection_strategy", "params": ["power_of_two", "p99"] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            create_test_cache(),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(config.read().unwrap().selection.name(), "power_of_two");
+        assert_eq!(config.read().unwrap().latency_metric, LatencyMetric::P99);
+    }
 }
@@ -1,25 +1,69 @@
 use crate::{
     balancer::{
+        coalesce::{
+            can_coalesce,
+            wait_for_leader,
+            Flight,
+            InFlightCalls,
+        },
         format::{
             incoming_to_value,
             replace_block_tags,
+            str_to_value,
+        },
+        hedge::send_hedged,
+        latest_cache::LatestCache,
+        logs::{
+            bisect_logs,
+            merge_logs,
+            split_logs,
+            LogsBudget,
+            MAX_BISECT_DEPTH,
+        },
+        normalize::{
+            fill_default_params,
+            normalize_request,
         },
         processing::{
+            cache_lookup,
             cache_querry,
+            record_rpc_failure,
+            record_rpc_success,
             update_rpc_latency,
             CacheArgs,
         },
-        selection::select::pick,
+        response_errors::{
+            ErrorKind,
+            ErrorStatus,
+            JsonRpcError,
+        },
+        selection::{
+            cache_rules::CacheRules,
+            error_rules::{
+                ErrorAction,
+                ErrorRules,
+            },
+            method_policy::{
+                MethodPolicies,
+                RequestPolicy,
+            },
+            queue::RateLimitQueue,
+            strategy::SelectionStrategy,
+        },
+        ttl_cache::TtlCache,
     },
-    cache_error,
     log_err,
     log_info,
     log_wrn,
-    no_rpc_available,
     print_cache_error,
-    rpc::types::Rpc,
+    rpc::{
+        error::{
+            FailureClass,
+            RpcError,
+        },
+        types::Rpc,
+    },
     rpc_response,
-    timed_out,
     websocket::{
         server::serve_websocket,
         types::{
@@ -62,7 +106,12 @@ use hyper_tungstenite::{
 
 use sled::Db;
 
-use tokio::time::timeout;
+use tokio::time::{
+    sleep,
+    timeout,
+};
+
+use futures::future::join_all;
 
 use std::{
     collections::BTreeMap,
@@ -72,10 +121,7 @@ use std::{
         Arc,
         RwLock,
     },
-    time::{
-        Duration,
-        Instant,
-    },
+    time::Instant,
 };
 
 #[derive(Debug, Clone)]
@@ -115,6 +161,15 @@ struct RequestParams {
     ttl: u128,
     max_retries: u32,
     header_check: bool,
+    error_status: ErrorStatus,
+    error_rules: Arc<ErrorRules>,
+    strategy: Arc<dyn SelectionStrategy>,
+    rate_limit: Arc<RateLimitQueue>,
+    method_policies: Arc<MethodPolicies>,
+    in_flight: Arc<InFlightCalls>,
+    cache_rules: Arc<CacheRules>,
+    ttl_cache: Arc<TtlCache>,
+    latest_cache: Arc<LatestCache>,
 }
 
 #[derive(Debug)]
@@ -173,50 +228,77 @@ macro_rules! accept {
     };
 }
 
-/// Macro for getting responses from either the cache or RPC nodes
+/// Macro for getting responses from either the cache or RPC nodes.
+///
+/// Evaluates to a `Result<String, JsonRpcError>`.
 macro_rules! get_response {
     (
         $tx:expr,
-        $cache:expr,
+        $cache_args:expr,
         $tx_hash:expr,
         $rpc_position:expr,
         $id:expr,
         $rpc_list_rwlock:expr,
-        $finalized_rx:expr,
-        $named_numbers:expr,
-        $head_cache:expr,
-        $ttl:expr,
-        $max_retries:expr
+        $policy:expr,
+        $error_rules:expr,
+        $strategy:expr,
+        $queue:expr
     ) => {
-        match $cache.get($tx_hash.as_bytes()) {
-            Ok(Some(mut rax)) => {
+        match cache_lookup(&$tx, $tx_hash, &$cache_args) {
+            Ok(Some(mut cached)) => {
                 $rpc_position = None;
                 // Reconstruct ID
-                let mut cached: Value = simd_json::serde::from_slice(&mut rax).unwrap();
-
-                cached["id"] = $id.into();
-                cached.to_string()
+                cached["id"] = $id;
+                Ok(cached.to_string())
             }
+            // Identical requests that miss the cache at the same time only go upstream once
             Ok(None) => {
-                fetch_from_rpc!(
-                    $tx,
-                    $id,
-                    $rpc_list_rwlock,
-                    $rpc_position,
-                    $cache,
-                    $tx_hash,
-                    $finalized_rx,
-                    $named_numbers,
-                    $head_cache,
-                    $ttl,
-                    $max_retries
-                )
+                let coalesce = can_coalesce(
+                    $tx["method"].as_str().unwrap_or_default(),
+                    &$cache_args.cache_rules,
+                );
+                loop {
+                    let flight = match coalesce {
+                        true => {
+                            match $cache_args.in_flight.join($tx_hash) {
+                                Flight::Leader(flight) => Some(flight),
+                                Flight::Follower(flight) => {
+                                    if let Some(result) = wait_for_leader(flight, $id.clone()).await
+                                    {
+                                        $rpc_position = None;
+                                        break result;
+                                    }
+                                    // The leader gave up without a response, so try again ourselves
+                                    continue;
+                                }
+                            }
+                        }
+                        false => None,
+                    };
+
+                    let result = fetch_from_rpc!(
+                        $tx,
+                        $id,
+                        $rpc_list_rwlock,
+                        $rpc_position,
+                        $cache_args,
+                        $tx_hash,
+                        $policy,
+                        $error_rules,
+                        $strategy,
+                        $queue
+                    );
+                    if let Some(flight) = flight {
+                        flight.finish(&result);
+                    }
+                    break result;
+                }
             }
             Err(_) => {
                 // If anything errors send an rpc request and see if it works, if not then gg
                 print_cache_error!();
                 $rpc_position = None;
-                return (cache_error!(), $rpc_position);
+                Err(JsonRpcError::new(ErrorKind::CacheError, $id))
             }
         }
     };
@@ -228,148 +310,521 @@ macro_rules! fetch_from_rpc {
         $id:expr,
         $rpc_list_rwlock:expr,
         $rpc_position:expr,
-        $cache:expr,
+        $cache_args:expr,
         $tx_hash:expr,
-        $finalized_rx:expr,
-        $named_numbers:expr,
-        $head_cache:expr,
-        $ttl:expr,
-        $max_retries:expr
-    ) => {{
+        $policy:expr,
+        $error_rules:expr,
+        $strategy:expr,
+        $queue:expr
+    ) => {'fetch: {
         // Kinda jank but set the id back to what it was before
-        $tx["id"] = $id.into();
+        $tx["id"] = $id;
+
+        // Head we're sending the request at, in case it's for the head
+        let sent_at = $cache_args.latest_cache.head();
 
         // Loop until we get a response
         let mut rx;
         let mut retries = 0;
         loop {
-            // Get the next Rpc in line.
-            let mut rpc;
-            {
-                let mut rpc_list = $rpc_list_rwlock.write().unwrap();
-                (rpc, $rpc_position) = pick(&mut rpc_list);
-            }
-            log_info!("Forwarding to: {}", rpc.name);
-
-            // Check if we have any RPCs in the list, if not return error
-            if $rpc_position == None {
-                return (no_rpc_available!(), None);
+            if retries > 0 {
+                sleep($policy.retry_delay(retries)).await;
             }
 
-            // Send the request. And return a timeout if it takes too long
+            // Get the next Rpc in line, waiting for one if they're all rate limited.
             //
-            // Check if it contains any errors or if its `latest` and insert it if it isn't
-            match timeout(
-                Duration::from_millis($ttl.try_into().unwrap()),
-                rpc.send_request($tx.clone()),
+            // If we don't have any RPCs in the list, return an error
+            let (mut rpc, mut position, _in_flight) = match $queue.pick(&$rpc_list_rwlock, $strategy.as_ref()).await {
+                Ok(picked) => picked,
+                Err(kind) => {
+                    $rpc_position = None;
+                    break 'fetch Err(JsonRpcError::new(kind, $tx["id"].clone()));
+                }
+            };
+            $rpc_position = Some(position);
+            log_info!("Forwarding to: {}", rpc.name);
+
+            // Send the request, hedging with another RPC if the method opted in.
+            // And return a timeout if it takes too long
+            let sent = timeout(
+                $policy.ttl,
+                send_hedged(
+                    &rpc,
+                    position,
+                    &$tx,
+                    &$rpc_list_rwlock,
+                    $strategy.as_ref(),
+                    $queue.serving_tier.top(),
+                    $policy.hedge_after,
+                ),
             )
             .await
-            {
-                Ok(rxa) => {
-                    rx = rxa.unwrap();
-                    break;
-                },
-                Err(_) => {
-                    log_wrn!("\x1b[93mWrn:\x1b[0m An RPC request has timed out, picking new RPC and retrying.");
-                    rpc.update_latency($ttl as f64);
+            .map(|(hedge, result)| {
+                // The hedge answered first, so it gets the credit or the blame.
+                // Its latency is already recorded.
+                if let Some((hedge, hedge_position)) = hedge {
+                    rpc = hedge;
+                    position = hedge_position;
+                    $rpc_position = None;
+                }
+                result
+            });
+
+            // Transport errors and timeouts are recorded against the RPC, and we retry on another one
+            let class = match sent {
+                Ok(Ok(rxa)) => {
+                    match $error_rules.classify(&rxa) {
+                        // Splitting a range that's too large is up to the caller
+                        ErrorAction::Pass | ErrorAction::Split => {
+                            record_rpc_success(&$rpc_list_rwlock, position);
+                            rx = rxa;
+                            break;
+                        }
+                        action => {
+                            log_wrn!("{} returned an error, picking new RPC and retrying.", rpc.name);
+                            match action {
+                                ErrorAction::Degrade => record_rpc_failure(
+                                    &$rpc_list_rwlock,
+                                    position,
+                                    FailureClass::ErrorResponse,
+                                    $policy.ttl,
+                                ),
+                                // Being rate limited doesn't mean the RPC is unhealthy
+                                ErrorAction::Throttle => rpc.record_throttle($queue.backoff(None)),
+                                // The RPC is up, it just can't serve this request
+                                _ => record_rpc_success(&$rpc_list_rwlock, position),
+                            }
+
+                            // Out of retries, so tell the client what the last RPC said
+                            retries += 1;
+                            if retries >= $policy.max_retries {
+                                let data = match str_to_value(&rxa) {
+                                    Some(mut rxa) if rxa.get("error").is_some() => rxa["error"].take(),
+                                    _ => rxa.into(),
+                                };
+                                break 'fetch Err(JsonRpcError::new(ErrorKind::UpstreamError, $tx["id"].clone())
+                                    .with_data(data));
+                            }
+                            continue;
+                        }
+                    }
+                }
+                Ok(Err(RpcError::Throttled(retry_after))) => {
+                    rpc.record_throttle($queue.backoff(retry_after));
+
                     retries += 1;
+                    if retries >= $policy.max_retries {
+                        break 'fetch Err(JsonRpcError::new(ErrorKind::RateLimited, $tx["id"].clone())
+                            .with_data("throttled"));
+                    }
+                    continue;
+                }
+                Ok(Err(RpcError::Transport(class))) => class,
+                Ok(Err(err)) => {
+                    log_wrn!("Unexpected error from {}: {}", rpc.name, err);
+                    FailureClass::Other
                 },
+                Err(_) => FailureClass::Timeout,
             };
 
-            if retries == $max_retries {
-                return (timed_out!(), $rpc_position,);
+            log_wrn!("Request to {} failed ({}), picking new RPC and retrying.", rpc.name, class);
+            record_rpc_failure(
+                &$rpc_list_rwlock,
+                position,
+                class,
+                $policy.ttl,
+            );
+            retries += 1;
+
+            if retries >= $policy.max_retries {
+                // Tell the client why the last attempt failed
+                let kind = match class {
+                    FailureClass::Timeout => ErrorKind::TimedOut,
+                    _ => ErrorKind::UpstreamError,
+                };
+                break 'fetch Err(JsonRpcError::new(kind, $tx["id"].clone())
+                    .with_data(class.to_string()));
             }
         }
 
-        let cache_args = CacheArgs {
-            finalized_rx: $finalized_rx,
-            named_numbers: $named_numbers,
-            cache: $cache,
-            head_cache: $head_cache,
-        };
-
         // Don't cache responses that contain errors or missing trie nodes
         cache_querry(
             &mut rx,
             $tx,
             $tx_hash,
-            &cache_args,
+            &$cache_args,
+            &sent_at,
         );
 
-        rx
+        Ok(rx)
     }};
 }
 
-/// Pick RPC and send request to it. In case the result is cached,
-/// read and return from the cache.
-async fn forward_body(
-    tx: Request<hyper::body::Incoming>,
+/// Process a single JSON-RPC call. Returns its response from either
+/// the cache or a RPC, and the position of the RPC used, if any.
+async fn process_call(
+    mut tx: Value,
     rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
     finalized_rx: &watch::Receiver<u64>,
     named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
     head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
-    cache: Db,
-    params: RequestParams,
-) -> (
-    Result<hyper::Response<Full<Bytes>>, Infallible>,
-    Option<usize>,
-) {
-    // Check if body has application/json
-    //
-    // Can be toggled via the config. Should be on if we want blutgang to be JSON-RPC compliant.
-    if params.header_check
-        && tx.headers().get("content-type") != Some(&HeaderValue::from_static("application/json"))
+    cache: &Db,
+    params: &RequestParams,
+) -> (Result<String, JsonRpcError>, Option<usize>) {
+    // Only objects with a valid id can be JSON-RPC requests
+    if !tx.is_object()
+        || !matches!(
+            tx.get("id"),
+            None | Some(Value::Null | Value::String(_) | Value::Number(_))
+        )
     {
         return (
-            Ok(hyper::Response::builder()
-                .status(400)
-                .body(Full::new(Bytes::from("Improper content-type header")))
-                .unwrap()),
+            Err(JsonRpcError::new(ErrorKind::InvalidRequest, Value::Null)),
             None,
         );
     }
 
-    // Convert incoming body to serde value
-    let mut tx = incoming_to_value(tx).await.unwrap();
-
     // Get the id of the request and set it to 0 for caching
     //
     // We're doing this ID gymnastics because we're hashing the
     // whole request and we don't want the ID as it's arbitrary
     // and does not impact the request result.
-    let id = tx["id"].take().as_u64().unwrap_or(0);
+    //
+    // The id is kept as is, so whatever the client sent gets echoed back.
+    let id = tx["id"].take();
+
+    // Spell out default params, so they get their tags rewritten as well
+    fill_default_params(&mut tx);
 
+    // Rewrite named block parameters if possible
+    let tx = replace_block_tags(&mut tx, named_numbers);
+
+    let cache_args = CacheArgs {
+        finalized_rx: finalized_rx.clone(),
+        named_numbers: named_numbers.clone(),
+        cache: cache.clone(),
+        head_cache: head_cache.clone(),
+        in_flight: params.in_flight.clone(),
+        cache_rules: params.cache_rules.clone(),
+        ttl_cache: params.ttl_cache.clone(),
+        latest_cache: params.latest_cache.clone(),
+    };
+
+    // Ranges of logs are cached in chunks, so overlapping queries can share them
+    if params.cache_rules.get("eth_getLogs").cache && cfg!(not(feature = "no-cache")) {
+        let latest = named_numbers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .latest;
+        if let Some(mut range) = split_logs(&tx, latest) {
+            let budget = LogsBudget::default();
+            if budget.take(range.chunks.len()) {
+                let responses = forward_logs(
+                    std::mem::take(&mut range.chunks),
+                    &id,
+                    rpc_list_rwlock,
+                    &cache_args,
+                    params,
+                    &budget,
+                    0,
+                )
+                .await;
+                return (range.stitch(responses, id), None);
+            }
+        }
+    }
+
+    forward_call(
+        tx,
+        id,
+        rpc_list_rwlock,
+        &cache_args,
+        params,
+        &LogsBudget::default(),
+        0,
+    )
+    .await
+}
+
+/// Get the response to `tx` from either the cache or a RPC, and the position
+/// of the RPC used, if any. `tx` has to have its id taken already.
+///
+/// `eth_getLogs` ranges that RPCs refuse to serve in one go are split in half
+/// and sent again, at most `MAX_BISECT_DEPTH` times and for as long as `budget`
+/// has parts left. `depth` is how many times `tx` was split already, and
+/// `budget` is shared by every part of the range.
+async fn forward_call(
+    mut tx: Value,
+    id: Value,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    cache_args: &CacheArgs,
+    params: &RequestParams,
+    budget: &LogsBudget,
+    depth: u32,
+) -> (Result<String, JsonRpcError>, Option<usize>) {
     // Hash the request with either blake3 or xxhash depending on the enabled feature
+    //
+    // This happens after rewriting tags, so a cached `latest` response
+    // only gets served for as long as the head stays the same.
+    // What gets hashed is the canonical form of `tx`, so equivalent requests share a key.
+    let canonical = normalize_request(&tx).to_string();
     let tx_hash;
     #[cfg(not(feature = "xxhash"))]
     {
-        tx_hash = hash(tx.to_string().as_bytes());
+        tx_hash = hash(canonical.as_bytes());
     }
     #[cfg(feature = "xxhash")]
     {
-        tx_hash = xxh3_64(tx.to_string().as_bytes());
+        tx_hash = xxh3_64(canonical.as_bytes());
     }
 
     // RPC used to get the response, we use it to update the latency for it later.
     let mut rpc_position;
 
-    // Rewrite named block parameters if possible
-    let mut tx = replace_block_tags(&mut tx, named_numbers);
+    // Timeout and retries can be overridden for each method
+    let policy = params.method_policies.get(
+        tx["method"].as_str().unwrap_or_default(),
+        RequestPolicy::new(params.ttl, params.max_retries),
+    );
+
+    // Ranges of logs to ask for instead, if every RPC is going to refuse this one
+    let halves = match depth < MAX_BISECT_DEPTH {
+        true => bisect_logs(&tx),
+        false => None,
+    };
+
+    // Get the response from either the cache or from a RPC. If it fails, retry.
+    //
+    // The slot is given back before splitting, so the halves can take it.
+    let rax = {
+        let _slot = budget.slot().await;
+        get_response!(
+            tx,
+            cache_args,
+            tx_hash,
+            rpc_position,
+            id.clone(),
+            rpc_list_rwlock,
+            policy,
+            params.error_rules,
+            params.strategy,
+            params.rate_limit
+        )
+    };
+
+    if let (Ok(rx), Some(halves)) = (&rax, halves) {
+        if params.error_rules.classify(rx) == ErrorAction::Split && budget.take(halves.len()) {
+            log_info!("Range of eth_getLogs too large, splitting it in half.");
+            let responses = forward_logs(
+                halves.to_vec(),
+                &id,
+                rpc_list_rwlock,
+                cache_args,
+                params,
+                budget,
+                depth + 1,
+            )
+            .await;
+            return (merge_logs(responses, id), None);
+        }
+    }
+
+    (rax, rpc_position)
+}
+
+/// Process parts of an `eth_getLogs` range concurrently, in order.
+///
+/// Each part is its own call, so it's looked up in the cache and coalesced on
+/// its own, and only the missing ones go upstream, as many at a time as `budget`
+/// allows. They can be served by different RPCs, so their latencies get updated here.
+async fn forward_logs(
+    parts: Vec<Value>,
+    id: &Value,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    cache_args: &CacheArgs,
+    params: &RequestParams,
+    budget: &LogsBudget,
+    depth: u32,
+) -> Vec<Result<String, JsonRpcError>> {
+    let calls = parts.into_iter().map(|part| {
+        async move {
+            let time = Instant::now();
+            let (rax, rpc_position) = Box::pin(forward_call(
+                part,
+                id.clone(),
+                rpc_list_rwlock,
+                cache_args,
+                params,
+                budget,
+                depth,
+            ))
+            .await;
+
+            if let Some(rpc_position) = rpc_position {
+                update_rpc_latency(rpc_list_rwlock, rpc_position, time.elapsed());
+            }
+            rax
+        }
+    });
+
+    join_all(calls).await
+}
+
+/// Response for notifications, which per the JSON-RPC spec we must not answer.
+fn notification_response() -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    rpc_response!(204, Full::new(Bytes::new()))
+}
+
+/// Process every call of a JSON-RPC batch concurrently and reassemble
+/// the responses in the order the calls came in.
+///
+/// Each call is looked up in the cache on its own, so only the misses
+/// get forwarded. Misses are spread across RPCs by `pick`.
+async fn forward_batch(
+    batch: Vec<Value>,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    finalized_rx: &watch::Receiver<u64>,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    cache: Db,
+    params: RequestParams,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    let calls = batch.into_iter().map(|tx| {
+        let cache = &cache;
+        let params = &params;
+        async move {
+            // Notifications don't get a response, but still get forwarded
+            let is_notification = tx.as_object().is_some_and(|tx| !tx.contains_key("id"));
+
+            let time = Instant::now();
+            let (rax, rpc_position) = process_call(
+                tx,
+                rpc_list_rwlock,
+                finalized_rx,
+                named_numbers,
+                head_cache,
+                cache,
+                params,
+            )
+            .await;
+
+            // Every call in a batch can be served by a different RPC
+            // so we have to update their latencies here.
+            if let Some(rpc_position) = rpc_position {
+                update_rpc_latency(rpc_list_rwlock, rpc_position, time.elapsed());
+            }
+
+            if is_notification {
+                return None;
+            }
+            match rax {
+                Ok(rax) => Some(rax),
+                Err(err) => Some(err.to_value().to_string()),
+            }
+        }
+    });
+
+    let responses: Vec<String> = join_all(calls).await.into_iter().flatten().collect();
 
-    // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let rax = get_response!(
+    // We must not return an empty array if the batch was only notifications
+    if responses.is_empty() {
+        return notification_response();
+    }
+
+    // Convert the reassembled responses to bytes and put them in a http_body_util::Full
+    let body = Full::new(Bytes::from(format!("[{}]", responses.join(","))));
+
+    // Build the response
+    let res = hyper::Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(body)
+        .unwrap();
+
+    Ok(res)
+}
+
+/// Pick RPC and send request to it. In case the result is cached,
+/// read and return from the cache.
+///
+/// JSON-RPC batches get forwarded via `forward_batch`.
+async fn forward_body(
+    tx: Request<hyper::body::Incoming>,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    finalized_rx: &watch::Receiver<u64>,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    cache: Db,
+    params: RequestParams,
+) -> (
+    Result<hyper::Response<Full<Bytes>>, Infallible>,
+    Option<usize>,
+) {
+    // Check if body has application/json
+    //
+    // Can be toggled via the config. Should be on if we want blutgang to be JSON-RPC compliant.
+    if params.header_check
+        && tx.headers().get("content-type") != Some(&HeaderValue::from_static("application/json"))
+    {
+        let err = JsonRpcError::new(ErrorKind::InvalidRequest, Value::Null)
+            .with_data("Improper content-type header");
+        return (err.into_response(&params.error_status), None);
+    }
+
+    // Convert incoming body to serde value
+    let tx = match incoming_to_value(tx).await {
+        Ok(tx) => tx,
+        Err(kind) => {
+            let err = JsonRpcError::new(kind, Value::Null);
+            return (err.into_response(&params.error_status), None);
+        }
+    };
+
+    // Batches can be served by multiple RPCs, so they update latencies themselves
+    let tx = match tx {
+        Value::Array(batch) if batch.is_empty() => {
+            let err = JsonRpcError::new(ErrorKind::InvalidRequest, Value::Null);
+            return (err.into_response(&params.error_status), None);
+        }
+        Value::Array(batch) => {
+            let response = forward_batch(
+                batch,
+                rpc_list_rwlock,
+                finalized_rx,
+                named_numbers,
+                head_cache,
+                cache,
+                params,
+            )
+            .await;
+            return (response, None);
+        }
+        tx => tx,
+    };
+
+    let is_notification = tx.as_object().is_some_and(|tx| !tx.contains_key("id"));
+
+    let (rax, rpc_position) = process_call(
         tx,
-        cache,
-        tx_hash,
-        rpc_position,
-        id,
         rpc_list_rwlock,
-        finalized_rx.clone(),
-        named_numbers.clone(),
-        head_cache.clone(),
-        params.ttl,
-        params.max_retries
-    );
+        finalized_rx,
+        named_numbers,
+        head_cache,
+        &cache,
+        &params,
+    )
+    .await;
+
+    // Notifications don't get a response, not even an error
+    if is_notification {
+        return (notification_response(), rpc_position);
+    }
+
+    let rax = match rax {
+        Ok(rax) => rax,
+        Err(err) => return (err.into_response(&params.error_status), rpc_position),
+    };
 
     // Convert rx to bytes and but it in a Buf
     let body = hyper::body::Bytes::from(rax);
@@ -400,23 +855,30 @@ pub async fn accept_request(
     if is_upgrade_request(&tx) {
         log_info!("Received WS upgrade request");
 
-        if !connection_params.config.read().unwrap().is_ws {
-            return rpc_response!(
-                500,
-                Full::new(Bytes::from(
-                    "{code:-32005, message:\"error: WebSockets are disabled!\"}".to_string(),
-                ))
-            );
+        let (is_ws, error_status, in_flight, cache_rules, ttl_cache, latest_cache) = {
+            let config_guard = connection_params.config.read().unwrap();
+            (
+                config_guard.is_ws,
+                config_guard.error_status,
+                config_guard.in_flight.clone(),
+                config_guard.cache_rules.clone(),
+                config_guard.ttl_cache.clone(),
+                config_guard.latest_cache.clone(),
+            )
+        };
+
+        if !is_ws {
+            return JsonRpcError::new(ErrorKind::WsDisabled, Value::Null)
+                .into_response(&error_status);
         }
 
         let (response, websocket) = match upgrade(&mut tx, None) {
             Ok((response, websocket)) => (response, websocket),
             Err(e) => {
                 log_err!("Websocket upgrade error: {}", e);
-                return rpc_response!(500, Full::new(Bytes::from(
-                    "{code:-32004, message:\"error: Websocket upgrade error! Try again later...\"}"
-                        .to_string(),
-                )));
+                return JsonRpcError::new(ErrorKind::WsUpgradeError, Value::Null)
+                    .with_data(e.to_string())
+                    .into_response(&error_status);
             }
         };
 
@@ -425,6 +887,10 @@ pub async fn accept_request(
             named_numbers: connection_params.named_numbers.clone(),
             cache: connection_params.cache,
             head_cache: connection_params.head_cache.clone(),
+            in_flight,
+            cache_rules,
+            ttl_cache,
+            latest_cache,
         };
 
         // Spawn a task to handle the websocket connection.
@@ -435,6 +901,7 @@ pub async fn accept_request(
                 connection_params.channels.outgoing_rx,
                 connection_params.sub_data.clone(),
                 cache_args,
+                connection_params.config,
             )
             .await
             {
@@ -457,6 +924,15 @@ pub async fn accept_request(
             ttl: config_guard.ttl,
             max_retries: config_guard.max_retries,
             header_check: config_guard.header_check,
+            error_status: config_guard.error_status,
+            error_rules: config_guard.error_rules.clone(),
+            strategy: config_guard.selection.clone(),
+            rate_limit: config_guard.rate_limit.clone(),
+            method_policies: config_guard.method_policies.clone(),
+            in_flight: config_guard.in_flight.clone(),
+            cache_rules: config_guard.cache_rules.clone(),
+            ttl_cache: config_guard.ttl_cache.clone(),
+            latest_cache: config_guard.latest_cache.clone(),
         }
     };
 
@@ -489,3 +965,899 @@ pub async fn accept_request(
 
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::selection::{
+        method_policy::MethodPolicy,
+        strategy::WeightedRoundRobin,
+    };
+    use crate::balancer::test_utils::{
+        MockResponse,
+        MockRpc,
+    };
+    use crate::rpc::types::hex_to_decimal;
+    use http_body_util::BodyExt;
+    use serde_json::json;
+    use std::time::Duration;
+
+    // Helper function to create a test cache
+    fn create_test_cache() -> Db {
+        let db = sled::Config::new().temporary(true);
+
+        db.open().unwrap()
+    }
+
+    fn test_params() -> RequestParams {
+        RequestParams {
+            ttl: 1000,
+            max_retries: 1,
+            header_check: false,
+            error_status: ErrorStatus::default(),
+            error_rules: Arc::new(ErrorRules::default()),
+            strategy: Arc::new(WeightedRoundRobin::default()),
+            rate_limit: Arc::new(RateLimitQueue::default()),
+            method_policies: Arc::new(MethodPolicies::default()),
+            in_flight: Arc::new(InFlightCalls::default()),
+            cache_rules: Arc::new(CacheRules::default()),
+            ttl_cache: Arc::new(TtlCache::default()),
+            latest_cache: Arc::new(LatestCache::default()),
+        }
+    }
+
+    // Fake RPC that answers `eth_getLogs` requests with `respond(from, to)` of their range
+    async fn spawn_logs_rpc(respond: fn(u64, u64) -> Value) -> MockRpc {
+        MockRpc::spawn(move |tx| {
+            let filter = &tx["params"][0];
+            let mut body = respond(
+                hex_to_decimal(filter["fromBlock"].as_str().unwrap()).unwrap(),
+                hex_to_decimal(filter["toBlock"].as_str().unwrap()).unwrap(),
+            );
+            body["jsonrpc"] = "2.0".into();
+            body["id"] = tx["id"].clone();
+            MockResponse::ok(body)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_forward_batch() {
+        let cache = create_test_cache();
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        // Cache the responses for the 1st and 3rd call
+        for (number, result) in [("0x1", "0xa"), ("0x3", "0xc")] {
+            let tx = json!({
+                "id": Value::Null,
+                "jsonrpc": "2.0",
+                "method": "eth_getBlockTransactionCountByNumber",
+                "params": [number],
+            });
+            let rx = json!({"id": Value::Null, "jsonrpc": "2.0", "result": result});
+            cache
+                .insert(
+                    hash(tx.to_string().as_bytes()).as_bytes(),
+                    rx.to_string().as_bytes(),
+                )
+                .unwrap();
+        }
+
+        let batch = ["0x1", "0x2", "0x3"]
+            .iter()
+            .enumerate()
+            .map(|(id, number)| {
+                json!({
+                    "id": id + 7,
+                    "jsonrpc": "2.0",
+                    "method": "eth_getBlockTransactionCountByNumber",
+                    "params": [number],
+                })
+            })
+            .collect();
+
+        let params = test_params();
+
+        let response = forward_batch(
+            batch,
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            cache,
+            params,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        // Responses should be in the original order with the original ids.
+        // The 2nd call is not cached and there are no RPCs to forward it to.
+        assert_eq!(
+            body,
+            json!([
+                {"id": 7, "jsonrpc": "2.0", "result": "0xa"},
+                {"id": 8, "jsonrpc": "2.0", "error": {
+                    "code": -32002,
+                    "message": "No working RPC available! Try again later...",
+                }},
+                {"id": 9, "jsonrpc": "2.0", "result": "0xc"},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_batch_ids() {
+        let cache = create_test_cache();
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        let tx = json!({
+            "id": Value::Null,
+            "jsonrpc": "2.0",
+            "method": "eth_chainId",
+        });
+        let rx = json!({"id": Value::Null, "jsonrpc": "2.0", "result": "0x1"});
+        cache
+            .insert(
+                hash(tx.to_string().as_bytes()).as_bytes(),
+                rx.to_string().as_bytes(),
+            )
+            .unwrap();
+
+        // String ids, integer ids of any size or sign, null ids and notifications
+        let batch = str_to_value(
+            r#"[
+                {"id":"abc-1","jsonrpc":"2.0","method":"eth_chainId"},
+                {"id":18446744073709551615,"jsonrpc":"2.0","method":"eth_chainId"},
+                {"id":123456789012345678901234567890,"jsonrpc":"2.0","method":"eth_chainId"},
+                {"id":-7,"jsonrpc":"2.0","method":"eth_chainId"},
+                {"jsonrpc":"2.0","method":"eth_chainId"},
+                {"id":null,"jsonrpc":"2.0","method":"eth_chainId"}
+            ]"#,
+        )
+        .unwrap();
+
+        let params = test_params();
+
+        let response = forward_batch(
+            batch.as_array().unwrap().to_owned(),
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            cache.clone(),
+            params,
+        )
+        .await
+        .unwrap();
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            concat!(
+                r#"[{"id":"abc-1","jsonrpc":"2.0","result":"0x1"},"#,
+                r#"{"id":18446744073709551615,"jsonrpc":"2.0","result":"0x1"},"#,
+                r#"{"id":123456789012345678901234567890,"jsonrpc":"2.0","result":"0x1"},"#,
+                r#"{"id":-7,"jsonrpc":"2.0","result":"0x1"},"#,
+                r#"{"id":null,"jsonrpc":"2.0","result":"0x1"}]"#,
+            )
+        );
+
+        // Batches made up of only notifications don't get a response body
+        let params = test_params();
+
+        let response = forward_batch(
+            vec![json!({"jsonrpc": "2.0", "method": "eth_chainId"})],
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            cache,
+            params,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 204);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forward_batch_invalid_requests() {
+        let cache = create_test_cache();
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        let params = test_params();
+
+        // Elements that aren't requests get an invalid request error with a null id
+        let response = forward_batch(
+            vec![
+                json!(1),
+                json!({"id": [1], "jsonrpc": "2.0", "method": "eth_chainId"}),
+            ],
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            cache,
+            params,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let error = json!({
+            "id": null,
+            "jsonrpc": "2.0",
+            "error": {"code": -32600, "message": "Invalid request"},
+        });
+        assert_eq!(
+            str_to_value(std::str::from_utf8(&body).unwrap()).unwrap(),
+            json!([error, error])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_call_failover() {
+        let cache = create_test_cache();
+        // Nothing is listening on either of these
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new("http://127.0.0.1:1".to_string(), None, 1, 0, 10.0),
+            Rpc::new("http://127.0.0.1:2".to_string(), None, 1, 0, 10.0),
+        ]));
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        let params = RequestParams {
+            max_retries: 4,
+            ..test_params()
+        };
+
+        let tx = json!({"id": 7, "jsonrpc": "2.0", "method": "eth_chainId"});
+        let (rax, _) = process_call(
+            tx,
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+
+        // We should give up after `max_retries` and say why
+        let err = rax.unwrap_err();
+        assert_eq!(
+            err.to_value(),
+            json!({
+                "id": 7,
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32008,
+                    "message": "Upstream RPC request failed! Try again later...",
+                    "data": "connection_refused",
+                },
+            })
+        );
+
+        // Every failure is recorded against the RPC that caused it
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(
+            rpc_list
+                .iter()
+                .map(|rpc| rpc.status.failures())
+                .sum::<u64>(),
+            4
+        );
+        assert!(rpc_list.iter().all(|rpc| rpc.status.failures() > 0));
+        assert!(rpc_list
+            .iter()
+            .all(|rpc| rpc.status.last_failure() == Some(FailureClass::ConnectionRefused)));
+    }
+
+    #[tokio::test]
+    async fn test_process_call_method_policy() {
+        let cache = create_test_cache();
+        // Nothing is listening on either of these
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new("http://127.0.0.1:1".to_string(), None, 1, 0, 10.0),
+            Rpc::new("http://127.0.0.1:2".to_string(), None, 1, 0, 10.0),
+        ]));
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        let mut method_policies = MethodPolicies::default();
+        method_policies.insert(
+            "eth_send*",
+            MethodPolicy {
+                retry: Some(false),
+                ..Default::default()
+            },
+        );
+        let params = RequestParams {
+            max_retries: 4,
+            method_policies: Arc::new(method_policies),
+            ..test_params()
+        };
+
+        // Methods without retries give up after the first failure
+        let tx = json!({"id": 1, "jsonrpc": "2.0", "method": "eth_sendRawTransaction", "params": ["0x00"]});
+        let (rax, _) = process_call(
+            tx,
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        assert!(rax.is_err());
+        let failures = |rpc_list: &Arc<RwLock<Vec<Rpc>>>| {
+            rpc_list
+                .read()
+                .unwrap()
+                .iter()
+                .map(|rpc| rpc.status.failures())
+                .sum::<u64>()
+        };
+        assert_eq!(failures(&rpc_list), 1);
+
+        // Everything else still uses `max_retries`
+        let tx = json!({"id": 1, "jsonrpc": "2.0", "method": "eth_chainId"});
+        let (rax, _) = process_call(
+            tx,
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        assert!(rax.is_err());
+        assert_eq!(failures(&rpc_list), 5);
+    }
+
+    #[tokio::test]
+    async fn test_process_call_error_rules() {
+        let cache = create_test_cache();
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        let lagging = MockRpc::fixed(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"header not found"}}"#,
+        )
+        .await;
+        let cdn = MockRpc::fixed("<html>Bad Gateway</html>").await;
+        let healthy = MockRpc::fixed(r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#).await;
+
+        let params = RequestParams {
+            max_retries: 16,
+            ..test_params()
+        };
+
+        // Retryable errors get rerouted until we hit the healthy RPC
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(lagging.url, None, 1, 0, 10.0),
+            Rpc::new(cdn.url, None, 1, 0, 10.0),
+            Rpc::new(healthy.url, None, 1, 0, 10.0),
+        ]));
+        let tx = json!({"id": 1, "jsonrpc": "2.0", "method": "eth_chainId"});
+        let (rax, rpc_position) = process_call(
+            tx.clone(),
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        assert_eq!(
+            str_to_value(&rax.unwrap()).unwrap(),
+            json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"})
+        );
+        assert_eq!(rpc_position, Some(2));
+
+        // Only degraded RPCs get the error recorded against them
+        {
+            let rpc_list = rpc_list.read().unwrap();
+            assert_eq!(rpc_list[0].status.failures(), 0);
+            assert_eq!(
+                rpc_list[1].status.last_failure(),
+                Some(FailureClass::ErrorResponse)
+            );
+            assert_eq!(rpc_list[2].status.failures(), 0);
+        }
+
+        // Once we run out of retries the last error is passed to the client, as ours
+        let lagging = MockRpc::fixed(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"header not found"}}"#,
+        )
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(lagging.url, None, 1, 0, 10.0)]));
+        let (rax, _) = process_call(
+            tx,
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        let err = rax.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UpstreamError);
+        assert_eq!(
+            err.to_value()["error"]["data"],
+            json!({"code": -32000, "message": "header not found"})
+        );
+
+        // Responses that aren't JSON-RPC errors are passed as they are
+        let cdn = MockRpc::fixed("<html>Bad Gateway</html>").await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(cdn.url, None, 1, 0, 10.0)]));
+        let (rax, _) = process_call(
+            json!({"id": 2, "jsonrpc": "2.0", "method": "eth_chainId"}),
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            // Before its circuit breaker opens
+            &RequestParams {
+                max_retries: 2,
+                ..test_params()
+            },
+        )
+        .await;
+        assert_eq!(
+            rax.unwrap_err().to_value(),
+            json!({"jsonrpc": "2.0", "id": 2, "error": {
+                "code": -32008,
+                "message": "Upstream RPC request failed! Try again later...",
+                "data": "<html>Bad Gateway</html>",
+            }})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_call_throttled() {
+        let cache = create_test_cache();
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        let throttled = MockRpc::spawn(|_| {
+            MockResponse::ok(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":429,"message":"Too Many Requests"}}"#,
+            )
+            .status("429 Too Many Requests")
+            .headers("retry-after: 30\r\n")
+        })
+        .await;
+        let healthy = MockRpc::fixed(r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#).await;
+
+        let params = RequestParams {
+            max_retries: 4,
+            ..test_params()
+        };
+
+        // Make sure the throttled RPC gets picked first
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(throttled.url, None, 1, 0, 10.0),
+            Rpc::new(healthy.url, None, 1, 0, 10.0),
+        ]));
+        rpc_list.read().unwrap()[1].status.set_latency(1000.0);
+
+        let tx = json!({"id": 1, "jsonrpc": "2.0", "method": "eth_chainId"});
+        let (rax, rpc_position) = process_call(
+            tx,
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        assert_eq!(
+            str_to_value(&rax.unwrap()).unwrap(),
+            json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"})
+        );
+        assert_eq!(rpc_position, Some(1));
+
+        // Throttling isn't a failure, but we stay away for as long as we were told to
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list[0].status.throttled(), 1);
+        assert_eq!(rpc_list[0].status.failures(), 0);
+        assert!(rpc_list[0].token_wait(Instant::now()) > Duration::from_secs(20));
+        assert_eq!(rpc_list[1].status.throttled(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_process_call_coalesced() {
+        let cache = create_test_cache();
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        // Slow RPC, so every call comes in while the first one is in flight
+        let rpc = MockRpc::spawn(|_| {
+            MockResponse::ok(r#"{"jsonrpc":"2.0","id":1,"result":"0x10"}"#)
+                .delay(Duration::from_millis(100))
+        })
+        .await;
+
+        let params = test_params();
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            rpc.url.clone(),
+            None,
+            1,
+            0,
+            10.0,
+        )]));
+
+        let calls = (0..8).map(|id| {
+            let tx = json!({"id": id, "jsonrpc": "2.0", "method": "eth_blockNumber"});
+            process_call(
+                tx,
+                &rpc_list,
+                &finalized_rx,
+                &named_numbers,
+                &head_cache,
+                &cache,
+                &params,
+            )
+        });
+        let responses = join_all(calls).await;
+
+        // Only the first call went upstream, and everyone else got their own id back
+        assert_eq!(rpc.requests(), 1);
+        assert_eq!(
+            responses
+                .iter()
+                .filter(|(_, rpc_position)| rpc_position.is_some())
+                .count(),
+            1
+        );
+        for (id, (rax, rpc_position)) in responses.into_iter().enumerate() {
+            let rax = str_to_value(&rax.unwrap()).unwrap();
+            assert_eq!(rax["result"], "0x10");
+            if rpc_position.is_none() {
+                assert_eq!(rax["id"], id);
+            }
+        }
+    }
+    #[tokio::test]
+    async fn test_process_call_stateful_not_coalesced() {
+        let cache = create_test_cache();
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        let rpc = MockRpc::spawn(|_| {
+            MockResponse::ok(r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#)
+                .delay(Duration::from_millis(100))
+        })
+        .await;
+        let params = test_params();
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            rpc.url.clone(),
+            None,
+            1,
+            0,
+            10.0,
+        )]));
+
+        // Everyone gets a filter of their own
+        let calls = (0..4).map(|id| {
+            let tx = json!({"id": id, "jsonrpc": "2.0", "method": "eth_newBlockFilter"});
+            process_call(
+                tx,
+                &rpc_list,
+                &finalized_rx,
+                &named_numbers,
+                &head_cache,
+                &cache,
+                &params,
+            )
+        });
+        let responses = join_all(calls).await;
+
+        assert_eq!(rpc.requests(), 4);
+        assert!(responses
+            .iter()
+            .all(|(rax, rpc_position)| rax.is_ok() && rpc_position.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_process_call_logs_chunks() {
+        let cache = create_test_cache();
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers {
+            latest: 10_000,
+            ..Default::default()
+        }));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        // RPC with a single log at the start of every range it's asked for
+        let rpc =
+            spawn_logs_rpc(|from, _| json!({"result": [{"blockNumber": format!("0x{:x}", from)}]}))
+                .await;
+
+        let params = test_params();
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            rpc.url.clone(),
+            None,
+            1,
+            0,
+            10.0,
+        )]));
+
+        let logs_request = |id: u64, from: &str, to: &str| {
+            json!({
+                "id": id,
+                "jsonrpc": "2.0",
+                "method": "eth_getLogs",
+                "params": [{"address": "0xabc", "fromBlock": from, "toBlock": to}],
+            })
+        };
+
+        // Blocks 1500 to 3500 have a whole chunk from 2000 to 2999, and the ends around it
+        let (rax, _) = process_call(
+            logs_request(1, "0x5dc", "0xdac"),
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        assert_eq!(
+            str_to_value(&rax.unwrap()).unwrap(),
+            json!({"jsonrpc": "2.0", "id": 1, "result": [
+                {"blockNumber": "0x5dc"},
+                {"blockNumber": "0x7d0"},
+                {"blockNumber": "0xbb8"},
+            ]})
+        );
+        assert_eq!(rpc.requests(), 3);
+
+        // Only the chunks from 3000 and 4000 are new
+        let (rax, _) = process_call(
+            logs_request(2, "0x7d0", "0x1387"),
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        assert_eq!(
+            str_to_value(&rax.unwrap()).unwrap(),
+            json!({"jsonrpc": "2.0", "id": 2, "result": [
+                {"blockNumber": "0x7d0"},
+                {"blockNumber": "0xbb8"},
+                {"blockNumber": "0xfa0"},
+            ]})
+        );
+        assert_eq!(rpc.requests(), 5);
+
+        // Small ranges are sent as they are, even if they cross chunks
+        let (rax, _) = process_call(
+            logs_request(3, "0x7cf", "0x7d0"),
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        assert_eq!(
+            str_to_value(&rax.unwrap()).unwrap()["result"],
+            json!([{"blockNumber": "0x7cf"}])
+        );
+        assert_eq!(rpc.requests(), 6);
+
+        // Parts aren't finalized yet, so they get dropped if they reorg
+        let head_cache = head_cache.read().unwrap();
+        assert_eq!(
+            head_cache.keys().copied().collect::<Vec<_>>(),
+            [1999, 2000, 2999, 3500, 3999, 4999]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_call_logs_concurrency() {
+        let cache = create_test_cache();
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers {
+            latest: 100_000,
+            ..Default::default()
+        }));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        let rpc = MockRpc::spawn(|tx| {
+            MockResponse::ok(json!({"jsonrpc": "2.0", "id": tx["id"], "result": []}))
+                .delay(Duration::from_millis(100))
+        })
+        .await;
+        let params = test_params();
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            rpc.url.clone(),
+            None,
+            1,
+            0,
+            10.0,
+        )]));
+
+        // 8 chunks only go upstream a few at a time
+        let start = Instant::now();
+        let (rax, _) = process_call(
+            json!({
+                "id": 1,
+                "jsonrpc": "2.0",
+                "method": "eth_getLogs",
+                "params": [{"fromBlock": "0x0", "toBlock": "0x1f3f"}],
+            }),
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        assert_eq!(str_to_value(&rax.unwrap()).unwrap()["result"], json!([]));
+        assert_eq!(rpc.requests(), 8);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_process_call_logs_budget() {
+        let cache = create_test_cache();
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers {
+            latest: 100_000,
+            ..Default::default()
+        }));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        // RPC that refuses every range, so chunks would get bisected all the way down
+        let rpc = spawn_logs_rpc(|_, _| {
+            json!({"error": {"code": -32005, "message": "query returned more than 10000 results"}})
+        })
+        .await;
+        let params = test_params();
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            rpc.url.clone(),
+            None,
+            1,
+            0,
+            10.0,
+        )]));
+
+        // 20 chunks, and whatever halves fit in the rest of the budget
+        let (rax, _) = process_call(
+            json!({
+                "id": 1,
+                "jsonrpc": "2.0",
+                "method": "eth_getLogs",
+                "params": [{"fromBlock": "0x0", "toBlock": "0x4e1f"}],
+            }),
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        // Once it runs out, the refusal gets passed along
+        assert_eq!(
+            str_to_value(&rax.unwrap()).unwrap()["error"]["code"],
+            -32005
+        );
+        assert_eq!(rpc.requests(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_process_call_logs_bisect() {
+        let cache = create_test_cache();
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+
+        // RPC that refuses anything wider than 2 blocks
+        let rpc = spawn_logs_rpc(
+            |from, to| {
+                match to - from {
+                    0 | 1 => json!({"result": [{"blockNumber": format!("0x{:x}", from)}]}),
+                    _ => {
+                        json!({"error": {"code": -32005, "message": "query returned more than 10000 results"}})
+                    }
+                }
+            },
+        )
+        .await;
+
+        let params = RequestParams {
+            max_retries: 4,
+            ..test_params()
+        };
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
+            rpc.url.clone(),
+            None,
+            1,
+            0,
+            10.0,
+        )]));
+
+        let tx = json!({
+            "id": "logs",
+            "jsonrpc": "2.0",
+            "method": "eth_getLogs",
+            "params": [{"fromBlock": "0x0", "toBlock": "0x7"}],
+        });
+        let (rax, rpc_position) = process_call(
+            tx.clone(),
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        assert_eq!(
+            str_to_value(&rax.unwrap()).unwrap(),
+            json!({"jsonrpc": "2.0", "id": "logs", "result": [
+                {"blockNumber": "0x0"},
+                {"blockNumber": "0x2"},
+                {"blockNumber": "0x4"},
+                {"blockNumber": "0x6"},
+            ]})
+        );
+        assert_eq!(rpc_position, None);
+        // 0-7, then 0-3 and 4-7, then every pair. Refusals don't get retried.
+        assert_eq!(rpc.requests(), 7);
+
+        // Pairs that were answered are cached on their own
+        let (rax, _) = process_call(
+            tx,
+            &rpc_list,
+            &finalized_rx,
+            &named_numbers,
+            &head_cache,
+            &cache,
+            &params,
+        )
+        .await;
+        assert_eq!(
+            str_to_value(&rax.unwrap()).unwrap()["result"][3]["blockNumber"],
+            "0x6"
+        );
+        assert_eq!(rpc.requests(), 10);
+    }
+}
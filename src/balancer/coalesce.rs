@@ -0,0 +1,202 @@
+//! Single-flight coalescing for cache misses.
+//!
+//! When a new block lands, lots of clients tend to send the exact same request
+//! at the same time. Only the first one that misses the cache goes upstream,
+//! and every identical request that comes in while it's in flight waits for
+//! its response instead. Requests are identified by their `tx_hash`, so ids
+//! don't matter, and each waiter gets the response back with its own id.
+//!
+//! Only requests that read state get coalesced. Sharing the response to something
+//! like `eth_newFilter` or `eth_sendRawTransaction` would hand everyone the same
+//! filter, or tell them their transaction was sent when it wasn't.
+
+use crate::balancer::{
+    response_errors::JsonRpcError,
+    selection::cache_rules::CacheRules,
+};
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+};
+
+use blake3::Hash;
+use serde_json::Value;
+use tokio::sync::watch;
+
+pub type CallResult = Result<String, JsonRpcError>;
+
+// Methods that only read state, but don't get cached
+const READ_ONLY_METHODS: [&str; 9] = [
+    "eth_blockNumber",
+    "eth_chainId",
+    "net_version",
+    "web3_clientVersion",
+    "eth_gasPrice",
+    "eth_maxPriorityFeePerGas",
+    "eth_blobBaseFee",
+    "eth_feeHistory",
+    "eth_syncing",
+];
+
+/// Check if identical requests for `method` can share a single upstream request.
+///
+/// That's the case for methods we cache, and the read-only ones we don't.
+pub fn can_coalesce(method: &str, cache_rules: &CacheRules) -> bool {
+    cache_rules.get(method).cache || READ_ONLY_METHODS.contains(&method)
+}
+
+#[derive(Debug, Default)]
+pub struct InFlightCalls {
+    calls: Mutex<HashMap<Hash, watch::Receiver<Option<CallResult>>>>,
+}
+
+pub enum Flight<'a> {
+    // Nobody is sending this request yet, so it's up to us
+    Leader(FlightGuard<'a>),
+    // Someone else is already sending it
+    Follower(watch::Receiver<Option<CallResult>>),
+}
+
+impl InFlightCalls {
+    /// Join the in-flight request for `tx_hash`, or start one if there is none.
+    pub fn join(&self, tx_hash: Hash) -> Flight<'_> {
+        let mut calls = self.calls.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(rx) = calls.get(&tx_hash) {
+            return Flight::Follower(rx.clone());
+        }
+
+        let (tx, rx) = watch::channel(None);
+        calls.insert(tx_hash, rx);
+        Flight::Leader(FlightGuard {
+            calls: self,
+            tx_hash,
+            tx,
+        })
+    }
+}
+
+/// Wait for the leader's response and give it our `id`.
+///
+/// Returns `None` if the leader went away without a response,
+/// eg. because its client disconnected.
+pub async fn wait_for_leader(
+    mut rx: watch::Receiver<Option<CallResult>>,
+    id: Value,
+) -> Option<CallResult> {
+    let result = rx.wait_for(Option::is_some).await.ok()?.clone()?;
+    Some(match result {
+        Ok(response) => {
+            let mut response: Value = serde_json::from_str(&response).ok()?;
+            response["id"] = id;
+            Ok(response.to_string())
+        }
+        Err(err) => Err(err.with_id(id)),
+    })
+}
+
+/// Held by the request that is actually sent upstream.
+/// Followers stop waiting on it once it's dropped.
+pub struct FlightGuard<'a> {
+    calls: &'a InFlightCalls,
+    tx_hash: Hash,
+    tx: watch::Sender<Option<CallResult>>,
+}
+
+impl FlightGuard<'_> {
+    /// Hand `result` to everyone waiting on us.
+    pub fn finish(self, result: &CallResult) {
+        self.tx.send_replace(Some(result.clone()));
+    }
+}
+
+impl Drop for FlightGuard<'_> {
+    fn drop(&mut self) {
+        self.calls
+            .calls
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.tx_hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::response_errors::ErrorKind;
+    use blake3::hash;
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let in_flight = InFlightCalls::default();
+        let tx_hash = hash(b"eth_getBlockByNumber");
+
+        let leader = match in_flight.join(tx_hash) {
+            Flight::Leader(leader) => leader,
+            Flight::Follower(_) => panic!("first request should lead"),
+        };
+        let followers: Vec<_> = (0..3)
+            .map(|_| {
+                match in_flight.join(tx_hash) {
+                    Flight::Follower(rx) => rx,
+                    Flight::Leader(_) => panic!("identical request should follow"),
+                }
+            })
+            .collect();
+        // Different requests don't wait on each other
+        assert!(matches!(
+            in_flight.join(hash(b"eth_blockNumber")),
+            Flight::Leader(_)
+        ));
+
+        leader.finish(&Ok(r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#.to_string()));
+        assert!(matches!(in_flight.join(tx_hash), Flight::Leader(_)));
+
+        for (id, rx) in followers.into_iter().enumerate() {
+            let response = wait_for_leader(rx, id.into()).await.unwrap().unwrap();
+            let response: Value = serde_json::from_str(&response).unwrap();
+            assert_eq!(response["id"], id);
+            assert_eq!(response["result"], "0x1");
+        }
+    }
+
+    #[test]
+    fn test_can_coalesce() {
+        let cache_rules = CacheRules::default();
+        assert!(can_coalesce("eth_getBlockByNumber", &cache_rules));
+        assert!(can_coalesce("eth_blockNumber", &cache_rules));
+        assert!(!can_coalesce("eth_newBlockFilter", &cache_rules));
+        assert!(!can_coalesce("eth_sendRawTransaction", &cache_rules));
+        assert!(!can_coalesce("eth_getBlockByNumber", &CacheRules::empty()));
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_errors_and_cancellation() {
+        let in_flight = InFlightCalls::default();
+        let tx_hash = hash(b"eth_call");
+
+        // Errors are shared too
+        let Flight::Leader(leader) = in_flight.join(tx_hash) else {
+            panic!("first request should lead");
+        };
+        let Flight::Follower(rx) = in_flight.join(tx_hash) else {
+            panic!("identical request should follow");
+        };
+        leader.finish(&Err(JsonRpcError::new(ErrorKind::TimedOut, 1.into())));
+        assert_eq!(
+            wait_for_leader(rx, "abc".into()).await,
+            Some(Err(JsonRpcError::new(ErrorKind::TimedOut, "abc".into())))
+        );
+
+        // Leader went away without a response
+        let Flight::Leader(leader) = in_flight.join(tx_hash) else {
+            panic!("request should lead once the previous one is done");
+        };
+        let Flight::Follower(rx) = in_flight.join(tx_hash) else {
+            panic!("identical request should follow");
+        };
+        drop(leader);
+        assert_eq!(wait_for_leader(rx, 2.into()).await, None);
+        assert!(matches!(in_flight.join(tx_hash), Flight::Leader(_)));
+    }
+}
@@ -1,4 +1,7 @@
-use crate::NamedBlocknumbers;
+use crate::{
+    balancer::response_errors::ErrorKind,
+    NamedBlocknumbers,
+};
 use http_body_util::BodyExt;
 use hyper::{
     body::Incoming,
@@ -8,7 +11,6 @@ use memchr::memmem;
 use serde_json::{
     json,
     Value,
-    Value::Null,
 };
 use simd_json::serde::from_str;
 use std::{
@@ -50,6 +52,33 @@ fn has_named_number(param: &str) -> NamedNumber {
     NamedNumber::Null
 }
 
+/// Index of the block number in the params of `method`, if it takes one.
+pub fn block_param_position(method: &str) -> Option<usize> {
+    // The JSON-RPC standard is all over the place so depending on the method, we need to look at
+    // different param indexes. Why? Has i ever???
+    match method {
+        "eth_getBalance" | "eth_getTransactionCount" | "eth_getCode" | "eth_call" => Some(1),
+        "eth_getStorageAt" => Some(2),
+        "eth_getBlockTransactionCountByNumber"
+        | "eth_getUncleCountByBlockNumber"
+        | "eth_getBlockByNumber"
+        | "eth_getTransactionByBlockNumberAndIndex"
+        | "eth_getUncleByBlockNumberAndIndex" => Some(0),
+        _ => None,
+    }
+}
+
+/// Number or tag a block param points to, or `None` if it's pinned to a block hash.
+///
+/// Besides plain numbers and tags, block params can be EIP-1898 objects,
+/// like `{"blockNumber":"0x1"}` or `{"blockHash":"0x..","requireCanonical":true}`.
+pub fn block_number_param(param: &Value) -> Option<&Value> {
+    if param.get("blockHash").is_some() {
+        return None;
+    }
+    Some(param.get("blockNumber").unwrap_or(param))
+}
+
 /// Return the blocknumber from a json-rpc request as a Option<String>,
 /// returning None if it cant find anything.
 pub fn get_block_number_from_request(
@@ -66,24 +95,13 @@ pub fn get_block_number_from_request(
         return None;
     }
 
-    // The JSON-RPC standard is all over the place so depending on the method, we need to look at
-    // different param indexes. Why? Has i ever???
-    let position = match tx["method"].as_str() {
-        Some("eth_getBalance") => 1,
-        Some("eth_getStorageAt") => 2,
-        Some("eth_getTransactionCount") => 1,
-        Some("eth_getBlockTransactionCountByNumber") => 0,
-        Some("eth_getUncleCountByBlockNumber") => 0,
-        Some("eth_getCode") => 1,
-        Some("eth_call") => 1,
-        Some("eth_getBlockByNumber") => 0,
-        Some("eth_getTransactionByBlockNumberAndIndex") => 0,
-        Some("eth_getUncleByBlockNumberAndIndex") => 0,
-        _ => return None,
-    };
-
     // Get the corresponding blockbumber from the params
-    let block_number = tx["params"][position].to_string().replace('\"', "");
+    let block_number = match tx["method"].as_str()? {
+        // Logs depend on every block in their range, so the last one is what counts
+        "eth_getLogs" => tx["params"][0]["toBlock"].to_string(),
+        method => block_number_param(&tx["params"][block_param_position(method)?])?.to_string(),
+    };
+    let block_number = block_number.replace('\"', "");
 
     // Return the corresponding named parameter from the RwLock is present
     let nn = has_named_number(&block_number);
@@ -101,7 +119,7 @@ pub fn get_block_number_from_request(
     }
 
     // Convert to decimal
-    let block_number = match u64::from_str_radix(&block_number[2..], 16) {
+    let block_number = match u64::from_str_radix(block_number.get(2..)?, 16) {
         Ok(block_number) => block_number,
         Err(_) => return None,
     };
@@ -109,6 +127,38 @@ pub fn get_block_number_from_request(
     Some(block_number)
 }
 
+/// Methods that take a hash instead of a block, so only their response says which block it's for.
+pub const HASH_METHODS: [&str; 5] = [
+    "eth_getTransactionReceipt",
+    "eth_getTransactionByHash",
+    "eth_getBlockByHash",
+    "debug_traceTransaction",
+    "trace_transaction",
+];
+
+/// Return the block a response to one of the `HASH_METHODS` is from, as a Option<u64>.
+///
+/// Returns None if the response doesn't say, eg. for transactions that are still pending,
+/// or for `debug_traceTransaction` whose traces don't include their block.
+pub fn get_block_number_from_response(method: &str, rx: &Value) -> Option<u64> {
+    let block_number = match method {
+        "eth_getTransactionReceipt" | "eth_getTransactionByHash" => &rx["result"]["blockNumber"],
+        "eth_getBlockByHash" => &rx["result"]["number"],
+        // Every trace of the transaction is from the same block
+        "trace_transaction" => &rx["result"][0]["blockNumber"],
+        _ => return None,
+    };
+
+    // Parity style traces have their block as a plain number
+    match block_number {
+        Value::Number(block_number) => block_number.as_u64(),
+        Value::String(block_number) => {
+            u64::from_str_radix(block_number.strip_prefix("0x")?, 16).ok()
+        }
+        _ => None,
+    }
+}
+
 /// Replaces block tags with a hex number and return the request
 pub fn replace_block_tags(
     tx: &mut Value,
@@ -120,74 +170,84 @@ pub fn replace_block_tags(
         return tx.to_owned();
     }
 
-    // Determine the correct parameter index based on the method
-    let position = match tx["method"].as_str() {
-        Some("eth_getBalance")
-        | Some("eth_getTransactionCount")
-        | Some("eth_getCode")
-        | Some("eth_call") => 1,
-        Some("eth_getStorageAt") => 2,
-        Some("eth_getBlockTransactionCountByNumber")
-        | Some("eth_getUncleCountByBlockNumber")
-        | Some("eth_getBlockByNumber")
-        | Some("eth_getTransactionByBlockNumberAndIndex")
-        | Some("eth_getUncleByBlockNumberAndIndex") => 0,
-        _ => return tx.to_owned(),
-    };
-
-    // Extract the block number parameter
-    let block_number = tx["params"][position].to_string().replace('\"', "");
-
-    // Check if the block number is a named tag
-    let nn = has_named_number(&block_number);
-    if nn != NamedNumber::Null {
-        let rwlock_guard = named_blocknumbers.read().unwrap_or_else(|e| {
-            // Handle the case where the RwLock is poisoned
-            e.into_inner()
-        });
-
-        // Replace the named block tag with its corresponding hex value
-        match nn {
-            NamedNumber::Latest => {
-                if rwlock_guard.latest != 0 {
-                    tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.latest));
+    let rwlock_guard = named_blocknumbers.read().unwrap_or_else(|e| {
+        // Handle the case where the RwLock is poisoned
+        e.into_inner()
+    });
+
+    match tx["method"].as_str() {
+        // Logs take a range instead, and either end can be a tag
+        Some("eth_getLogs") => {
+            for key in ["fromBlock", "toBlock"] {
+                if let Some(block) = tx["params"][0].get_mut(key) {
+                    replace_block_tag(block, &rwlock_guard);
                 }
             }
-            NamedNumber::Finalized => {
-                if rwlock_guard.finalized != 0 {
-                    tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.finalized));
+        }
+        // Determine the correct parameter index based on the method
+        Some(method) => {
+            if let Some(position) = block_param_position(method) {
+                let block = &mut tx["params"][position];
+                // EIP-1898 objects pinned to a number can still use a tag
+                match block.get_mut("blockNumber") {
+                    Some(block) => replace_block_tag(block, &rwlock_guard),
+                    None => replace_block_tag(block, &rwlock_guard),
                 }
             }
-            _ => (),
         }
+        None => (),
     }
 
     tx.to_owned()
 }
 
+/// Replaces `block` with its hex number if it's a tag we know the number of.
+fn replace_block_tag(block: &mut Value, named_blocknumbers: &NamedBlocknumbers) {
+    // Check if the block number is a named tag
+    let nn = match block.as_str() {
+        Some(block) => has_named_number(block),
+        None => return,
+    };
+
+    // Replace the named block tag with its corresponding hex value
+    match nn {
+        NamedNumber::Latest if named_blocknumbers.latest != 0 => {
+            *block = json!(format!("0x{:x}", named_blocknumbers.latest));
+        }
+        NamedNumber::Finalized if named_blocknumbers.finalized != 0 => {
+            *block = json!(format!("0x{:x}", named_blocknumbers.finalized));
+        }
+        _ => (),
+    }
+}
+
+/// Parses a JSON string into a `serde_json::Value`.
+///
+/// `simd_json` is used when possible. Inputs it rejects, like integers
+/// that don't fit into a u64, are retried with `serde_json`. Its
+/// `arbitrary_precision` feature keeps such numbers as the exact digits
+/// the client sent, so ids and params are echoed back unchanged.
+pub fn str_to_value(tx: &str) -> Option<Value> {
+    let mut buf = tx.to_owned();
+
+    match unsafe { from_str(&mut buf) } {
+        Ok(ret) => Some(ret),
+        Err(_) => serde_json::from_str(tx).ok(),
+    }
+}
+
 /// *Converts* a hyper `Incoming` request to a `serde_json::Value`.
-pub async fn incoming_to_value(tx: Request<Incoming>) -> Result<Value, hyper::Error> {
+pub async fn incoming_to_value(tx: Request<Incoming>) -> Result<Value, ErrorKind> {
     #[cfg(feature = "debug-verbose")]
     println!("Incoming request: {:?}", tx);
 
-    let tx = tx.collect().await?.to_bytes().clone();
-    let mut tx = from_utf8(&tx).unwrap().to_owned();
-
-    let ret = match unsafe { from_str(&mut tx) } {
-        Ok(ret) => ret,
-        Err(_) => {
-            // Insane error handling
-            let ret = json!({
-                "id": Null,
-                "jsonrpc": "2.0",
-                "result": "Invalid JSON",
-            });
-
-            return Ok(ret);
-        }
+    let tx = match tx.collect().await {
+        Ok(tx) => tx.to_bytes(),
+        Err(_) => return Err(ErrorKind::InternalError),
     };
+    let tx = from_utf8(&tx).map_err(|_| ErrorKind::ParseError)?;
 
-    Ok(ret)
+    str_to_value(tx).ok_or(ErrorKind::ParseError)
 }
 
 #[cfg(test)]
@@ -416,6 +476,38 @@ mod tests {
         );
     }
 
+    #[test]
+    fn get_block_number_from_response_test() {
+        let receipt = json!({"id": 1, "jsonrpc": "2.0", "result": {"blockNumber": "0x10"}});
+        assert_eq!(
+            get_block_number_from_response("eth_getTransactionReceipt", &receipt),
+            Some(16)
+        );
+        let block = json!({"id": 1, "jsonrpc": "2.0", "result": {"number": "0x11"}});
+        assert_eq!(
+            get_block_number_from_response("eth_getBlockByHash", &block),
+            Some(17)
+        );
+        let traces = json!({"id": 1, "jsonrpc": "2.0", "result": [{"blockNumber": 18}]});
+        assert_eq!(
+            get_block_number_from_response("trace_transaction", &traces),
+            Some(18)
+        );
+
+        // Pending transactions, traces without a block and other methods
+        let pending = json!({"id": 1, "jsonrpc": "2.0", "result": {"blockNumber": null}});
+        assert_eq!(
+            get_block_number_from_response("eth_getTransactionByHash", &pending),
+            None
+        );
+        let trace = json!({"id": 1, "jsonrpc": "2.0", "result": {"structLogs": []}});
+        assert_eq!(
+            get_block_number_from_response("debug_traceTransaction", &trace),
+            None
+        );
+        assert_eq!(get_block_number_from_response("eth_chainId", &block), None);
+    }
+
     #[test]
     fn replace_named_block_number_test() {
         let named_blocknumbers = dummy_named_blocknumbers();
@@ -432,6 +524,61 @@ mod tests {
         assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), expected);
     }
 
+    #[test]
+    fn eip1898_block_params_test() {
+        let named_blocknumbers = dummy_named_blocknumbers();
+
+        let mut tx = json!({
+            "method": "eth_call",
+            "params": [{"to": "0xabc"}, {"blockNumber": "latest"}]
+        });
+        let expected = json!({
+            "method": "eth_call",
+            "params": [{"to": "0xabc"}, {"blockNumber": "0xa"}]
+        });
+        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), expected);
+        assert_eq!(
+            get_block_number_from_request(expected, &named_blocknumbers),
+            Some(10)
+        );
+
+        // Hashes aren't associated with any number
+        let mut tx = json!({
+            "method": "eth_getBalance",
+            "params": ["0xabc", {"blockHash": "0xdef", "requireCanonical": true}]
+        });
+        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
+        assert_eq!(get_block_number_from_request(tx, &named_blocknumbers), None);
+    }
+
+    #[test]
+    fn replace_logs_block_tags_test() {
+        let named_blocknumbers = dummy_named_blocknumbers();
+        let mut tx = json!({
+            "method": "eth_getLogs",
+            "params": [{"address": "0xabc", "fromBlock": "0x1", "toBlock": "latest"}]
+        });
+
+        let expected = json!({
+            "method": "eth_getLogs",
+            "params": [{"address": "0xabc", "fromBlock": "0x1", "toBlock": "0xa"}]
+        });
+
+        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), expected);
+        assert_eq!(
+            get_block_number_from_request(expected, &named_blocknumbers),
+            Some(10)
+        );
+
+        // Missing ends are left alone
+        let mut tx = json!({
+            "method": "eth_getLogs",
+            "params": [{"blockHash": "0xabc"}]
+        });
+        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
+        assert_eq!(get_block_number_from_request(tx, &named_blocknumbers), None);
+    }
+
     #[test]
     fn keep_hex_block_number_test() {
         let named_blocknumbers = dummy_named_blocknumbers();
@@ -525,4 +672,29 @@ mod tests {
 
         assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
     }
+
+    #[test]
+    fn str_to_value_test() {
+        let tx = str_to_value(r#"{"id":"abc-1","method":"eth_chainId"}"#).unwrap();
+        assert_eq!(tx["id"], json!("abc-1"));
+
+        let tx = str_to_value(r#"{"id":18446744073709551615}"#).unwrap();
+        assert_eq!(tx.to_string(), r#"{"id":18446744073709551615}"#);
+
+        // simd_json rejects integers that don't fit in a u64, serde_json keeps their digits
+        let raw = r#"{"id":123456789012345678901234567890}"#;
+        let tx = str_to_value(raw).unwrap();
+        assert_eq!(tx.to_string(), raw);
+        let raw = r#"{"id":-123456789012345678901234567890,"params":[1.50]}"#;
+        assert_eq!(str_to_value(raw).unwrap().to_string(), raw);
+
+        // Numbers that do fit read and compare the same as before
+        let tx = str_to_value(r#"{"id":7,"neg":-7,"float":1.5,"params":[0]}"#).unwrap();
+        assert_eq!(tx["id"].as_u64(), Some(7));
+        assert_eq!(tx["neg"].as_i64(), Some(-7));
+        assert_eq!(tx["float"].as_f64(), Some(1.5));
+        assert_eq!(tx, json!({"id": 7, "neg": -7, "float": 1.5, "params": [0]}));
+
+        assert!(str_to_value("{\"id\":").is_none());
+    }
 }
@@ -0,0 +1,233 @@
+//! Hedged requests, for cutting tail latency.
+//!
+//! If the RPC we sent a request to hasn't answered within the method's
+//! `hedge_after`, we send the same request to a second RPC. Whichever one
+//! answers first wins, and the other request gets cancelled. A hedge that
+//! fails never wins, we just keep waiting on the first RPC instead.
+//!
+//! The second RPC is picked like any other, so it has to be below its
+//! `max_per_second` and have a closed circuit breaker. If none is available
+//! right away we keep waiting on the first one instead.
+
+use crate::{
+    balancer::selection::{
+        method_policy::HedgeAfter,
+        select::pick_except,
+        strategy::SelectionStrategy,
+    },
+    log_info,
+    log_wrn,
+    rpc::error::RpcError,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Instant,
+};
+
+use serde_json::Value;
+use tokio::time::timeout;
+
+/// Send `tx` to `rpc`, hedging with another RPC if it's slow.
+///
+/// Returns the RPC that answered along with its position if it was the hedge,
+/// in which case the latency of both RPCs has already been recorded.
+pub async fn send_hedged(
+    rpc: &Rpc,
+    position: usize,
+    tx: &Value,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    strategy: &dyn SelectionStrategy,
+    top_tier: u32,
+    hedge_after: Option<HedgeAfter>,
+) -> (Option<(Rpc, usize)>, Result<String, RpcError>) {
+    let start = Instant::now();
+    let request = rpc.send_request(tx.clone());
+    tokio::pin!(request);
+
+    let delay = match hedge_after.and_then(|hedge_after| hedge_after.delay(rpc)) {
+        Some(delay) => delay,
+        None => return (None, request.await),
+    };
+
+    if let Ok(result) = timeout(delay, &mut request).await {
+        return (None, result);
+    }
+
+    let picked = {
+        let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+        match pick_except(&rpc_list, strategy, top_tier, position) {
+            (hedge, Some(hedge_position)) => {
+                let in_flight = hedge.track_in_flight();
+                Some((hedge, hedge_position, in_flight))
+            }
+            (_, None) => None,
+        }
+    };
+    let (hedge, hedge_position, _in_flight) = match picked {
+        Some(picked) => picked,
+        None => return (None, request.await),
+    };
+
+    log_info!(
+        "{} is taking over {:?}, hedging with {}.",
+        rpc.name,
+        delay,
+        hedge.name
+    );
+    hedge.record_hedge(false);
+    let hedge_start = Instant::now();
+
+    tokio::select! {
+        result = &mut request => (None, result),
+        result = hedge.send_request(tx.clone()) => {
+            // A hedge that failed doesn't win anything, so keep waiting on the first one
+            let result = match result {
+                Ok(result) => result,
+                Err(err) => {
+                    log_wrn!("Hedge to {} failed: {}", hedge.name, err);
+                    return (None, request.await);
+                }
+            };
+
+            hedge.record_hedge(true);
+            hedge.update_latency(hedge_start.elapsed().as_nanos() as f64);
+            // We don't know how long the slow one would have taken, but it's at least this long
+            rpc.update_latency(start.elapsed().as_nanos() as f64);
+            (Some((hedge, hedge_position)), Ok(result))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::{
+        selection::strategy::WeightedRoundRobin,
+        test_utils::{
+            refused_url,
+            MockResponse,
+            MockRpc,
+        },
+    };
+    use std::time::Duration;
+
+    // Fake RPC that answers with `result` after `delay`
+    async fn spawn_slow_rpc(delay: Duration, result: &'static str) -> Rpc {
+        let rpc = MockRpc::spawn(move |_| {
+            MockResponse::ok(format!(
+                r#"{{"jsonrpc":"2.0","id":1,"result":"{}"}}"#,
+                result
+            ))
+            .delay(delay)
+        })
+        .await;
+
+        Rpc::new(rpc.url, None, 10, 0, 10.0)
+    }
+
+    #[tokio::test]
+    async fn test_hedge_wins() {
+        let slow = spawn_slow_rpc(Duration::from_secs(5), "slow").await;
+        let fast = spawn_slow_rpc(Duration::ZERO, "fast").await;
+        let rpc_list = Arc::new(RwLock::new(vec![slow.clone(), fast]));
+        let tx = serde_json::json!({"id": 1, "jsonrpc": "2.0", "method": "eth_call"});
+
+        let start = Instant::now();
+        let (hedge, result) = send_hedged(
+            &slow,
+            0,
+            &tx,
+            &rpc_list,
+            &WeightedRoundRobin::default(),
+            0,
+            Some(HedgeAfter::Delay(Duration::from_millis(50))),
+        )
+        .await;
+
+        assert!(result.unwrap().contains("fast"));
+        assert_eq!(hedge.unwrap().1, 1);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list[1].status.hedged(), 1);
+        assert_eq!(rpc_list[1].status.hedge_wins(), 1);
+        assert_eq!(rpc_list[1].in_flight(), 0);
+        assert!(rpc_list[0].status.latency() >= 50_000_000.0);
+    }
+
+    #[tokio::test]
+    async fn test_hedge_loses() {
+        let primary = spawn_slow_rpc(Duration::from_millis(100), "primary").await;
+        let hedge = spawn_slow_rpc(Duration::from_secs(5), "hedge").await;
+        let rpc_list = Arc::new(RwLock::new(vec![primary.clone(), hedge]));
+        let tx = serde_json::json!({"id": 1, "jsonrpc": "2.0", "method": "eth_call"});
+
+        let (winner, result) = send_hedged(
+            &primary,
+            0,
+            &tx,
+            &rpc_list,
+            &WeightedRoundRobin::default(),
+            0,
+            Some(HedgeAfter::Delay(Duration::from_millis(10))),
+        )
+        .await;
+
+        assert!(result.unwrap().contains("primary"));
+        assert!(winner.is_none());
+
+        {
+            let rpc_list = rpc_list.read().unwrap();
+            assert_eq!(rpc_list[1].status.hedged(), 1);
+            assert_eq!(rpc_list[1].status.hedge_wins(), 0);
+            assert_eq!(rpc_list[1].in_flight(), 0);
+        }
+
+        // Nothing else to hedge with
+        let (winner, result) = send_hedged(
+            &primary,
+            0,
+            &tx,
+            &Arc::new(RwLock::new(vec![primary.clone()])),
+            &WeightedRoundRobin::default(),
+            0,
+            Some(HedgeAfter::Delay(Duration::from_millis(10))),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert!(winner.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_hedge_fails() {
+        let primary = spawn_slow_rpc(Duration::from_millis(100), "primary").await;
+        let refused = Rpc::new(refused_url().await, None, 10, 0, 10.0);
+        let rpc_list = Arc::new(RwLock::new(vec![primary.clone(), refused]));
+        let tx = serde_json::json!({"id": 1, "jsonrpc": "2.0", "method": "eth_call"});
+
+        let (winner, result) = send_hedged(
+            &primary,
+            0,
+            &tx,
+            &rpc_list,
+            &WeightedRoundRobin::default(),
+            0,
+            Some(HedgeAfter::Delay(Duration::from_millis(10))),
+        )
+        .await;
+
+        // The hedge got refused right away, but the first RPC still answers
+        assert!(result.unwrap().contains("primary"));
+        assert!(winner.is_none());
+
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list[1].status.hedged(), 1);
+        assert_eq!(rpc_list[1].status.hedge_wins(), 0);
+        assert_eq!(rpc_list[1].in_flight(), 0);
+    }
+}
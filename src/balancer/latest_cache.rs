@@ -0,0 +1,186 @@
+//! In-memory cache for requests at the head of the chain.
+//!
+//! Requests for `latest` get rewritten to the block number of the current head,
+//! and lots of clients tend to ask for the same head state over and over until
+//! the next block lands. Their responses are kept here, keyed by the rewritten
+//! request, and everything gets dropped as soon as we see a new head.
+//!
+//! These never go in the DB, since the head is the block most likely to reorg
+//! and nobody is going to ask for it by number once it's not the head anymore.
+//!
+//! Heads are told apart by their hash as well as their number, so a response
+//! that was in flight while the head reorged at the same height gets dropped.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+
+use blake3::Hash;
+
+/// Block at the head of the chain.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Head {
+    pub number: u64,
+    pub hash: String,
+}
+
+impl Head {
+    pub fn new(number: u64, hash: impl Into<String>) -> Self {
+        Self {
+            number,
+            hash: hash.into(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    // Head all of the responses below are for, number 0 if we don't know it
+    head: Head,
+    map: HashMap<Hash, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct LatestCache {
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl LatestCache {
+    /// Response for `tx_hash`, if we have one for `block`.
+    pub fn get(&self, tx_hash: &Hash, block: u64) -> Option<String> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        let response = match entries.head.number == block {
+            true => entries.map.get(tx_hash).cloned(),
+            false => None,
+        };
+
+        let counter = match response {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        response
+    }
+
+    /// Keep `response` around until the head moves on from `head`,
+    /// the head we sent the request at.
+    ///
+    /// Responses for any head other than the current one are dropped, eg. because
+    /// a new head landed or the head reorged while the request was in flight.
+    pub fn insert(&self, tx_hash: Hash, response: String, head: &Head) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if head.number != 0 && entries.head == *head {
+            entries.map.insert(tx_hash, response);
+        }
+    }
+
+    /// Drop everything we have, since `head` is the new head.
+    ///
+    /// Also called when the head stays at the same height, as that means it reorged.
+    pub fn advance(&self, head: Head) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.head = head;
+        entries.map.clear();
+    }
+
+    /// Current head, to insert responses with once they come back.
+    pub fn head(&self) -> Head {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .head
+            .clone()
+    }
+
+    /// Number of the head the cached responses are for.
+    pub fn block(&self) -> u64 {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .head
+            .number
+    }
+
+    pub fn entries(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .map
+            .len()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blake3::hash;
+
+    #[test]
+    fn test_latest_cache() {
+        let cache = LatestCache::default();
+        let tx_hash = hash(b"eth_getBalance 0x10");
+
+        // We don't know the head yet
+        cache.insert(tx_hash, "0x1".to_string(), &cache.head());
+        assert_eq!(cache.entries(), 0);
+
+        cache.advance(Head::new(16, "0xa"));
+        assert_eq!(cache.get(&tx_hash, 16), None);
+        cache.insert(tx_hash, "0x1".to_string(), &Head::new(16, "0xa"));
+        assert_eq!(cache.get(&tx_hash, 16), Some("0x1".to_string()));
+
+        // Response for a head that's already gone
+        cache.insert(
+            hash(b"eth_getBalance 0xf"),
+            "0x2".to_string(),
+            &Head::new(15, "0x9"),
+        );
+        assert_eq!(cache.entries(), 1);
+
+        // New head, or a reorg at the same height
+        cache.advance(Head::new(16, "0xb"));
+        assert_eq!(cache.get(&tx_hash, 16), None);
+        cache.insert(tx_hash, "0x3".to_string(), &Head::new(16, "0xb"));
+        cache.advance(Head::new(17, "0xc"));
+        assert_eq!(cache.get(&tx_hash, 16), None);
+        assert_eq!(cache.entries(), 0);
+        assert_eq!(cache.block(), 17);
+
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 3);
+    }
+
+    #[test]
+    fn test_latest_cache_same_height_reorg() {
+        let cache = LatestCache::default();
+        let tx_hash = hash(b"eth_getBalance 0x10");
+
+        // Request sent at the head that then got orphaned
+        cache.advance(Head::new(16, "0xa"));
+        let sent_at = cache.head();
+        cache.advance(Head::new(16, "0xb"));
+
+        cache.insert(tx_hash, "0x1".to_string(), &sent_at);
+        assert_eq!(cache.get(&tx_hash, 16), None);
+        assert_eq!(cache.entries(), 0);
+    }
+}
@@ -0,0 +1,538 @@
+//! Caching `eth_getLogs` by block range.
+//!
+//! Caching a whole `eth_getLogs` request only helps clients that send the exact
+//! same filter again, and block explorers or indexers rarely do. Instead, ranges
+//! get split along fixed-size chunks aligned to `LOGS_CHUNK_SIZE`, and each part
+//! is requested and cached on its own with the same address and topics.
+//! Overlapping queries then only have to fetch the chunks nobody asked for yet,
+//! and the logs of every part get stitched back together in order.
+//!
+//! Parts are never wider than the range we were asked for, so the ends of a
+//! range are sent as they are, and ranges without a whole chunk in them aren't
+//! split at all. Parts of a single request only go upstream a few at a time.
+//!
+//! Some RPCs refuse ranges with too many logs in them, or that are just too wide.
+//! Those ranges get bisected, and the halves are sent again, possibly to
+//! different RPCs. Each half is cached on its own like any other request.
+//! Chunks and halves share a budget, so once a request went through
+//! `MAX_LOGS_PARTS` of them, refusals get passed to the client instead.
+
+use crate::balancer::response_errors::{
+    ErrorKind,
+    JsonRpcError,
+};
+
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use tokio::sync::{
+    Semaphore,
+    SemaphorePermit,
+};
+
+/// Amount of blocks in each cached chunk.
+pub const LOGS_CHUNK_SIZE: u64 = 1000;
+
+// Ranges spanning more chunks than this are sent as is
+const MAX_LOGS_CHUNKS: u64 = 20;
+
+// Parts of a single request that can be in flight at once
+const MAX_CONCURRENT_LOGS_PARTS: usize = 4;
+
+// Parts a single request can turn into, counting chunks and halves alike
+const MAX_LOGS_PARTS: usize = 64;
+
+/// Amount of times a range can be split in half, so a single request
+/// can't turn into more than `2^MAX_BISECT_DEPTH` of them.
+pub const MAX_BISECT_DEPTH: u32 = 6;
+
+/// An `eth_getLogs` range split into chunk requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogsRange {
+    pub from: u64,
+    pub to: u64,
+    // Requests for each part of the range, in order
+    pub chunks: Vec<Value>,
+}
+
+/// Limits on the upstream requests a single `eth_getLogs` turns into,
+/// shared by all of its chunks and their halves.
+#[derive(Debug)]
+pub struct LogsBudget {
+    slots: Semaphore,
+    // Parts that can still be sent
+    parts: AtomicUsize,
+}
+
+impl Default for LogsBudget {
+    fn default() -> Self {
+        Self {
+            slots: Semaphore::new(MAX_CONCURRENT_LOGS_PARTS),
+            parts: AtomicUsize::new(MAX_LOGS_PARTS),
+        }
+    }
+}
+
+impl LogsBudget {
+    /// Wait until another part can be sent, and hold on to the returned
+    /// permit for as long as it's in flight.
+    pub async fn slot(&self) -> SemaphorePermit<'_> {
+        // We never close the semaphore
+        self.slots.acquire().await.unwrap()
+    }
+
+    /// Take `parts` more parts out of the budget, if there are that many left.
+    pub fn take(&self, parts: usize) -> bool {
+        self.parts
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(parts)
+            })
+            .is_ok()
+    }
+}
+
+// Block numbers have to be hex, tags get replaced before we get here
+fn block_number(block: &Value) -> Option<u64> {
+    let block = block.as_str()?.strip_prefix("0x")?;
+    u64::from_str_radix(block, 16).ok()
+}
+
+// Addresses and topics are case insensitive, and the order of alternatives doesn't matter
+fn normalize_alternatives(value: &Value) -> Value {
+    match value {
+        Value::String(value) => Value::String(value.to_lowercase()),
+        Value::Array(values) => {
+            let mut values: Vec<String> = values
+                .iter()
+                .filter_map(|value| value.as_str().map(str::to_lowercase))
+                .collect();
+            values.sort_unstable();
+            values.dedup();
+            values.into()
+        }
+        value => value.clone(),
+    }
+}
+
+/// Filter of `tx` with everything that doesn't change its logs stripped,
+/// so identical filters always get the same cache key.
+fn normalize_filter(filter: &Map<String, Value>) -> Map<String, Value> {
+    let mut normalized = Map::new();
+
+    if let Some(address) = filter.get("address").filter(|address| !address.is_null()) {
+        normalized.insert("address".to_string(), normalize_alternatives(address));
+    }
+
+    if let Some(topics) = filter.get("topics").and_then(Value::as_array) {
+        let mut topics: Vec<Value> = topics.iter().map(normalize_alternatives).collect();
+        // Trailing wildcards match anything anyway
+        while topics.last().is_some_and(Value::is_null) {
+            topics.pop();
+        }
+        if !topics.is_empty() {
+            normalized.insert("topics".to_string(), topics.into());
+        }
+    }
+
+    normalized
+}
+
+/// Split `tx` into chunk requests if it's an `eth_getLogs` we can cache in chunks.
+/// The chunk requests have a null id, like every request before it's hashed.
+///
+/// Only ranges that end at or below `latest` are split, since chunks past
+/// the head would be missing logs we don't know about yet. Chunks at either end
+/// of the range are clipped to it, so we never ask for blocks nobody wanted.
+///
+/// Returns `None` if `tx` can't be split, or doesn't have a whole chunk in it.
+pub fn split_logs(tx: &Value, latest: u64) -> Option<LogsRange> {
+    if tx["method"] != "eth_getLogs" {
+        return None;
+    }
+
+    let filter = tx["params"][0].as_object()?;
+    if filter.contains_key("blockHash") {
+        return None;
+    }
+
+    let from = block_number(filter.get("fromBlock")?)?;
+    let to = block_number(filter.get("toBlock")?)?;
+    if latest == 0 || from > to || to > latest {
+        return None;
+    }
+
+    let first = from / LOGS_CHUNK_SIZE;
+    let last = to / LOGS_CHUNK_SIZE;
+    if last - first >= MAX_LOGS_CHUNKS {
+        return None;
+    }
+
+    let filter = normalize_filter(filter);
+    let mut whole_chunks = 0;
+    let chunks: Vec<(u64, u64)> = (first..=last)
+        .map(|chunk| {
+            let start = chunk * LOGS_CHUNK_SIZE;
+            let end = start + LOGS_CHUNK_SIZE - 1;
+            if start >= from && end <= to {
+                whole_chunks += 1;
+            }
+            (start.max(from), end.min(to))
+        })
+        .collect();
+
+    // Only whole chunks can be shared with other ranges, and splitting
+    // a chunk that is the whole range doesn't do anything
+    if whole_chunks == 0 || chunks.len() == 1 {
+        return None;
+    }
+
+    let chunks = chunks
+        .into_iter()
+        .map(|(start, end)| {
+            let mut filter = filter.clone();
+            filter.insert("fromBlock".to_string(), format!("0x{:x}", start).into());
+            filter.insert("toBlock".to_string(), format!("0x{:x}", end).into());
+            json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "method": "eth_getLogs",
+                "params": [filter],
+            })
+        })
+        .collect();
+
+    Some(LogsRange { from, to, chunks })
+}
+
+/// Split the range of `tx` in half, if it's an `eth_getLogs` spanning more than one block.
+/// The halves have a null id, like every request before it's hashed.
+pub fn bisect_logs(tx: &Value) -> Option<[Value; 2]> {
+    if tx["method"] != "eth_getLogs" {
+        return None;
+    }
+
+    let filter = tx["params"][0].as_object()?;
+    let from = block_number(filter.get("fromBlock")?)?;
+    let to = block_number(filter.get("toBlock")?)?;
+    if from >= to {
+        return None;
+    }
+
+    let middle = from + (to - from) / 2;
+    Some([(from, middle), (middle + 1, to)].map(|(start, end)| {
+        let mut half = tx.clone();
+        half["id"] = Value::Null;
+        half["params"][0]["fromBlock"] = format!("0x{:x}", start).into();
+        half["params"][0]["toBlock"] = format!("0x{:x}", end).into();
+        half
+    }))
+}
+
+/// Concatenate the logs of `responses` into a single response with `id`.
+///
+/// Only logs `keep` returns true for are kept. If any response failed, its error
+/// is returned instead, and a response that isn't a JSON-RPC object is an upstream
+/// error, so logs never go missing without the client knowing.
+fn concat_logs(
+    responses: Vec<Result<String, JsonRpcError>>,
+    id: Value,
+    keep: impl Fn(&Value) -> bool,
+) -> Result<String, JsonRpcError> {
+    let mut logs = Vec::new();
+
+    for response in responses {
+        let response = response.map_err(|err| err.with_id(id.clone()))?;
+        let mut response: Value = match serde_json::from_str(&response) {
+            Ok(response @ Value::Object(_)) => response,
+            _ => {
+                return Err(JsonRpcError::new(ErrorKind::UpstreamError, id).with_data(response));
+            }
+        };
+
+        let part_logs = match response["result"].take() {
+            Value::Array(part_logs) => part_logs,
+            // Pass upstream errors on as they are
+            _ => {
+                response["id"] = id;
+                return Ok(response.to_string());
+            }
+        };
+
+        logs.extend(part_logs.into_iter().filter(|log| keep(log)));
+    }
+
+    Ok(json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": logs,
+    })
+    .to_string())
+}
+
+/// Merge the `responses` to the halves of a bisected range into a single response with `id`.
+pub fn merge_logs(
+    responses: Vec<Result<String, JsonRpcError>>,
+    id: Value,
+) -> Result<String, JsonRpcError> {
+    concat_logs(responses, id, |_| true)
+}
+
+impl LogsRange {
+    /// Stitch the `responses` to our chunks into a single response with `id`.
+    ///
+    /// Logs outside of the range we were asked for are dropped, in case an RPC sends
+    /// any. If any chunk failed or isn't JSON, an error is returned instead.
+    pub fn stitch(
+        &self,
+        responses: Vec<Result<String, JsonRpcError>>,
+        id: Value,
+    ) -> Result<String, JsonRpcError> {
+        concat_logs(responses, id, |log| {
+            block_number(&log["blockNumber"])
+                .is_some_and(|block| block >= self.from && block <= self.to)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logs_request(from: u64, to: u64) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_getLogs",
+            "params": [{
+                "address": ["0xBB", "0xaa", "0xbb"],
+                "topics": ["0xDDF2", null],
+                "fromBlock": format!("0x{:x}", from),
+                "toBlock": format!("0x{:x}", to),
+            }],
+        })
+    }
+
+    fn chunk_range(chunk: &Value) -> (String, String) {
+        let filter = &chunk["params"][0];
+        (
+            filter["fromBlock"].as_str().unwrap().to_string(),
+            filter["toBlock"].as_str().unwrap().to_string(),
+        )
+    }
+
+    #[test]
+    fn test_split_logs() {
+        let range = split_logs(&logs_request(1500, 3200), 10_000).unwrap();
+        assert_eq!((range.from, range.to), (1500, 3200));
+
+        // Only the chunk in the middle is whole
+        let ranges: Vec<_> = range.chunks.iter().map(chunk_range).collect();
+        assert_eq!(
+            ranges,
+            [
+                ("0x5dc".to_string(), "0x7cf".to_string()),
+                ("0x7d0".to_string(), "0xbb7".to_string()),
+                ("0xbb8".to_string(), "0xc80".to_string()),
+            ]
+        );
+
+        // Whole chunks of overlapping ranges are the same
+        let other = split_logs(&logs_request(2000, 4100), 10_000).unwrap();
+        assert_eq!(other.chunks[0], range.chunks[1]);
+
+        assert_eq!(
+            range.chunks[0]["params"][0],
+            json!({
+                "address": ["0xaa", "0xbb"],
+                "topics": ["0xddf2"],
+                "fromBlock": "0x5dc",
+                "toBlock": "0x7cf",
+            })
+        );
+    }
+
+    #[test]
+    fn test_split_logs_head() {
+        // The chunk with the head in it only goes as far as we were asked
+        let range = split_logs(&logs_request(2000, 3100), 3100).unwrap();
+        let ranges: Vec<_> = range.chunks.iter().map(chunk_range).collect();
+        assert_eq!(
+            ranges,
+            [
+                ("0x7d0".to_string(), "0xbb7".to_string()),
+                ("0xbb8".to_string(), "0xc1c".to_string()),
+            ]
+        );
+
+        // Past the head, or we don't know where it is
+        assert_eq!(split_logs(&logs_request(2000, 3200), 3100), None);
+        assert_eq!(split_logs(&logs_request(2000, 3100), 0), None);
+    }
+
+    #[test]
+    fn test_split_logs_unsplittable() {
+        // Already a chunk
+        assert_eq!(split_logs(&logs_request(2000, 2999), 10_000), None);
+        assert_eq!(split_logs(&logs_request(2000, 2100), 2100), None);
+
+        // No whole chunk in it, so we'd only be sending more requests for nothing
+        assert_eq!(split_logs(&logs_request(1999, 2000), 10_000), None);
+        assert_eq!(split_logs(&logs_request(1500, 2500), 10_000), None);
+
+        // Too wide
+        assert_eq!(split_logs(&logs_request(0, 1_000_000), 2_000_000), None);
+
+        let mut tx = logs_request(1500, 3200);
+        tx["params"][0]["toBlock"] = "latest".into();
+        assert_eq!(split_logs(&tx, 10_000), None);
+
+        let tx = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_getLogs",
+            "params": [{"blockHash": "0xabc"}],
+        });
+        assert_eq!(split_logs(&tx, 10_000), None);
+    }
+
+    #[test]
+    fn test_logs_budget() {
+        let budget = LogsBudget::default();
+        assert!(budget.take(MAX_LOGS_PARTS - 2));
+        assert!(!budget.take(3));
+        assert!(budget.take(2));
+        assert!(!budget.take(1));
+    }
+
+    #[test]
+    fn test_bisect_logs() {
+        let [low, high] = bisect_logs(&logs_request(1000, 1999)).unwrap();
+        assert_eq!(
+            chunk_range(&low),
+            ("0x3e8".to_string(), "0x5db".to_string())
+        );
+        assert_eq!(
+            chunk_range(&high),
+            ("0x5dc".to_string(), "0x7cf".to_string())
+        );
+        assert_eq!(low["id"], Value::Null);
+        assert_eq!(low["params"][0]["address"], json!(["0xBB", "0xaa", "0xbb"]));
+
+        let [low, high] = bisect_logs(&logs_request(10, 11)).unwrap();
+        assert_eq!(chunk_range(&low), ("0xa".to_string(), "0xa".to_string()));
+        assert_eq!(chunk_range(&high), ("0xb".to_string(), "0xb".to_string()));
+
+        // Can't split a single block
+        assert_eq!(bisect_logs(&logs_request(10, 10)), None);
+        assert_eq!(
+            bisect_logs(&json!({"method": "eth_getBalance", "params": ["0xabc", "0x1"]})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_merge_logs() {
+        let responses = vec![
+            Ok(json!({"jsonrpc": "2.0", "id": 1, "result": [{"blockNumber": "0x1"}]}).to_string()),
+            Ok(json!({"jsonrpc": "2.0", "id": 1, "result": [{"blockNumber": "0x2"}]}).to_string()),
+        ];
+        let response: Value =
+            serde_json::from_str(&merge_logs(responses, 7.into()).unwrap()).unwrap();
+        assert_eq!(
+            response,
+            json!({"jsonrpc": "2.0", "id": 7, "result": [
+                {"blockNumber": "0x1"},
+                {"blockNumber": "0x2"},
+            ]})
+        );
+    }
+
+    fn logs_range(from: u64, to: u64) -> LogsRange {
+        LogsRange {
+            from,
+            to,
+            chunks: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_stitch_logs() {
+        let range = logs_range(1500, 2500);
+        let responses = vec![
+            Ok(json!({"jsonrpc": "2.0", "id": 1, "result": [
+                {"blockNumber": "0x3e8", "logIndex": "0x0"},
+                {"blockNumber": "0x5dc", "logIndex": "0x0"},
+            ]})
+            .to_string()),
+            Ok(json!({"jsonrpc": "2.0", "id": 1, "result": [
+                {"blockNumber": "0x9c4", "logIndex": "0x1"},
+                {"blockNumber": "0x9c5", "logIndex": "0x0"},
+            ]})
+            .to_string()),
+        ];
+
+        let response: Value =
+            serde_json::from_str(&range.stitch(responses, "abc".into()).unwrap()).unwrap();
+        assert_eq!(
+            response,
+            json!({"jsonrpc": "2.0", "id": "abc", "result": [
+                {"blockNumber": "0x5dc", "logIndex": "0x0"},
+                {"blockNumber": "0x9c4", "logIndex": "0x1"},
+            ]})
+        );
+    }
+
+    #[test]
+    fn test_stitch_logs_errors() {
+        let range = logs_range(1500, 2500);
+
+        let responses =
+            vec![
+            Ok(json!({"jsonrpc": "2.0", "id": 1, "result": []}).to_string()),
+            Ok(json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "oops"}})
+                .to_string()),
+        ];
+        let response: Value =
+            serde_json::from_str(&range.stitch(responses, 2.into()).unwrap()).unwrap();
+        assert_eq!(response["id"], 2);
+        assert_eq!(response["error"]["message"], "oops");
+
+        let responses = vec![
+            Ok(json!({"jsonrpc": "2.0", "id": 1, "result": []}).to_string()),
+            Err(JsonRpcError::new(ErrorKind::TimedOut, 1.into())),
+        ];
+        assert_eq!(
+            range.stitch(responses, 3.into()),
+            Err(JsonRpcError::new(ErrorKind::TimedOut, 3.into()))
+        );
+
+        // Never leave a chunk out just because it's not JSON
+        let responses = vec![
+            Ok(json!({"jsonrpc": "2.0", "id": 1, "result": []}).to_string()),
+            Ok("<html>502 Bad Gateway</html>".to_string()),
+        ];
+        assert_eq!(
+            range.stitch(responses, 4.into()),
+            Err(JsonRpcError::new(ErrorKind::UpstreamError, 4.into())
+                .with_data("<html>502 Bad Gateway</html>"))
+        );
+
+        // Same for the halves of a bisected range
+        let responses = vec![
+            Ok(r#"{"jsonrpc":"2.0","id":1,"result":[{"blockNumber":"0x1"}"#.to_string()),
+            Ok(json!({"jsonrpc": "2.0", "id": 1, "result": []}).to_string()),
+        ];
+        let err = merge_logs(responses, 5.into()).unwrap_err();
+        assert_eq!(err.to_value()["id"], 5);
+        assert_eq!(
+            err.to_value()["error"]["code"],
+            ErrorKind::UpstreamError.code()
+        );
+    }
+}
@@ -8,7 +8,15 @@
 //! and processing incoming data.
 
 pub mod accept_http;
+pub mod coalesce;
 pub mod format;
+pub mod hedge;
+pub mod latest_cache;
+pub mod logs;
+pub mod normalize;
 pub mod processing;
-mod response_errors;
+pub mod response_errors;
 pub mod selection;
+#[cfg(test)]
+pub mod test_utils;
+pub mod ttl_cache;
@@ -0,0 +1,353 @@
+//! Canonical form of requests, which is what cache keys are made from.
+//!
+//! Clients can spell the exact same request in lots of ways: `0x01` vs `0x1`,
+//! checksummed vs lowercase addresses, `params: []` vs no params at all...
+//! All of those should hit the same cache entry, so before hashing a request
+//! gets rewritten to its canonical form, param by param, based on its method.
+//! Key order and whitespace don't matter, since `serde_json` always serializes
+//! objects sorted and without any.
+//!
+//! The canonical form of a request is only ever hashed, the request we send
+//! upstream is the one the client sent, save for `fill_default_params`.
+//!
+//! Changing anything here changes cache keys, so `CACHE_KEY_VERSION` has to be bumped
+//! along with it. DBs with keys from another version get cleared on startup.
+
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+
+/// Version of the canonical form, stored in the DB.
+pub const CACHE_KEY_VERSION: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Param {
+    // Hex number
+    Quantity,
+    // Hex bytes, like addresses, hashes or calldata
+    Data,
+    // Number, tag, or EIP-1898 object
+    Block,
+    // Transaction object of `eth_call` and friends
+    Call,
+    // Filter object of `eth_getLogs`
+    Filter,
+    // List of hex bytes
+    DataList,
+    // Anything we don't know how to canonicalize, like bools
+    Other,
+}
+
+/// What each param of `method` is. Params past the end are left as they are.
+fn method_params(method: &str) -> &'static [Param] {
+    use Param::*;
+
+    match method {
+        "eth_getBalance" | "eth_getTransactionCount" | "eth_getCode" => &[Data, Block],
+        "eth_getStorageAt" => &[Data, Data, Block],
+        "eth_call" | "eth_estimateGas" | "eth_createAccessList" => &[Call, Block],
+        "eth_getProof" => &[Data, DataList, Block],
+        "eth_getBlockByNumber" => &[Block, Other],
+        "eth_getBlockByHash" => &[Data, Other],
+        "eth_getBlockTransactionCountByNumber"
+        | "eth_getUncleCountByBlockNumber"
+        | "eth_getBlockReceipts" => &[Block],
+        "eth_getTransactionByBlockNumberAndIndex" | "eth_getUncleByBlockNumberAndIndex" => {
+            &[Block, Quantity]
+        }
+        "eth_getTransactionByBlockHashAndIndex" | "eth_getUncleByBlockHashAndIndex" => {
+            &[Data, Quantity]
+        }
+        "eth_getBlockTransactionCountByHash"
+        | "eth_getUncleCountByBlockHash"
+        | "eth_getTransactionByHash"
+        | "eth_getTransactionReceipt"
+        | "debug_traceTransaction"
+        | "trace_transaction" => &[Data],
+        "eth_getLogs" => &[Filter],
+        _ => &[],
+    }
+}
+
+// Numbers don't have leading zeroes, and are lowercase
+fn normalize_quantity(value: &mut Value) {
+    let Some(digits) = value
+        .as_str()
+        .and_then(|value| value.strip_prefix("0x").or(value.strip_prefix("0X")))
+    else {
+        return;
+    };
+    if digits.is_empty() || !digits.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return;
+    }
+
+    let digits = digits.trim_start_matches('0');
+    let digits = if digits.is_empty() { "0" } else { digits };
+    *value = format!("0x{}", digits.to_ascii_lowercase()).into();
+}
+
+// Hex bytes are lowercase, which also drops EIP-55 checksums of addresses
+fn normalize_data(value: &mut Value) {
+    if let Some(data) = value.as_str() {
+        if data.starts_with("0x") || data.starts_with("0X") {
+            *value = data.to_ascii_lowercase().into();
+        }
+    }
+}
+
+fn normalize_block(value: &mut Value) {
+    match value {
+        // EIP-1898 block params
+        Value::Object(block) => {
+            if let Some(number) = block.get_mut("blockNumber") {
+                normalize_quantity(number);
+            }
+            if let Some(hash) = block.get_mut("blockHash") {
+                normalize_data(hash);
+            }
+            // Blocks don't have to be canonical by default
+            if block.get("requireCanonical") == Some(&Value::Bool(false)) {
+                block.remove("requireCanonical");
+            }
+        }
+        // Tags are left as they are
+        value => normalize_quantity(value),
+    }
+}
+
+fn normalize_call(value: &mut Value) {
+    let Some(call) = value.as_object_mut() else {
+        return;
+    };
+
+    for (key, value) in call.iter_mut() {
+        match key.as_str() {
+            "from" | "to" | "data" | "input" => normalize_data(value),
+            "gas"
+            | "gasPrice"
+            | "maxFeePerGas"
+            | "maxPriorityFeePerGas"
+            | "value"
+            | "nonce"
+            | "type"
+            | "chainId"
+            | "maxFeePerBlobGas" => normalize_quantity(value),
+            _ => (),
+        }
+    }
+}
+
+// Both addresses and topics can be a single value, a list of alternatives, or null
+fn normalize_alternatives(value: &mut Value) {
+    match value {
+        Value::Array(values) => values.iter_mut().for_each(normalize_data),
+        value => normalize_data(value),
+    }
+}
+
+fn normalize_filter(value: &mut Value) {
+    let Some(filter) = value.as_object_mut() else {
+        return;
+    };
+
+    for (key, value) in filter.iter_mut() {
+        match key.as_str() {
+            "fromBlock" | "toBlock" => normalize_block(value),
+            "blockHash" => normalize_data(value),
+            "address" => normalize_alternatives(value),
+            "topics" => {
+                if let Some(topics) = value.as_array_mut() {
+                    topics.iter_mut().for_each(normalize_alternatives);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Canonical form of `tx`, to hash for its cache key.
+///
+/// Only the method and params are kept, so the id and anything
+/// else that doesn't change the response is dropped.
+pub fn normalize_request(tx: &Value) -> Value {
+    let method = tx["method"].as_str().unwrap_or_default();
+    let mut canonical = Map::new();
+    canonical.insert("id".to_string(), Value::Null);
+    canonical.insert("jsonrpc".to_string(), "2.0".into());
+    canonical.insert("method".to_string(), tx["method"].clone());
+
+    let mut params = tx["params"].clone();
+    if let Some(values) = params.as_array_mut() {
+        for (value, param) in values.iter_mut().zip(method_params(method)) {
+            match param {
+                Param::Quantity => normalize_quantity(value),
+                Param::Data => normalize_data(value),
+                Param::Block => normalize_block(value),
+                Param::Call => normalize_call(value),
+                Param::Filter => normalize_filter(value),
+                Param::DataList => {
+                    if let Some(values) = value.as_array_mut() {
+                        values.iter_mut().for_each(normalize_data);
+                    }
+                }
+                Param::Other => (),
+            }
+        }
+    }
+
+    // No params and an empty list of them are the same
+    let has_params = match &params {
+        Value::Null => false,
+        Value::Array(values) => !values.is_empty(),
+        _ => true,
+    };
+    if has_params {
+        canonical.insert("params".to_string(), params);
+    }
+
+    Value::Object(canonical)
+}
+
+/// Spell out params clients can leave out, so requests using their
+/// default look the same as ones that don't.
+///
+/// Unlike `normalize_request` this changes the request we send upstream, so it
+/// only fills in what every client treats the same, and has to be done before
+/// replacing block tags.
+pub fn fill_default_params(tx: &mut Value) {
+    match tx["method"].as_str() {
+        // The block defaults to `latest`
+        Some("eth_call") => {
+            if let Some(params) = tx["params"].as_array_mut() {
+                if params.len() == 1 {
+                    params.push(json!("latest"));
+                }
+            }
+        }
+        // Both ends of the range default to `latest`, unless it's for a single block
+        Some("eth_getLogs") => {
+            if let Some(filter) = tx["params"][0].as_object_mut() {
+                if !filter.contains_key("blockHash") {
+                    for key in ["fromBlock", "toBlock"] {
+                        filter.entry(key).or_insert_with(|| json!("latest"));
+                    }
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_request() {
+        let canonical = normalize_request(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_getBalance",
+            "params": ["0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", "0x00010"],
+        }));
+        assert_eq!(
+            canonical,
+            json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "method": "eth_getBalance",
+                "params": ["0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed", "0x10"],
+            })
+        );
+
+        // Tags, zero and whatever isn't hex are left alone
+        let canonical = normalize_request(&json!({
+            "method": "eth_getTransactionByBlockNumberAndIndex",
+            "params": ["latest", "0x000"],
+        }));
+        assert_eq!(canonical["params"], json!(["latest", "0x0"]));
+        let canonical = normalize_request(&json!({
+            "method": "eth_getBlockByNumber",
+            "params": ["0xzz", true],
+        }));
+        assert_eq!(canonical["params"], json!(["0xzz", true]));
+
+        // Unknown methods only lose their id
+        let tx = json!({"id": null, "jsonrpc": "2.0", "method": "foo_bar", "params": ["0xAB"]});
+        assert_eq!(
+            normalize_request(
+                &json!({"id": 5, "jsonrpc": "2.0", "method": "foo_bar", "params": ["0xAB"]})
+            ),
+            tx
+        );
+    }
+
+    #[test]
+    fn test_normalize_equivalent_requests() {
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_call",
+            "params": [
+                {"to": "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", "data": "0xABCD", "gas": "0x0100"},
+                {"blockHash": "0xDEF", "requireCanonical": false},
+            ],
+        });
+        let same_call = serde_json::from_str::<Value>(
+            r#"{ "method": "eth_call", "id": "x", "jsonrpc": "2.0", "params": [
+                { "gas": "0x100", "data": "0xabcd", "to": "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed" },
+                { "blockHash": "0xdef" }
+            ] }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            normalize_request(&call).to_string(),
+            normalize_request(&same_call).to_string()
+        );
+
+        // Empty params are the same as none
+        assert_eq!(
+            normalize_request(&json!({"method": "eth_chainId", "params": []})),
+            normalize_request(&json!({"method": "eth_chainId"}))
+        );
+
+        let logs = json!({
+            "method": "eth_getLogs",
+            "params": [{
+                "address": ["0xABC"],
+                "topics": [["0xDDF2", "0xAA"], null, "0xFF"],
+                "fromBlock": "0x01",
+                "toBlock": "0x0a",
+            }],
+        });
+        assert_eq!(
+            normalize_request(&logs)["params"],
+            json!([{
+                "address": ["0xabc"],
+                "topics": [["0xddf2", "0xaa"], null, "0xff"],
+                "fromBlock": "0x1",
+                "toBlock": "0xa",
+            }])
+        );
+    }
+
+    #[test]
+    fn test_fill_default_params() {
+        let mut tx = json!({"method": "eth_call", "params": [{"to": "0xabc"}]});
+        fill_default_params(&mut tx);
+        assert_eq!(tx["params"], json!([{"to": "0xabc"}, "latest"]));
+
+        let mut tx = json!({"method": "eth_getLogs", "params": [{"address": "0xabc"}]});
+        fill_default_params(&mut tx);
+        assert_eq!(
+            tx["params"],
+            json!([{"address": "0xabc", "fromBlock": "latest", "toBlock": "latest"}])
+        );
+
+        let mut tx = json!({"method": "eth_getLogs", "params": [{"blockHash": "0xabc"}]});
+        fill_default_params(&mut tx);
+        assert_eq!(tx["params"], json!([{"blockHash": "0xabc"}]));
+    }
+}
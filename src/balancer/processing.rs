@@ -1,12 +1,23 @@
 use crate::{
     balancer::{
-        format::get_block_number_from_request,
-        selection::cache_rules::{
-            cache_method,
-            cache_result,
+        coalesce::InFlightCalls,
+        format::{
+            block_number_param,
+            block_param_position,
+            get_block_number_from_request,
+            get_block_number_from_response,
+            HASH_METHODS,
         },
+        latest_cache::{
+            Head,
+            LatestCache,
+        },
+        normalize::normalize_request,
+        selection::cache_rules::CacheRules,
+        ttl_cache::TtlCache,
     },
     health::safe_block::NamedBlocknumbers,
+    rpc::error::FailureClass,
     Rpc,
 };
 
@@ -17,13 +28,22 @@ use std::{
         Arc,
         RwLock,
     },
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use tokio::sync::watch;
 
-use blake3::Hash;
-use serde_json::Value;
+use blake3::{
+    hash,
+    Hash,
+};
+use serde_json::{
+    json,
+    Value,
+};
 use simd_json::to_vec;
 use sled::Db;
 
@@ -33,6 +53,10 @@ pub struct CacheArgs {
     pub named_numbers: Arc<RwLock<NamedBlocknumbers>>,
     pub cache: Db,
     pub head_cache: Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    pub in_flight: Arc<InFlightCalls>,
+    pub cache_rules: Arc<CacheRules>,
+    pub ttl_cache: Arc<TtlCache>,
+    pub latest_cache: Arc<LatestCache>,
 }
 
 impl CacheArgs {
@@ -41,53 +65,176 @@ impl CacheArgs {
         CacheArgs {
             finalized_rx: watch::channel(0).1,
             named_numbers: Arc::new(RwLock::new(NamedBlocknumbers::default())),
-            cache: (sled::Config::default().open().unwrap()),
+            cache: sled::Config::default().temporary(true).open().unwrap(),
             head_cache: Arc::new(RwLock::new(BTreeMap::new())),
+            in_flight: Arc::new(InFlightCalls::default()),
+            cache_rules: Arc::new(CacheRules::default()),
+            ttl_cache: Arc::new(TtlCache::default()),
+            latest_cache: Arc::new(LatestCache::default()),
         }
     }
 }
 
-// TODO: we should find a way to check values directly and not convert Value to str
-pub fn can_cache(method: &str, result: &str) -> bool {
-    if cache_method(method) && cache_result(result) {
-        return true;
+/// Current head, if `tx` is for it.
+///
+/// Only looks at block numbers, so requests for `latest` have to have their tag
+/// replaced first.
+fn head_block(tx: &Value, cache_args: &CacheArgs) -> Option<u64> {
+    let position = block_param_position(tx["method"].as_str()?)?;
+    let block = block_number_param(&tx["params"][position])?
+        .as_str()?
+        .strip_prefix("0x")?;
+    let block = u64::from_str_radix(block, 16).ok()?;
+
+    let latest = cache_args
+        .named_numbers
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .latest;
+    (latest != 0 && block == latest).then_some(latest)
+}
+
+/// Block the response `rx` to one of the `HASH_METHODS` is from.
+///
+/// `debug_traceTransaction` traces don't say, so we go by the receipt or
+/// transaction with the same hash, if we have either of them cached.
+fn response_block(tx: &Value, rx: &Value, cache_args: &CacheArgs) -> Option<u64> {
+    let method = tx["method"].as_str()?;
+    if method != "debug_traceTransaction" {
+        return get_block_number_from_response(method, rx);
     }
-    false
+
+    ["eth_getTransactionReceipt", "eth_getTransactionByHash"]
+        .into_iter()
+        .find_map(|method| {
+            let request = normalize_request(&json!({
+                "id": null,
+                "jsonrpc": "2.0",
+                "method": method,
+                "params": [tx["params"][0]],
+            }));
+            let tx_hash = hash(request.to_string().as_bytes());
+            let cached = cache_lookup(&request, tx_hash, cache_args).ok()??;
+            get_block_number_from_response(method, &cached)
+        })
 }
 
-/// Check if we should cache the querry, and if so cache it in the DB
-pub fn cache_querry(rx: &mut str, method: Value, tx_hash: Hash, cache_args: &CacheArgs) {
-    let tx_string = method.to_string();
+/// Look up the cached response to `tx`, with its id set to `Value::Null`.
+///
+/// Methods with a TTL and requests for the current head are looked up in memory,
+/// everything else in the DB.
+pub fn cache_lookup(
+    tx: &Value,
+    tx_hash: Hash,
+    cache_args: &CacheArgs,
+) -> Result<Option<Value>, sled::Error> {
+    let method = tx["method"].as_str().unwrap_or_default();
+    if cache_args.cache_rules.ttl(method).is_some() {
+        return Ok(cache_args
+            .ttl_cache
+            .get(&tx_hash, Instant::now())
+            .and_then(|rx| serde_json::from_str(&rx).ok()));
+    }
 
-    if can_cache(&tx_string, rx) {
-        // Insert the response hash into the head_cache
-        let num = get_block_number_from_request(method, &cache_args.named_numbers);
+    if let Some(head) = head_block(tx, cache_args) {
+        if let Some(rx) = cache_args.latest_cache.get(&tx_hash, head) {
+            return Ok(serde_json::from_str(&rx).ok());
+        }
+    }
 
-        // Insert the key of the request we made into our `head_cache`
-        // so we can invalidate it and remove it from the DB if it reorgs.
-        if let Some(num) = num {
-            if num > *cache_args.finalized_rx.borrow() {
-                let mut head_cache = cache_args.head_cache.write().unwrap();
-                head_cache.entry(num).or_default().push(tx_hash.to_string());
-            }
+    match cache_args.cache.get(tx_hash.as_bytes())? {
+        Some(mut rax) => Ok(Some(simd_json::serde::from_slice(&mut rax).unwrap())),
+        None => Ok(None),
+    }
+}
+
+/// Check if we should cache the querry according to the cache rules, and if so cache it
+///
+/// `sent_at` is the head when we sent the request, so responses for a head
+/// that changed while they were in flight don't get cached as the new one's.
+pub fn cache_querry(
+    rx: &mut str,
+    method: Value,
+    tx_hash: Hash,
+    cache_args: &CacheArgs,
+    sent_at: &Head,
+) {
+    // TODO: kinda cringe how we do this gymnasctics of changing things back and forth
+    let mut rx_value: Value = match unsafe { simd_json::serde::from_str(rx) } {
+        Ok(rx_value) => rx_value,
+        Err(_) => return,
+    };
+
+    if !cache_args.cache_rules.can_cache(&method, &rx_value) {
+        return;
+    }
+
+    // Replace the id with Value::Null and insert the request
+    rx_value["id"] = Value::Null;
 
-            // Replace the id with Value::Null and insert the request
-            // TODO: kinda cringe how we do this gymnasctics of changing things back and forth
-            let mut rx_value: Value = unsafe { simd_json::serde::from_str(rx).unwrap() };
-            rx_value["id"] = Value::Null;
+    // Responses that expire on their own don't have to be tracked for reorgs
+    if let Some(ttl) = cache_args
+        .cache_rules
+        .ttl(method["method"].as_str().unwrap_or_default())
+    {
+        cache_args
+            .ttl_cache
+            .insert(tx_hash, rx_value.to_string(), ttl, Instant::now());
+        return;
+    }
 
+    // Same for the head, which gets dropped as soon as the next block lands
+    if let Some(head) = head_block(&method, cache_args) {
+        if head == sent_at.number {
             cache_args
-                .cache
-                .insert(tx_hash.as_bytes(), to_vec(&rx_value).unwrap().as_slice())
-                .unwrap();
+                .latest_cache
+                .insert(tx_hash, rx_value.to_string(), sent_at);
+        }
+        return;
+    }
+
+    // Insert the key of the request we made into our `head_cache`
+    // so we can invalidate it and remove it from the DB if it reorgs.
+    //
+    // Methods keyed by a hash don't have a block in the request, so we take it
+    // from the response instead.
+    let num = match HASH_METHODS.contains(&method["method"].as_str().unwrap_or_default()) {
+        true => {
+            match response_block(&method, &rx_value, cache_args) {
+                Some(num) => Some(num),
+                // Can't tell if it reorgs, eg. transactions that are still pending
+                None => return,
+            }
+        }
+        false => get_block_number_from_request(method, &cache_args.named_numbers),
+    };
+    if let Some(num) = num {
+        // Blocks past the head might still change, eg. logs of a range that isn't over yet
+        let latest = cache_args
+            .named_numbers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .latest;
+        if latest != 0 && num > latest {
+            return;
+        }
+
+        if num > *cache_args.finalized_rx.borrow() {
+            let mut head_cache = cache_args.head_cache.write().unwrap();
+            head_cache.entry(num).or_default().push(tx_hash.to_string());
         }
     }
+
+    cache_args
+        .cache
+        .insert(tx_hash.as_bytes(), to_vec(&rx_value).unwrap().as_slice())
+        .unwrap();
 }
 
 /// Updates the latency of an RPC node given an rpc list, its position, and the time it took for
 /// a request to complete.
 pub fn update_rpc_latency(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize, time: Duration) {
-    let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
+    let rpc_list_guard = rpc_list.read().unwrap_or_else(|e| {
         // Handle the case where the RwLock is poisoned
         e.into_inner()
     });
@@ -100,35 +247,356 @@ pub fn update_rpc_latency(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize,
             rpc_position
         };
         rpc_list_guard[index].update_latency(time.as_nanos() as f64);
-        rpc_list_guard[index].last_used = time.as_micros();
-        println!("LA {}", rpc_list_guard[index].status.latency);
+        println!("LA {}", rpc_list_guard[index].status.latency());
+    }
+}
+
+/// Record a failed request against the RPC at `rpc_position`.
+///
+/// The RPC also gets `penalty` added as a latency sample, so it gets
+/// pushed back in line when picking the next RPC.
+pub fn record_rpc_failure(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    rpc_position: usize,
+    class: FailureClass,
+    penalty: Duration,
+) {
+    let rpc_list_guard = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+
+    // The list might have changed while we were waiting on the RPC
+    if let Some(rpc) = rpc_list_guard.get(rpc_position) {
+        rpc.record_failure(class);
+        rpc.update_latency(penalty.as_nanos() as f64);
+    }
+}
+
+/// Record a request the RPC at `rpc_position` answered.
+pub fn record_rpc_success(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize) {
+    let rpc_list_guard = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+
+    if let Some(rpc) = rpc_list_guard.get(rpc_position) {
+        rpc.record_success();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::balancer::selection::cache_rules::CacheRule;
+
+    #[test]
+    fn test_cache_querry() {
+        let mut cache_args = CacheArgs::default();
+        cache_args.cache = sled::Config::new().temporary(true).open().unwrap();
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method = json!({"method": "eth_getBlockByNumber", "params": ["0x10", false]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+
+        let cached: Value =
+            serde_json::from_slice(&cache_args.cache.get(tx_hash.as_bytes()).unwrap().unwrap())
+                .unwrap();
+        assert_eq!(
+            cached,
+            json!({"id": null, "jsonrpc": "2.0", "result": "0x1"})
+        );
+        assert_eq!(
+            cache_args.head_cache.read().unwrap().get(&16),
+            Some(&vec![tx_hash.to_string()])
+        );
+
+        // Tags can't be cached
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method = json!({"method": "eth_getBlockByNumber", "params": ["latest", false]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cache_querry_eip1898() {
+        let mut cache_args = CacheArgs::default();
+        cache_args.cache = sled::Config::new().temporary(true).open().unwrap();
+        cache_args.named_numbers.write().unwrap().latest = 32;
+
+        // Numbers get tracked for reorgs like plain ones
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method =
+            json!({"method": "eth_getBalance", "params": ["0xabc", {"blockNumber": "0x10"}]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_some());
+        assert_eq!(
+            cache_args.head_cache.read().unwrap().get(&16),
+            Some(&vec![tx_hash.to_string()])
+        );
+
+        // Hashes are cached for good
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method =
+            json!({"method": "eth_getBalance", "params": ["0xabc", {"blockHash": "0xdef"}]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_some());
+        assert_eq!(cache_args.head_cache.read().unwrap().len(), 1);
+
+        // Unless the block has to stay canonical
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method = json!({
+            "method": "eth_getBalance",
+            "params": ["0xabc", {"blockHash": "0xdef", "requireCanonical": true}],
+        });
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cache_querry_hash_methods() {
+        let mut cache_args = CacheArgs::default();
+        cache_args.cache = sled::Config::new().temporary(true).open().unwrap();
+        cache_args.named_numbers.write().unwrap().latest = 32;
+        let (finalized_tx, finalized_rx) = watch::channel(16);
+        cache_args.finalized_rx = finalized_rx;
+
+        let receipt_request = |tx: &str| {
+            normalize_request(&json!({"method": "eth_getTransactionReceipt", "params": [tx]}))
+        };
+
+        // Finalized blocks are cached for good
+        let mut rx = r#"{"jsonrpc":"2.0","result":{"blockNumber":"0x10"},"id":1}"#.to_string();
+        let method = receipt_request("0xaa");
+        let tx_hash = hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_some());
+        assert!(cache_args.head_cache.read().unwrap().is_empty());
+
+        // Newer ones get tracked for reorgs under the block of the response
+        let mut rx = r#"{"jsonrpc":"2.0","result":{"blockNumber":"0x14"},"id":1}"#.to_string();
+        let method = receipt_request("0xbb");
+        let receipt_hash = hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            receipt_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args
+            .cache
+            .get(receipt_hash.as_bytes())
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            cache_args.head_cache.read().unwrap().get(&20),
+            Some(&vec![receipt_hash.to_string()])
+        );
+
+        // Pending transactions aren't cached at all
+        let mut rx = r#"{"jsonrpc":"2.0","result":{"blockNumber":null},"id":1}"#.to_string();
+        let method =
+            normalize_request(&json!({"method": "eth_getTransactionByHash", "params": ["0xcc"]}));
+        let tx_hash = hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_none());
+
+        // Traces go by the receipt we have for the same transaction
+        let trace = |tx: &str| {
+            normalize_request(&json!({"method": "debug_traceTransaction", "params": [tx]}))
+        };
+        let mut rx = r#"{"jsonrpc":"2.0","result":{"structLogs":[]},"id":1}"#.to_string();
+        let method = trace("0xbb");
+        let tx_hash = hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_some());
+        assert_eq!(
+            cache_args.head_cache.read().unwrap().get(&20),
+            Some(&vec![receipt_hash.to_string(), tx_hash.to_string()])
+        );
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":{"structLogs":[]},"id":1}"#.to_string();
+        let method = trace("0xdd");
+        let tx_hash = hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_none());
+
+        drop(finalized_tx);
+    }
 
     #[test]
-    fn test_can_cache() {
-        assert!(can_cache("eth_getBlockByNumber", r#"{"result": "0x1"}"#));
-        assert!(!can_cache("eth_subscribe", r#"{"result": "0x1"}"#));
+    fn test_cache_querry_ttl() {
+        let mut cache_args = CacheArgs::default();
+        cache_args.cache = sled::Config::new().temporary(true).open().unwrap();
+        let mut cache_rules = CacheRules::default();
+        cache_rules.rules.insert(
+            "eth_gasPrice".to_string(),
+            CacheRule {
+                ttl: Some(Duration::from_secs(3)),
+                ..Default::default()
+            },
+        );
+        cache_args.cache_rules = Arc::new(cache_rules);
+
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x3b9aca00","id":1}"#.to_string();
+        let method = json!({"method": "eth_gasPrice", "params": []});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        assert_eq!(cache_lookup(&method, tx_hash, &cache_args).unwrap(), None);
+        cache_querry(
+            &mut rx,
+            method.clone(),
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+
+        // Kept in memory, not in the DB
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_none());
+        assert_eq!(
+            cache_lookup(&method, tx_hash, &cache_args).unwrap(),
+            Some(json!({"id": null, "jsonrpc": "2.0", "result": "0x3b9aca00"}))
+        );
+        assert_eq!(cache_args.ttl_cache.hits(), 1);
+        assert_eq!(cache_args.ttl_cache.misses(), 1);
     }
 
-    // TODO: this :(
-    // #[tokio::test]
-    // async fn test_cache_querry() {
-    //     let cache_args = CacheArgs::default();
-    //     let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
-    //     let method = json!({"method": "eth_getBlockByNumber", "params": ["latest", false]});
-    //     let tx_hash = blake3::hash(method.to_string().as_bytes());
+    #[test]
+    fn test_cache_querry_head() {
+        let mut cache_args = CacheArgs::default();
+        cache_args.cache = sled::Config::new().temporary(true).open().unwrap();
+        cache_args.named_numbers.write().unwrap().latest = 16;
+        cache_args.latest_cache.advance(Head::new(16, "0x16"));
 
-    //     cache_querry(&mut rx, method.clone(), tx_hash, &cache_args);
+        // What `latest` gets rewritten to
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method = json!({"method": "eth_getBalance", "params": ["0xabc", "0x10"]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        assert_eq!(cache_lookup(&method, tx_hash, &cache_args).unwrap(), None);
+        cache_querry(
+            &mut rx,
+            method.clone(),
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+
+        // Kept in memory, and not tracked for reorgs
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_none());
+        assert!(cache_args.head_cache.read().unwrap().is_empty());
+        assert_eq!(
+            cache_lookup(&method, tx_hash, &cache_args).unwrap(),
+            Some(json!({"id": null, "jsonrpc": "2.0", "result": "0x1"}))
+        );
+
+        // Gone once the next block lands
+        cache_args.named_numbers.write().unwrap().latest = 17;
+        cache_args.latest_cache.advance(Head::new(17, "0x17"));
+        assert_eq!(cache_lookup(&method, tx_hash, &cache_args).unwrap(), None);
+
+        // Older blocks still go in the DB
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        cache_querry(
+            &mut rx,
+            method.clone(),
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_some());
+        assert_eq!(cache_args.latest_cache.entries(), 0);
+
+        // EIP-1898 objects count too
+        cache_args.latest_cache.advance(Head::new(17, "0x17"));
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method =
+            json!({"method": "eth_getBalance", "params": ["0xabc", {"blockNumber": "0x11"}]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert_eq!(cache_args.latest_cache.entries(), 1);
 
-    //     let cached_value = cache_args.cache.get(tx_hash.as_bytes()).unwrap().unwrap();
-    //     let cached_str = std::str::from_utf8(&cached_value).unwrap();
-    //     assert_eq!(cached_str, r#"{"id":null,"jsonrpc":"2.0","result":"0x1"}"#);
-    // }
+        // Blocks we haven't seen yet can't be cached
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method = json!({"method": "eth_getBalance", "params": ["0xabc", "0x12"]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(
+            &mut rx,
+            method,
+            tx_hash,
+            &cache_args,
+            &cache_args.latest_cache.head(),
+        );
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_none());
+
+        // The head reorged at the same height while the request was in flight
+        let sent_at = cache_args.latest_cache.head();
+        cache_args.latest_cache.advance(Head::new(17, "0x17b"));
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method = json!({"method": "eth_getBalance", "params": ["0xabc", "0x11"]});
+        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        cache_querry(&mut rx, method, tx_hash, &cache_args, &sent_at);
+        assert_eq!(cache_args.latest_cache.entries(), 0);
+        assert!(cache_args.cache.get(tx_hash.as_bytes()).unwrap().is_none());
+    }
 
     #[tokio::test]
     async fn test_update_rpc_latency() {
@@ -142,7 +610,7 @@ mod tests {
         update_rpc_latency(&rpc_list, 0, Duration::from_nanos(100));
 
         let rpcs = rpc_list.read().unwrap();
-        assert_eq!(rpcs[0].status.latency, 100.0);
+        assert_eq!(rpcs[0].status.latency(), 100.0);
     }
 
     #[tokio::test]
@@ -166,7 +634,7 @@ mod tests {
         update_rpc_latency(&rpc_list, 1, Duration::from_nanos(200));
 
         let rpcs = rpc_list.read().unwrap();
-        assert_eq!(rpcs[1].status.latency, 200.0);
+        assert_eq!(rpcs[1].status.latency(), 200.0);
     }
 
     #[tokio::test]
@@ -182,7 +650,7 @@ mod tests {
 
         // Since the position is invalid, it should update the last available RPC
         let rpcs = rpc_list.read().unwrap();
-        assert_eq!(rpcs[0].status.latency, 300.0);
+        assert_eq!(rpcs[0].status.latency(), 300.0);
     }
 
     #[tokio::test]
@@ -218,7 +686,8 @@ mod tests {
         update_rpc_latency(&rpc_list, 2, Duration::from_nanos(500));
         let rpcs = rpc_list.read().unwrap();
         assert_eq!(
-            rpcs[1].status.latency, 500.0,
+            rpcs[1].status.latency(),
+            500.0,
             "Should update the last RPC in the list"
         );
     }
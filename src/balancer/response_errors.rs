@@ -1,31 +1,39 @@
-//! Due to how schizophrenic hyper is, we're defining our http errors like this.
-//! ???
+//! JSON-RPC 2.0 error responses returned by Blutgang.
+//!
+//! Every failure Blutgang reports on its own, be it in the balancer, WS server
+//! or admin namespace, is built from an `ErrorKind`. Codes are stable:
+//!
+//! | code   | name                | default HTTP status | meaning                                  |
+//! |--------|---------------------|---------------------|------------------------------------------|
+//! | -32700 | `parse_error`       | 400                 | Request body is not valid JSON           |
+//! | -32600 | `invalid_request`   | 400                 | Request is not a valid JSON-RPC request  |
+//! | -32601 | `method_not_found`  | 200                 | Method does not exist (admin namespace)  |
+//! | -32602 | `invalid_params`    | 200                 | Invalid params supplied                  |
+//! | -32603 | `internal_error`    | 500                 | Internal Blutgang error                  |
+//! | -32001 | `timed_out`         | 408                 | Request timed out on every retry         |
+//! | -32002 | `no_rpc_available`  | 500                 | No working RPC available                 |
+//! | -32003 | `cache_error`       | 500                 | Error reading from the cache             |
+//! | -32004 | `ws_upgrade_error`  | 500                 | WebSocket upgrade failed                 |
+//! | -32006 | `read_only`         | 200                 | Admin namespace is read-only             |
+//! | -32007 | `unauthorized`      | 401                 | Missing or invalid JWT                   |
+//! | -32008 | `upstream_error`    | 502                 | Every retry failed on the upstream RPCs  |
+//! | -32009 | `rate_limited`      | 429                 | Every RPC is over its `max_per_second`   |
+//! | -32010 | `ws_disabled`       | 500                 | WebSockets are disabled                  |
+//!
+//! -32005 is left out on purpose. EIP-1474 uses it for "limit exceeded", which
+//! upstream RPCs send us and our default `upstream_errors` rules throttle on.
+//!
+//! The HTTP status of each error can be changed via the `error_status` config table.
+//! Errors inside of a batch response are always returned with the batch's status.
 
-#[macro_export]
-macro_rules! no_rpc_available {
-    () => {
-        Ok(hyper::Response::builder()
-            .status(500)
-            .body(Full::new(Bytes::from(
-                "{code:-32002, message:\"error: No working RPC available! Try again later...\"}"
-                    .to_string(),
-            )))
-            .unwrap())
-    };
-}
+use std::convert::Infallible;
 
-#[macro_export]
-macro_rules! timed_out {
-    () => {
-        Ok(hyper::Response::builder()
-            .status(408)
-            .body(Full::new(Bytes::from(
-                "{code:-32001, message:\"error: Request timed out! Try again later...\"}"
-                    .to_string(),
-            )))
-            .unwrap())
-    };
-}
+use http_body_util::Full;
+use hyper::body::Bytes;
+use serde_json::{
+    json,
+    Value,
+};
 
 #[macro_export]
 macro_rules! print_cache_error {
@@ -36,18 +44,6 @@ macro_rules! print_cache_error {
     };
 }
 
-#[macro_export]
-macro_rules! cache_error {
-    () => {
-        Ok(hyper::Response::builder()
-            .status(500)
-            .body(Full::new(Bytes::from(
-                "{code:-32003, message:\"error: Cache error! Try again later...\"}".to_string(),
-            )))
-            .unwrap())
-    };
-}
-
 #[macro_export]
 macro_rules! rpc_response {
     (
@@ -60,3 +56,275 @@ macro_rules! rpc_response {
             .unwrap())
     };
 }
+
+/// Every kind of error Blutgang can respond with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    ParseError,
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams,
+    InternalError,
+    TimedOut,
+    NoRpcAvailable,
+    CacheError,
+    WsUpgradeError,
+    WsDisabled,
+    ReadOnly,
+    Unauthorized,
+    UpstreamError,
+    RateLimited,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 14] = [
+        ErrorKind::ParseError,
+        ErrorKind::InvalidRequest,
+        ErrorKind::MethodNotFound,
+        ErrorKind::InvalidParams,
+        ErrorKind::InternalError,
+        ErrorKind::TimedOut,
+        ErrorKind::NoRpcAvailable,
+        ErrorKind::CacheError,
+        ErrorKind::WsUpgradeError,
+        ErrorKind::WsDisabled,
+        ErrorKind::ReadOnly,
+        ErrorKind::Unauthorized,
+        ErrorKind::UpstreamError,
+        ErrorKind::RateLimited,
+    ];
+
+    pub fn code(&self) -> i64 {
+        match self {
+            ErrorKind::ParseError => -32700,
+            ErrorKind::InvalidRequest => -32600,
+            ErrorKind::MethodNotFound => -32601,
+            ErrorKind::InvalidParams => -32602,
+            ErrorKind::InternalError => -32603,
+            ErrorKind::TimedOut => -32001,
+            ErrorKind::NoRpcAvailable => -32002,
+            ErrorKind::CacheError => -32003,
+            ErrorKind::WsUpgradeError => -32004,
+            ErrorKind::WsDisabled => -32010,
+            ErrorKind::ReadOnly => -32006,
+            ErrorKind::Unauthorized => -32007,
+            ErrorKind::UpstreamError => -32008,
+            ErrorKind::RateLimited => -32009,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorKind::ParseError => "Parse error",
+            ErrorKind::InvalidRequest => "Invalid request",
+            ErrorKind::MethodNotFound => "Method not found",
+            ErrorKind::InvalidParams => "Invalid params",
+            ErrorKind::InternalError => "Internal error",
+            ErrorKind::TimedOut => "Request timed out! Try again later...",
+            ErrorKind::NoRpcAvailable => "No working RPC available! Try again later...",
+            ErrorKind::CacheError => "Cache error! Try again later...",
+            ErrorKind::WsUpgradeError => "Websocket upgrade error! Try again later...",
+            ErrorKind::WsDisabled => "WebSockets are disabled!",
+            ErrorKind::ReadOnly => "Admin namespace is set to read-only",
+            ErrorKind::Unauthorized => "Unauthorized or invalid token",
+            ErrorKind::UpstreamError => "Upstream RPC request failed! Try again later...",
+            ErrorKind::RateLimited => "Rate limited by every RPC! Try again later...",
+        }
+    }
+
+    /// Name used to refer to the error in the config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::ParseError => "parse_error",
+            ErrorKind::InvalidRequest => "invalid_request",
+            ErrorKind::MethodNotFound => "method_not_found",
+            ErrorKind::InvalidParams => "invalid_params",
+            ErrorKind::InternalError => "internal_error",
+            ErrorKind::TimedOut => "timed_out",
+            ErrorKind::NoRpcAvailable => "no_rpc_available",
+            ErrorKind::CacheError => "cache_error",
+            ErrorKind::WsUpgradeError => "ws_upgrade_error",
+            ErrorKind::WsDisabled => "ws_disabled",
+            ErrorKind::ReadOnly => "read_only",
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::UpstreamError => "upstream_error",
+            ErrorKind::RateLimited => "rate_limited",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ErrorKind> {
+        ErrorKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    fn default_status(&self) -> u16 {
+        match self {
+            ErrorKind::ParseError | ErrorKind::InvalidRequest => 400,
+            ErrorKind::MethodNotFound | ErrorKind::InvalidParams | ErrorKind::ReadOnly => 200,
+            ErrorKind::TimedOut => 408,
+            ErrorKind::Unauthorized => 401,
+            ErrorKind::UpstreamError => 502,
+            ErrorKind::RateLimited => 429,
+            ErrorKind::InternalError
+            | ErrorKind::NoRpcAvailable
+            | ErrorKind::CacheError
+            | ErrorKind::WsUpgradeError
+            | ErrorKind::WsDisabled => 500,
+        }
+    }
+
+    fn index(&self) -> usize {
+        ErrorKind::ALL.iter().position(|kind| kind == self).unwrap()
+    }
+}
+
+/// HTTP status codes we respond with for each `ErrorKind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorStatus([u16; ErrorKind::ALL.len()]);
+
+impl Default for ErrorStatus {
+    fn default() -> Self {
+        Self(ErrorKind::ALL.map(|kind| kind.default_status()))
+    }
+}
+
+impl ErrorStatus {
+    pub fn get(&self, kind: ErrorKind) -> u16 {
+        self.0[kind.index()]
+    }
+
+    pub fn set(&mut self, kind: ErrorKind, status: u16) {
+        self.0[kind.index()] = status;
+    }
+}
+
+/// A JSON-RPC error, along with the id of the request that caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonRpcError {
+    pub kind: ErrorKind,
+    id: Value,
+    data: Option<Value>,
+}
+
+impl JsonRpcError {
+    pub fn new(kind: ErrorKind, id: Value) -> Self {
+        Self {
+            kind,
+            id,
+            data: None,
+        }
+    }
+
+    /// Attach additional information about the error.
+    pub fn with_data(mut self, data: impl Into<Value>) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    /// Answer a different request with the same error.
+    pub fn with_id(mut self, id: Value) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Build the JSON-RPC response object for this error.
+    pub fn to_value(&self) -> Value {
+        let mut error = json!({
+            "code": self.kind.code(),
+            "message": self.kind.message(),
+        });
+
+        if let Some(data) = &self.data {
+            error["data"] = data.clone();
+        }
+
+        json!({
+            "jsonrpc": "2.0",
+            "id": self.id,
+            "error": error,
+        })
+    }
+
+    /// Build the HTTP response returned when a single (non-batch) request fails.
+    pub fn into_response(
+        self,
+        error_status: &ErrorStatus,
+    ) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+        Ok(hyper::Response::builder()
+            .status(error_status.get(self.kind))
+            .header("Content-Type", "application/json")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Full::new(Bytes::from(self.to_value().to_string())))
+            .unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_to_value() {
+        let err = JsonRpcError::new(ErrorKind::TimedOut, json!("abc-1"));
+        assert_eq!(
+            err.to_value(),
+            json!({
+                "jsonrpc": "2.0",
+                "id": "abc-1",
+                "error": {
+                    "code": -32001,
+                    "message": "Request timed out! Try again later...",
+                },
+            })
+        );
+
+        let err = JsonRpcError::new(ErrorKind::InvalidParams, Value::Null).with_data("bad index");
+        assert_eq!(
+            err.to_value(),
+            json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": {
+                    "code": -32602,
+                    "message": "Invalid params",
+                    "data": "bad index",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let mut codes: Vec<i64> = ErrorKind::ALL.iter().map(|kind| kind.code()).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), ErrorKind::ALL.len());
+
+        // Upstream RPCs use it for limits
+        assert!(!codes.contains(&-32005));
+        assert_eq!(ErrorKind::WsDisabled.code(), -32010);
+    }
+
+    #[test]
+    fn test_error_names() {
+        for kind in ErrorKind::ALL {
+            assert_eq!(ErrorKind::from_name(kind.name()), Some(kind));
+        }
+        assert_eq!(ErrorKind::from_name("not_an_error"), None);
+    }
+
+    #[test]
+    fn test_error_status() {
+        let mut error_status = ErrorStatus::default();
+        assert_eq!(error_status.get(ErrorKind::TimedOut), 408);
+        assert_eq!(error_status.get(ErrorKind::NoRpcAvailable), 500);
+
+        error_status.set(ErrorKind::NoRpcAvailable, 503);
+        assert_eq!(error_status.get(ErrorKind::NoRpcAvailable), 503);
+        assert_eq!(error_status.get(ErrorKind::TimedOut), 408);
+
+        let response = JsonRpcError::new(ErrorKind::NoRpcAvailable, json!(1))
+            .into_response(&error_status)
+            .unwrap();
+        assert_eq!(response.status(), 503);
+    }
+}
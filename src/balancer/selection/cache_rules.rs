@@ -1,57 +1,332 @@
-use memchr::memmem;
-
-// Return true if we are supposed to be caching the input.
-//
-// This loop cannot be unrolled because it wouldn't work against mangled queries that would
-// overall be valid. Too bad!
-pub fn cache_method(rx: &str) -> bool {
-    // If no-cache feature is on, return false
-    #[cfg(feature = "no-cache")]
-    return false;
-
-    // all of the below cannot be cached properly
-    let blacklist = [
-        "latest",
-        "eth_blockNumber",
-        "earliest",
-        "safe",
-        "finalized",
-        "pending",
-        "eth_subscribe",
-        "eth_unsubscribe",
-    ];
-    // rx should look something like `{"id":1,"jsonrpc":"2.0","method":"eth_call","params":...`
-    // Even tho rx should look like the example above, its still a valid request if the method
-    // is first, so it will be skipped if we try to be smart and skip the first n charachters.
-    //
-    // We could potentially try to find `params` and then move from there but it would end up
-    // being slower in most cases.
-    for item in blacklist.iter() {
-        if memmem::find(rx.as_bytes(), item.as_bytes()).is_some() {
-            return false;
+//! Decides which responses get cached.
+//!
+//! Every method has a rule saying whether its responses can be cached at all,
+//! and what the request and response have to look like for that.
+//! Methods without a rule are never cached.
+
+use crate::balancer::format::{
+    block_param_position,
+    HASH_METHODS,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use std::{
+    collections::BTreeMap,
+    time::Duration,
+};
+
+// Methods that take a block number, and are cached by default
+const BLOCK_METHODS: [&str; 11] = [
+    "eth_getBalance",
+    "eth_getStorageAt",
+    "eth_getTransactionCount",
+    "eth_getBlockTransactionCountByNumber",
+    "eth_getUncleCountByBlockNumber",
+    "eth_getCode",
+    "eth_call",
+    "eth_getBlockByNumber",
+    "eth_getTransactionByBlockNumberAndIndex",
+    "eth_getUncleByBlockNumberAndIndex",
+    "eth_getLogs",
+];
+
+// Tags never start with `0x`, numbers and hashes always do
+fn is_resolved(block: &Value) -> bool {
+    match block {
+        // EIP-1898 block params
+        Value::Object(block) => {
+            match block.get("blockHash") {
+                // Responses pinned to a hash never change, unless the block has to be
+                // canonical, in which case they turn into errors if it reorgs
+                Some(hash) => {
+                    is_resolved(hash) && block.get("requireCanonical") != Some(&Value::Bool(true))
+                }
+                None => block.get("blockNumber").is_some_and(is_resolved),
+            }
         }
+        block => block.as_str().is_some_and(|block| block.starts_with("0x")),
     }
+}
 
-    true
+/// Check if every block `tx` depends on is a number or hash.
+fn block_resolved(tx: &Value) -> bool {
+    match tx["method"].as_str() {
+        // Logs either take a single block hash, or a range
+        Some("eth_getLogs") => {
+            let filter = &tx["params"][0];
+            filter.get("blockHash").is_some()
+                || (is_resolved(&filter["fromBlock"]) && is_resolved(&filter["toBlock"]))
+        }
+        Some(method) => {
+            match block_param_position(method) {
+                Some(position) => is_resolved(&tx["params"][position]),
+                None => true,
+            }
+        }
+        None => true,
+    }
 }
 
-// Same as cache_method but for results
-pub fn cache_result(rx: &str) -> bool {
-    // If no-cache feature is on, return false
-    #[cfg(feature = "no-cache")]
-    return false;
+/// When responses of a method can be cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheRule {
+    // Cache the method at all
+    pub cache: bool,
+    // The block param has to be a number or hash, not a tag like `latest`.
+    // EIP-1898 hashes with `requireCanonical` don't count, since they can still reorg.
+    // For `eth_getLogs` that's both ends of the range, or its block hash.
+    // Methods without a block param always pass this.
+    pub block_resolved: bool,
+    // `result` can't be null, eg. for a block the RPC doesn't have yet
+    pub non_null: bool,
+    // The response can't have an `error` member
+    pub no_error: bool,
+    // Keep responses in memory for this long instead of in the DB,
+    // for methods that don't depend on a block but still change over time
+    pub ttl: Option<Duration>,
+}
+
+impl Default for CacheRule {
+    fn default() -> Self {
+        Self {
+            cache: true,
+            block_resolved: true,
+            non_null: true,
+            no_error: true,
+            ttl: None,
+        }
+    }
+}
+
+impl CacheRule {
+    pub const NEVER: CacheRule = CacheRule {
+        cache: false,
+        block_resolved: true,
+        non_null: true,
+        no_error: true,
+        ttl: None,
+    };
 
-    // just checking if `error` is present should be enough, but include the beggining error
-    // codes juuuust to be extra safe
-    //
-    // `null` can appear in results if the node is malfunctioning and we shouldnt try and cache it as a result
-    let blacklist = ["error", "-32", "null"];
+    fn allows(&self, tx: &Value, rx: &Value) -> bool {
+        if !self.cache {
+            return false;
+        }
 
-    for item in blacklist.iter() {
-        if memmem::find(rx.as_bytes(), item.as_bytes()).is_some() {
+        if self.block_resolved && !block_resolved(tx) {
             return false;
         }
+
+        if self.non_null && rx["result"].is_null() {
+            return false;
+        }
+
+        !(self.no_error && rx.get("error").is_some())
+    }
+
+    pub fn to_value(self) -> Value {
+        json!({
+            "cache": self.cache,
+            "block_resolved": self.block_resolved,
+            "non_null": self.non_null,
+            "no_error": self.no_error,
+            "ttl_ms": self.ttl.map(|ttl| ttl.as_millis() as u64),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheRules {
+    pub rules: BTreeMap<String, CacheRule>,
+}
+
+impl Default for CacheRules {
+    fn default() -> Self {
+        Self {
+            rules: BLOCK_METHODS
+                .iter()
+                .chain(HASH_METHODS.iter())
+                .map(|method| (method.to_string(), CacheRule::default()))
+                .collect(),
+        }
+    }
+}
+
+impl CacheRules {
+    /// No method gets cached.
+    pub fn empty() -> Self {
+        Self {
+            rules: BTreeMap::new(),
+        }
     }
 
-    true
+    /// Rule for `method`. Methods without one are never cached.
+    pub fn get(&self, method: &str) -> CacheRule {
+        self.rules.get(method).copied().unwrap_or(CacheRule::NEVER)
+    }
+
+    /// How long responses to `method` are cached in memory for, if they don't go in the DB.
+    pub fn ttl(&self, method: &str) -> Option<Duration> {
+        self.rules.get(method).filter(|rule| rule.cache)?.ttl
+    }
+
+    /// Check if the response `rx` to the request `tx` can be cached.
+    pub fn can_cache(&self, tx: &Value, rx: &Value) -> bool {
+        // If no-cache feature is on, return false
+        #[cfg(feature = "no-cache")]
+        return false;
+
+        match tx["method"].as_str() {
+            Some(method) => self.get(method).allows(tx, rx),
+            None => false,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        self.rules
+            .iter()
+            .map(|(method, rule)| (method.clone(), rule.to_value()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_request(block: &str) -> Value {
+        json!({"jsonrpc": "2.0", "method": "eth_getBlockByNumber", "params": [block, false]})
+    }
+
+    #[test]
+    fn test_default_cache_rules() {
+        let rules = CacheRules::default();
+        let rx = json!({"jsonrpc": "2.0", "id": 1, "result": {"number": "0x10"}});
+
+        assert!(rules.can_cache(&block_request("0x10"), &rx));
+        assert!(!rules.can_cache(&block_request("latest"), &rx));
+        assert!(!rules.can_cache(
+            &json!({"jsonrpc": "2.0", "method": "eth_blockNumber", "params": []}),
+            &json!({"jsonrpc": "2.0", "id": 1, "result": "0x10"})
+        ));
+
+        // Block we don't have yet, or an error
+        assert!(!rules.can_cache(
+            &block_request("0x10"),
+            &json!({"jsonrpc": "2.0", "id": 1, "result": null})
+        ));
+        assert!(!rules.can_cache(
+            &block_request("0x10"),
+            &json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "oops"}})
+        ));
+    }
+
+    // Only the structure of the response matters, not what's in it
+    #[test]
+    fn test_cache_rules_ignore_contents() {
+        let rules = CacheRules::default();
+
+        let tx = json!({
+            "jsonrpc": "2.0",
+            "method": "eth_getBlockByNumber",
+            "params": ["0x10", true],
+        });
+        // Contract creations have `"to":null`, and any string can show up in extra data
+        let rx = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "extraData": "error -32000 latest pending",
+                "transactions": [{"to": null}],
+            },
+        });
+        assert!(rules.can_cache(&tx, &rx));
+    }
+
+    #[test]
+    fn test_eip1898_cache_rules() {
+        let rules = CacheRules::default();
+        let rx = json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"});
+        let balance_request = |block: Value| json!({"jsonrpc": "2.0", "method": "eth_getBalance", "params": ["0xabc", block]});
+
+        assert!(rules.can_cache(&balance_request(json!({"blockNumber": "0x10"})), &rx));
+        assert!(rules.can_cache(&balance_request(json!({"blockHash": "0xdef"})), &rx));
+        assert!(rules.can_cache(
+            &balance_request(json!({"blockHash": "0xdef", "requireCanonical": false})),
+            &rx
+        ));
+        assert!(!rules.can_cache(
+            &balance_request(json!({"blockHash": "0xdef", "requireCanonical": true})),
+            &rx
+        ));
+        assert!(!rules.can_cache(&balance_request(json!({"blockNumber": "latest"})), &rx));
+    }
+
+    #[test]
+    fn test_logs_cache_rules() {
+        let rules = CacheRules::default();
+        let rx = json!({"jsonrpc": "2.0", "id": 1, "result": []});
+        let logs_request =
+            |filter: Value| json!({"jsonrpc": "2.0", "method": "eth_getLogs", "params": [filter]});
+
+        assert!(rules.can_cache(
+            &logs_request(json!({"fromBlock": "0x1", "toBlock": "0x10"})),
+            &rx
+        ));
+        assert!(rules.can_cache(&logs_request(json!({"blockHash": "0xabc"})), &rx));
+        assert!(!rules.can_cache(
+            &logs_request(json!({"fromBlock": "0x1", "toBlock": "latest"})),
+            &rx
+        ));
+        // Both ends default to `latest`
+        assert!(!rules.can_cache(&logs_request(json!({"fromBlock": "0x1"})), &rx));
+    }
+
+    #[test]
+    fn test_hash_methods_cache_rules() {
+        let rules = CacheRules::default();
+        let tx =
+            json!({"jsonrpc": "2.0", "method": "eth_getTransactionReceipt", "params": ["0xabc"]});
+
+        assert!(rules.can_cache(
+            &tx,
+            &json!({"jsonrpc": "2.0", "id": 1, "result": {"blockNumber": "0x10"}})
+        ));
+        // Transactions we don't know about yet
+        assert!(!rules.can_cache(&tx, &json!({"jsonrpc": "2.0", "id": 1, "result": null})));
+    }
+
+    #[test]
+    fn test_custom_cache_rules() {
+        let mut rules = CacheRules::empty();
+        assert!(!rules.can_cache(
+            &block_request("0x10"),
+            &json!({"jsonrpc": "2.0", "id": 1, "result": "0x10"})
+        ));
+
+        rules.rules.insert(
+            "eth_getTransactionReceipt".to_string(),
+            CacheRule {
+                non_null: false,
+                ..Default::default()
+            },
+        );
+        assert!(rules.can_cache(
+            &json!({"jsonrpc": "2.0", "method": "eth_getTransactionReceipt", "params": ["0xabc"]}),
+            &json!({"jsonrpc": "2.0", "id": 1, "result": null})
+        ));
+
+        rules.rules.insert(
+            "eth_gasPrice".to_string(),
+            CacheRule {
+                ttl: Some(Duration::from_secs(3)),
+                ..Default::default()
+            },
+        );
+        assert_eq!(rules.ttl("eth_gasPrice"), Some(Duration::from_secs(3)));
+        assert_eq!(rules.ttl("eth_getTransactionReceipt"), None);
+    }
 }
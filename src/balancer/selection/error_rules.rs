@@ -0,0 +1,260 @@
+//! Decides what happens to error responses from upstream RPCs.
+//!
+//! Each rule matches an error by its code, part of its message, or both, and
+//! maps it to an `ErrorAction`. Rules are checked in order and the first
+//! matching one wins, so a rule's position is its precedence, not its action.
+//! Errors that match no rule are passed to the client, and responses that
+//! aren't JSON at all get the `invalid_response` action, `degrade` by default.
+//!
+//! The default rules, in order:
+//!
+//! | match                                  | action     |
+//! |----------------------------------------|------------|
+//! | message `query returned more than`     | `split`    |
+//! | message `block range`                  | `split`    |
+//! | message `response size exceeded`       | `split`    |
+//! | code -32005                            | `throttle` |
+//! | message `limit exceeded`               | `throttle` |
+//! | message `rate limit`                   | `throttle` |
+//! | message `too many requests`            | `throttle` |
+//! | message `compute units`                | `throttle` |
+//! | message `header not found`             | `retry`    |
+//! | message `missing trie node`            | `retry`    |
+//!
+//! Some providers send oversized `eth_getLogs` ranges with -32005, the
+//! EIP-1474 "limit exceeded" code, so the `split` rules come before it.
+//! Setting `rules` in the `upstream_errors` config table replaces the
+//! defaults as a whole.
+
+use memchr::memmem;
+use serde_json::Value;
+
+/// What to do with an error response from an upstream RPC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    // Return the error to the client as is
+    Pass,
+    // Send the request to another RPC
+    Retry,
+    // Record the error against the RPC and send the request to another one
+    Degrade,
+    // The RPC is rate limiting us, so back off from it and send the request to another one
+    Throttle,
+    // The block range of an `eth_getLogs` is too large, so split it and send the halves.
+    // Passed to the client like `Pass` for anything else.
+    Split,
+}
+
+impl ErrorAction {
+    pub fn from_name(name: &str) -> Option<ErrorAction> {
+        match name {
+            "pass" => Some(ErrorAction::Pass),
+            "retry" => Some(ErrorAction::Retry),
+            "degrade" => Some(ErrorAction::Degrade),
+            "throttle" => Some(ErrorAction::Throttle),
+            "split" => Some(ErrorAction::Split),
+            _ => None,
+        }
+    }
+}
+
+/// Matches a JSON-RPC error by its code, message, or both.
+///
+/// `message` is matched case insensitively against any part of the error message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorRule {
+    pub code: Option<i64>,
+    pub message: Option<String>,
+    pub action: ErrorAction,
+}
+
+impl ErrorRule {
+    pub fn new(code: Option<i64>, message: Option<&str>, action: ErrorAction) -> Self {
+        Self {
+            code,
+            message: message.map(|message| message.to_lowercase()),
+            action,
+        }
+    }
+
+    fn matches(&self, code: Option<i64>, message: &str) -> bool {
+        if self.code.is_some() && self.code != code {
+            return false;
+        }
+
+        match &self.message {
+            Some(pattern) => message.contains(pattern.as_str()),
+            None => true,
+        }
+    }
+}
+
+/// Decides what happens to error responses we get from upstream RPCs.
+///
+/// Rules are checked in order and the first matching one wins.
+/// Errors not matching any rule are passed to the client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorRules {
+    pub rules: Vec<ErrorRule>,
+    // Action for responses that aren't JSON, like HTML error pages
+    pub invalid_response: ErrorAction,
+}
+
+impl Default for ErrorRules {
+    fn default() -> Self {
+        Self {
+            rules: vec![
+                // Some providers use the rate limit code for these too, so they go first
+                ErrorRule::new(None, Some("query returned more than"), ErrorAction::Split),
+                ErrorRule::new(None, Some("block range"), ErrorAction::Split),
+                ErrorRule::new(None, Some("response size exceeded"), ErrorAction::Split),
+                ErrorRule::new(Some(-32005), None, ErrorAction::Throttle),
+                ErrorRule::new(None, Some("limit exceeded"), ErrorAction::Throttle),
+                ErrorRule::new(None, Some("rate limit"), ErrorAction::Throttle),
+                ErrorRule::new(None, Some("too many requests"), ErrorAction::Throttle),
+                ErrorRule::new(None, Some("compute units"), ErrorAction::Throttle),
+                ErrorRule::new(None, Some("header not found"), ErrorAction::Retry),
+                ErrorRule::new(None, Some("missing trie node"), ErrorAction::Retry),
+            ],
+            invalid_response: ErrorAction::Degrade,
+        }
+    }
+}
+
+impl ErrorRules {
+    /// Classify the response `rx` of an upstream RPC.
+    pub fn classify(&self, rx: &str) -> ErrorAction {
+        // JSON-RPC responses are always objects, or arrays for batches
+        match rx.trim_start().as_bytes().first() {
+            Some(b'{') | Some(b'[') => {}
+            _ => return self.invalid_response,
+        }
+
+        // Skip parsing for the vast majority of responses
+        if memmem::find(rx.as_bytes(), b"\"error\"").is_none() {
+            return ErrorAction::Pass;
+        }
+
+        let rx: Value = match serde_json::from_str(rx) {
+            Ok(rx) => rx,
+            Err(_) => return self.invalid_response,
+        };
+
+        let error = &rx["error"];
+        if !error.is_object() {
+            return ErrorAction::Pass;
+        }
+
+        let code = error["code"].as_i64();
+        let message = error["message"].as_str().unwrap_or_default().to_lowercase();
+
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(code, &message))
+            .map_or(ErrorAction::Pass, |rule| rule.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_default_rules() {
+        let rules = ErrorRules::default();
+
+        assert_eq!(
+            rules.classify(r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#),
+            ErrorAction::Pass
+        );
+        assert_eq!(
+            rules.classify(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"header not found"}}"#
+            ),
+            ErrorAction::Retry
+        );
+        assert_eq!(
+            rules.classify(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"Missing trie node abc"}}"#
+            ),
+            ErrorAction::Retry
+        );
+        assert_eq!(
+            rules.classify(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32005,"message":"slow down"}}"#
+            ),
+            ErrorAction::Throttle
+        );
+        assert_eq!(
+            rules.classify(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":429,"message":"Your app has exceeded its compute units per second capacity"}}"#
+            ),
+            ErrorAction::Throttle
+        );
+        // Ranges of logs RPCs won't serve in one go
+        assert_eq!(
+            rules.classify(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32005,"message":"query returned more than 10000 results"}}"#
+            ),
+            ErrorAction::Split
+        );
+        assert_eq!(
+            rules.classify(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32600,"message":"eth_getLogs block range too large"}}"#
+            ),
+            ErrorAction::Split
+        );
+        // Errors the client caused get passed through
+        assert_eq!(
+            rules.classify(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":3,"message":"execution reverted"}}"#
+            ),
+            ErrorAction::Pass
+        );
+        assert_eq!(
+            rules.classify("<html><body>502 Bad Gateway</body></html>"),
+            ErrorAction::Degrade
+        );
+        assert_eq!(rules.classify(""), ErrorAction::Degrade);
+    }
+
+    #[test]
+    fn test_classify_rule_order() {
+        let rules = ErrorRules {
+            rules: vec![
+                ErrorRule::new(Some(-32000), Some("header"), ErrorAction::Pass),
+                ErrorRule::new(Some(-32000), None, ErrorAction::Retry),
+            ],
+            invalid_response: ErrorAction::Pass,
+        };
+
+        assert_eq!(
+            rules.classify(r#"{"id":1,"error":{"code":-32000,"message":"header not found"}}"#),
+            ErrorAction::Pass
+        );
+        assert_eq!(
+            rules.classify(r#"{"id":1,"error":{"code":-32000,"message":"something else"}}"#),
+            ErrorAction::Retry
+        );
+        assert_eq!(
+            rules.classify(r#"{"id":1,"error":{"code":-32001,"message":"header"}}"#),
+            ErrorAction::Pass
+        );
+        assert_eq!(rules.classify("Bad Gateway"), ErrorAction::Pass);
+    }
+
+    #[test]
+    fn test_action_names() {
+        assert_eq!(ErrorAction::from_name("pass"), Some(ErrorAction::Pass));
+        assert_eq!(ErrorAction::from_name("retry"), Some(ErrorAction::Retry));
+        assert_eq!(
+            ErrorAction::from_name("degrade"),
+            Some(ErrorAction::Degrade)
+        );
+        assert_eq!(
+            ErrorAction::from_name("throttle"),
+            Some(ErrorAction::Throttle)
+        );
+        assert_eq!(ErrorAction::from_name("explode"), None);
+    }
+}
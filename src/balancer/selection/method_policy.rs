@@ -0,0 +1,245 @@
+//! Per-method overrides for `ttl` and `max_retries`, and opting in to hedged requests.
+//!
+//! Policies are keyed either by a method name, eg. `eth_getLogs`, or by a
+//! prefix ending in `*`, eg. `debug_*`. Exact matches win over prefixes,
+//! and longer prefixes win over shorter ones. Anything a policy doesn't set
+//! falls back to the global settings.
+
+use crate::{
+    rpc::latency::LatencyMetric,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    time::Duration,
+};
+
+// Don't double the retry backoff more than this many times
+const MAX_BACKOFF_DOUBLINGS: u32 = 10;
+
+/// How long to wait for an RPC before sending the same request to another one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HedgeAfter {
+    Delay(Duration),
+    // The latency of the RPC we're waiting on
+    Latency(LatencyMetric),
+}
+
+impl HedgeAfter {
+    /// Returns `None` if we have no latency data for `rpc` to go off of.
+    pub fn delay(&self, rpc: &Rpc) -> Option<Duration> {
+        match self {
+            HedgeAfter::Delay(delay) => Some(*delay),
+            HedgeAfter::Latency(metric) => {
+                let latency = rpc.status.latency_of(*metric);
+                (latency > 0.0).then(|| Duration::from_nanos(latency as u64))
+            }
+        }
+    }
+}
+
+/// How a single request gets sent upstream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestPolicy {
+    // How long to wait for an RPC to respond
+    pub ttl: Duration,
+    // How many times we try to send the request before giving up, 1 means no retries
+    pub max_retries: u32,
+    // Wait before the first retry, doubling every retry after that
+    pub retry_backoff: Duration,
+    // Share of the backoff that is randomized, between 0 and 1
+    pub jitter: f64,
+    // Send the request to a second RPC if the first one is slow. Off if `None`.
+    pub hedge_after: Option<HedgeAfter>,
+}
+
+impl RequestPolicy {
+    /// Policy for requests without a method policy.
+    pub fn new(ttl: u128, max_retries: u32) -> Self {
+        Self {
+            ttl: Duration::from_millis(ttl as u64),
+            max_retries,
+            retry_backoff: Duration::ZERO,
+            jitter: 0.0,
+            hedge_after: None,
+        }
+    }
+
+    /// How long to wait before retry number `retry`, starting from 1.
+    pub fn retry_delay(&self, retry: u32) -> Duration {
+        if self.retry_backoff.is_zero() {
+            return Duration::ZERO;
+        }
+
+        let delay = self
+            .retry_backoff
+            .saturating_mul(1 << retry.saturating_sub(1).min(MAX_BACKOFF_DOUBLINGS));
+        delay.mul_f64(1.0 - self.jitter * rand::random::<f64>())
+    }
+}
+
+/// Overrides for the methods a policy applies to. `None` keeps the default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MethodPolicy {
+    pub ttl: Option<Duration>,
+    pub max_retries: Option<u32>,
+    // Set to false to never retry, regardless of `max_retries`
+    pub retry: Option<bool>,
+    pub retry_backoff: Option<Duration>,
+    pub jitter: Option<f64>,
+    pub hedge_after: Option<HedgeAfter>,
+}
+
+impl MethodPolicy {
+    fn apply(&self, mut policy: RequestPolicy) -> RequestPolicy {
+        if let Some(ttl) = self.ttl {
+            policy.ttl = ttl;
+        }
+        if let Some(max_retries) = self.max_retries {
+            policy.max_retries = max_retries;
+        }
+        if self.retry == Some(false) {
+            policy.max_retries = 1;
+        }
+        if let Some(retry_backoff) = self.retry_backoff {
+            policy.retry_backoff = retry_backoff;
+        }
+        if let Some(jitter) = self.jitter {
+            policy.jitter = jitter.clamp(0.0, 1.0);
+        }
+        if self.hedge_after.is_some() {
+            policy.hedge_after = self.hedge_after;
+        }
+        policy
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MethodPolicies {
+    methods: HashMap<String, MethodPolicy>,
+    // Sorted from the longest prefix to the shortest
+    prefixes: Vec<(String, MethodPolicy)>,
+}
+
+impl MethodPolicies {
+    /// Add a policy for `pattern`, which is either a method or a prefix ending in `*`.
+    pub fn insert(&mut self, pattern: &str, policy: MethodPolicy) {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => {
+                self.prefixes.retain(|(existing, _)| existing != prefix);
+                self.prefixes.push((prefix.to_string(), policy));
+                self.prefixes
+                    .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+            }
+            None => {
+                self.methods.insert(pattern.to_string(), policy);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty() && self.prefixes.is_empty()
+    }
+
+    /// Policy for requests calling `method`, falling back to `default`.
+    pub fn get(&self, method: &str, default: RequestPolicy) -> RequestPolicy {
+        if self.is_empty() {
+            return default;
+        }
+
+        let policy = self.methods.get(method).or_else(|| {
+            self.prefixes
+                .iter()
+                .find(|(prefix, _)| method.starts_with(prefix.as_str()))
+                .map(|(_, policy)| policy)
+        });
+
+        match policy {
+            Some(policy) => policy.apply(default),
+            None => default,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_policies() {
+        let mut policies = MethodPolicies::default();
+        policies.insert(
+            "debug_*",
+            MethodPolicy {
+                ttl: Some(Duration::from_secs(30)),
+                max_retries: Some(2),
+                ..Default::default()
+            },
+        );
+        policies.insert(
+            "debug_traceCall*",
+            MethodPolicy {
+                max_retries: Some(4),
+                ..Default::default()
+            },
+        );
+        policies.insert(
+            "eth_sendRawTransaction",
+            MethodPolicy {
+                retry: Some(false),
+                max_retries: Some(8),
+                ..Default::default()
+            },
+        );
+
+        let default = RequestPolicy::new(30, 32);
+        assert_eq!(policies.get("eth_call", default), default);
+
+        let trace = policies.get("debug_traceTransaction", default);
+        assert_eq!(trace.ttl, Duration::from_secs(30));
+        assert_eq!(trace.max_retries, 2);
+        assert_eq!(trace.hedge_after, None);
+
+        // Longest prefix wins, without inheriting from shorter ones
+        let trace_call = policies.get("debug_traceCall", default);
+        assert_eq!(trace_call.ttl, Duration::from_millis(30));
+        assert_eq!(trace_call.max_retries, 4);
+
+        assert_eq!(
+            policies.get("eth_sendRawTransaction", default).max_retries,
+            1
+        );
+    }
+
+    #[test]
+    fn test_hedge_after() {
+        let rpc = Rpc::default();
+        let p90 = HedgeAfter::Latency(LatencyMetric::P90);
+        assert_eq!(p90.delay(&rpc), None);
+
+        rpc.status.set_latency(2_000_000.0);
+        assert_eq!(p90.delay(&rpc), Some(Duration::from_millis(2)));
+        assert_eq!(
+            HedgeAfter::Delay(Duration::from_millis(5)).delay(&rpc),
+            Some(Duration::from_millis(5))
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let mut policy = RequestPolicy::new(1000, 4);
+        assert_eq!(policy.retry_delay(1), Duration::ZERO);
+
+        policy.retry_backoff = Duration::from_millis(10);
+        assert_eq!(policy.retry_delay(1), Duration::from_millis(10));
+        assert_eq!(policy.retry_delay(3), Duration::from_millis(40));
+        assert_eq!(policy.retry_delay(100), Duration::from_millis(10 << 10));
+
+        policy.jitter = 0.5;
+        for _ in 0..32 {
+            let delay = policy.retry_delay(1);
+            assert!(delay > Duration::from_millis(5) && delay <= Duration::from_millis(10));
+        }
+    }
+}
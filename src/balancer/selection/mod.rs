@@ -1,2 +1,6 @@
 pub mod cache_rules;
+pub mod error_rules;
+pub mod method_policy;
+pub mod queue;
 pub mod select;
+pub mod strategy;
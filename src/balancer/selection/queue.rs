@@ -0,0 +1,227 @@
+//! Bounded queue for requests that can't get a token from any RPC.
+//!
+//! Instead of going over an RPC's `max_per_second`, or sending requests to an
+//! RPC that told us to back off, requests wait here until one of the RPCs gets
+//! a token, or until `queue_timeout` passes.
+
+use crate::{
+    balancer::{
+        response_errors::ErrorKind,
+        selection::{
+            select::{
+                next_token_in,
+                pick,
+                ServingTier,
+            },
+            strategy::SelectionStrategy,
+        },
+    },
+    rpc::types::InFlightGuard,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::Semaphore,
+    time::{
+        sleep,
+        Instant,
+    },
+};
+
+// Don't spin if a token is about to become available
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitSettings {
+    // Max amount of requests waiting for a token. 0 disables queueing.
+    pub queue_size: usize,
+    // How long a request can wait for a token
+    pub queue_timeout: Duration,
+    // How long to back off from an RPC that is rate limiting us without saying for how long
+    pub default_backoff: Duration,
+    // Upper bound for `Retry-After`
+    pub max_backoff: Duration,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            queue_size: 1024,
+            queue_timeout: Duration::from_millis(1000),
+            default_backoff: Duration::from_millis(1000),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimitQueue {
+    pub settings: RateLimitSettings,
+    // Tier of the RPC list we pick from
+    pub serving_tier: ServingTier,
+    slots: Semaphore,
+}
+
+impl Default for RateLimitQueue {
+    fn default() -> Self {
+        Self::new(RateLimitSettings::default())
+    }
+}
+
+impl RateLimitQueue {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        Self {
+            settings,
+            serving_tier: ServingTier::default(),
+            slots: Semaphore::new(settings.queue_size),
+        }
+    }
+
+    /// How long to back off from an RPC that is rate limiting us.
+    pub fn backoff(&self, retry_after: Option<Duration>) -> Duration {
+        retry_after
+            .unwrap_or(self.settings.default_backoff)
+            .min(self.settings.max_backoff)
+    }
+
+    /// Pick an RPC, waiting in the queue if every available one is rate limited.
+    ///
+    /// The returned RPC is already counted as in flight.
+    pub async fn pick(
+        &self,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        strategy: &dyn SelectionStrategy,
+    ) -> Result<(Rpc, usize, InFlightGuard), ErrorKind> {
+        let deadline = Instant::now() + self.settings.queue_timeout;
+        let mut _slot = None;
+
+        loop {
+            let wait = {
+                let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+                match pick(&rpc_list, strategy, self.serving_tier.top()) {
+                    (rpc, Some(position)) => {
+                        self.serving_tier.update(rpc.tier);
+                        // Count it while we hold the lock so concurrent picks see it
+                        let in_flight = rpc.track_in_flight();
+                        return Ok((rpc, position, in_flight));
+                    }
+                    (_, None) => next_token_in(&rpc_list),
+                }
+            };
+
+            // Nothing to wait for if there are no RPCs at all
+            let wait = match wait {
+                Some(wait) => wait.max(MIN_WAIT),
+                None => return Err(ErrorKind::NoRpcAvailable),
+            };
+
+            if _slot.is_none() {
+                match self.slots.try_acquire() {
+                    Ok(slot) => _slot = Some(slot),
+                    Err(_) => return Err(ErrorKind::RateLimited),
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ErrorKind::RateLimited);
+            }
+            sleep(wait.min(deadline - now)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::selection::strategy::WeightedRoundRobin;
+
+    fn limited_rpc_list(min_time_delta: u128) -> Arc<RwLock<Vec<Rpc>>> {
+        let mut rpc = Rpc::default();
+        rpc.min_time_delta = min_time_delta;
+        Arc::new(RwLock::new(vec![rpc]))
+    }
+
+    #[tokio::test]
+    async fn test_queue_waits_for_token() {
+        let rpc_list = limited_rpc_list(50_000);
+        let queue = RateLimitQueue::default();
+        let strategy = WeightedRoundRobin::default();
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            let (_, position, _) = queue.pick(&rpc_list, &strategy).await.unwrap();
+            assert_eq!(position, 0);
+        }
+
+        // 1 token right away, and then 1 every 50ms
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    // Throttled RPCs are waited on like rate limited ones
+    #[tokio::test]
+    async fn test_queue_waits_for_backoff() {
+        let rpc_list = limited_rpc_list(0);
+        let queue = RateLimitQueue::default();
+        let strategy = WeightedRoundRobin::default();
+
+        rpc_list.read().unwrap()[0].record_throttle(queue.backoff(Some(Duration::from_millis(50))));
+        assert_eq!(rpc_list.read().unwrap()[0].status.throttled(), 1);
+
+        let start = Instant::now();
+        assert!(queue.pick(&rpc_list, &strategy).await.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(40));
+
+        // Retry-After is capped
+        assert_eq!(
+            queue.backoff(Some(Duration::from_secs(3600))),
+            Duration::from_secs(60)
+        );
+        assert_eq!(queue.backoff(None), Duration::from_millis(1000));
+    }
+
+    #[tokio::test]
+    async fn test_queue_timeout() {
+        let rpc_list = limited_rpc_list(10_000_000);
+        let queue = RateLimitQueue::new(RateLimitSettings {
+            queue_size: 8,
+            queue_timeout: Duration::from_millis(20),
+            ..Default::default()
+        });
+        let strategy = WeightedRoundRobin::default();
+
+        assert!(queue.pick(&rpc_list, &strategy).await.is_ok());
+        assert_eq!(
+            queue.pick(&rpc_list, &strategy).await.unwrap_err(),
+            ErrorKind::RateLimited
+        );
+
+        // No queueing at all
+        let queue = RateLimitQueue::new(RateLimitSettings {
+            queue_size: 0,
+            queue_timeout: Duration::from_secs(10),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert_eq!(
+            queue.pick(&rpc_list, &strategy).await.unwrap_err(),
+            ErrorKind::RateLimited
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // No RPCs to wait for
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        assert_eq!(
+            queue.pick(&rpc_list, &strategy).await.unwrap_err(),
+            ErrorKind::NoRpcAvailable
+        );
+    }
+}
@@ -1,122 +1,211 @@
-use crate::Rpc;
-use std::time::SystemTime;
+use crate::{
+    balancer::selection::strategy::SelectionStrategy,
+    log_info,
+    log_wrn,
+    rpc::latency::LatencyMetric,
+    Rpc,
+};
+use std::{
+    sync::atomic::{
+        AtomicU32,
+        Ordering,
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
+};
+
+/// Tier an RPC list is being served from, so we can log when it changes.
+#[derive(Debug)]
+pub struct ServingTier {
+    // `u32::MAX` before the first request
+    current: AtomicU32,
+    // Highest tier we have RPCs configured in, poverty list included
+    top: AtomicU32,
+}
 
-// Generic entry point fn to select the next rpc and return its position
-pub fn pick(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
-    // If len is 1, return the only element
-    if list.len() == 1 {
-        return (list[0].clone(), Some(0));
-    } else if list.is_empty() {
-        return (Rpc::default(), None);
+impl Default for ServingTier {
+    fn default() -> Self {
+        Self {
+            current: AtomicU32::new(u32::MAX),
+            top: AtomicU32::new(0),
+        }
     }
-
-    algo(list)
 }
 
-// Sorting algo
-pub fn argsort(data: &[Rpc]) -> Vec<usize> {
-    let mut indices = (0..data.len()).collect::<Vec<usize>>();
+impl ServingTier {
+    /// Tier of the RPC that got the last request, if any.
+    pub fn get(&self) -> Option<u32> {
+        match self.current.load(Ordering::Relaxed) {
+            u32::MAX => None,
+            tier => Some(tier),
+        }
+    }
 
-    // Use sort_by_cached_key with a closure that compares latency
-    // Uses pdqsort and does not allocate so should be fast
-    indices.sort_unstable_by_key(|&index| data[index].status.latency as u64);
+    /// Highest tier we have RPCs configured in.
+    pub fn top(&self) -> u32 {
+        self.top.load(Ordering::Relaxed)
+    }
 
-    indices
+    /// Sets the highest tier from every configured RPC, whether it's
+    /// currently in the RPC list or in poverty.
+    pub fn set_top<'a>(&self, rpcs: impl IntoIterator<Item = &'a Rpc>) {
+        let top = rpcs.into_iter().map(|rpc| rpc.tier).min().unwrap_or(0);
+        self.top.store(top, Ordering::Relaxed);
+    }
+
+    /// Called with the tier of every RPC picked.
+    pub fn update(&self, tier: u32) {
+        let previous = self.current.swap(tier, Ordering::Relaxed);
+        if previous == tier {
+            return;
+        }
+
+        // Before the first request, compare against the highest tier we have
+        let previous = match previous {
+            u32::MAX => self.top(),
+            previous => previous,
+        };
+
+        if tier > previous {
+            log_wrn!(
+                "No RPCs available in tier {}, spilling over to tier {}.",
+                previous,
+                tier
+            );
+        } else if tier < previous {
+            log_info!(
+                "Tier {} available again, moving back from tier {}.",
+                tier,
+                previous
+            );
+        }
+    }
 }
 
-// Selection algorithms
-//
-// Selected via features. selection-weighed-round-robin is a default feature.
-// In order to have custom algos, you must add and enable the feature,
-// as well as modify the cfg of the default algo to accomodate your new feature.
+// Generic entry point fn to select the next rpc and return its position
 //
-#[cfg(all(
-    feature = "selection-weighed-round-robin",
-    not(feature = "selection-random"),
-    not(feature = "old-weighted-round-robin"),
-))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
-    // Sort by latency
-    let indices = argsort(list);
-
-    let time = SystemTime::now()
+// RPCs with an open circuit breaker, a spent compute unit budget, or without a token
+// left in their bucket are skipped, and `strategy` picks among the rest.
+// We only spill over to a lower tier if no RPC in a higher one is available.
+// `top_tier` is the highest tier we have RPCs configured in, so picks below it
+// count as spills even when the RPCs above are in poverty.
+pub fn pick(list: &[Rpc], strategy: &dyn SelectionStrategy, top_tier: u32) -> (Rpc, Option<usize>) {
+    pick_from(list, strategy, top_tier, None)
+}
+
+/// Same as `pick`, but never picks the RPC at `except`.
+pub fn pick_except(
+    list: &[Rpc],
+    strategy: &dyn SelectionStrategy,
+    top_tier: u32,
+    except: usize,
+) -> (Rpc, Option<usize>) {
+    pick_from(list, strategy, top_tier, Some(except))
+}
+
+fn pick_from(
+    list: &[Rpc],
+    strategy: &dyn SelectionStrategy,
+    top_tier: u32,
+    except: Option<usize>,
+) -> (Rpc, Option<usize>) {
+    let now = Instant::now();
+    let now_micros = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("Failed to get current time")
         .as_micros();
 
-    // Picks the second fastest one rpc that meets our requirements
-    // Also take into account min_delta_time
-
-    // Set fastest rpc as default
-    let mut choice = indices[0];
-    let mut choice_consecutive = 0;
-    for i in indices.iter().rev() {
-        if list[*i].max_consecutive > list[*i].consecutive
-            && (time - list[*i].last_used > list[*i].min_time_delta)
-        {
-            choice = *i;
-            choice_consecutive = list[*i].consecutive;
-        }
+    let mut available: Vec<usize> = (0..list.len())
+        .filter(|&index| Some(index) != except && list[index].can_pick(now))
+        .collect();
 
-        // remove consecutive
-        list[*i].consecutive = 0;
-    }
+    let choice = loop {
+        let tier = highest_available_tier(list, &available, now);
+
+        // If only one is available, return it
+        let choice = match tier.len() {
+            0 => return (Rpc::default(), None),
+            1 => tier[0],
+            _ => strategy.select(list, &tier),
+        };
+
+        // Someone else could have taken the last token since we checked
+        if list[choice].try_acquire_token(now) {
+            break choice;
+        }
+        available.retain(|&index| index != choice);
+    };
 
-    // If no RPC has been selected, fall back to the fastest RPC
-    list[choice].consecutive = choice_consecutive + 1;
-    list[choice].last_used = time;
+    // Spilling over means there's a higher tier that couldn't take the request
+    list[choice].record_pick(now, now_micros, list[choice].tier > top_tier);
     (list[choice].clone(), Some(choice))
 }
 
-#[cfg(all(
-    feature = "selection-weighed-round-robin",
-    feature = "selection-random"
-))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
-    use rand::Rng;
+/// Narrow `available` down to the RPCs of the highest tier that have a token left.
+fn highest_available_tier(list: &[Rpc], available: &[usize], now: Instant) -> Vec<usize> {
+    let with_token: Vec<usize> = available
+        .iter()
+        .copied()
+        .filter(|&index| list[index].token_wait(now).is_zero())
+        .collect();
+
+    match with_token.iter().map(|&index| list[index].tier).min() {
+        Some(tier) => {
+            with_token
+                .into_iter()
+                .filter(|&index| list[index].tier == tier)
+                .collect()
+        }
+        None => Vec::new(),
+    }
+}
 
-    let mut rng = rand::thread_rng();
-    let index = rng.gen_range(0..list.len());
-    (list[index].clone(), Some(index))
+/// If `pick` found no RPC only because of rate limits, returns how long
+/// until one of them gets a token.
+pub fn next_token_in(list: &[Rpc]) -> Option<Duration> {
+    let now = Instant::now();
+    list.iter()
+        .filter(|rpc| rpc.can_pick(now))
+        .map(|rpc| rpc.token_wait(now))
+        .min()
 }
 
-#[cfg(all(
-    feature = "selection-weighed-round-robin",
-    feature = "old-weighted-round-robin",
-))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
-    // Sort by latency
-    let indices = argsort(list);
-
-    // Picks the second fastest one if the fastest one has maxed out
-    if list[indices[0]].max_consecutive <= list[indices[0]].consecutive {
-        list[indices[1]].consecutive = 1;
-        list[indices[0]].consecutive = 0;
-        return (list[indices[1]].clone(), Some(indices[1]));
-    }
+// Sorting algo
+pub fn argsort(data: &[Rpc], metric: LatencyMetric) -> Vec<usize> {
+    let mut indices = (0..data.len()).collect::<Vec<usize>>();
+
+    // Use sort_by_cached_key with a closure that compares latency
+    // Uses pdqsort and does not allocate so should be fast
+    indices.sort_unstable_by_key(|&index| data[index].status.latency_of(metric) as u64);
 
-    list[indices[0]].consecutive += 1;
-    (list[indices[0]].clone(), Some(indices[0]))
+    indices
 }
 
 // Tests
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        balancer::selection::strategy::WeightedRoundRobin,
+        rpc::breaker::BreakerSettings,
+    };
 
     #[test]
     fn test_sort_algo() {
-        let mut rpc1 = Rpc::default();
-        let mut rpc2 = Rpc::default();
-        let mut rpc3 = Rpc::default();
+        let rpc1 = Rpc::default();
+        let rpc2 = Rpc::default();
+        let rpc3 = Rpc::default();
 
-        rpc1.status.latency = 1.0;
-        rpc2.status.latency = 2.0;
-        rpc3.status.latency = 3.0;
+        rpc1.status.set_latency(1.0);
+        rpc2.status.set_latency(2.0);
+        rpc3.status.set_latency(3.0);
 
         let v = vec![rpc2, rpc3, rpc1];
         let vx = v.clone();
-        let i = argsort(&v);
+        let i = argsort(&v, LatencyMetric::Mean);
         assert_eq!(i, &[2, 0, 1]);
         assert_eq!(v[0].get_url(), vx[0].get_url());
     }
@@ -130,36 +219,36 @@ mod tests {
         let mut rpc2 = Rpc::default();
         let mut rpc3 = Rpc::default();
 
-        rpc1.status.latency = 3.0;
+        rpc1.status.set_latency(3.0);
         rpc1.max_consecutive = 10;
         rpc1.min_time_delta = 100;
 
-        rpc2.status.latency = 7.0;
+        rpc2.status.set_latency(7.0);
         rpc2.max_consecutive = 10;
         rpc2.min_time_delta = 100;
 
-        rpc3.status.latency = 5.0;
+        rpc3.status.set_latency(5.0);
         rpc3.max_consecutive = 10;
         rpc3.min_time_delta = 100;
 
-        let mut rpc_list = vec![rpc1, rpc2, rpc3];
+        let rpc_list = vec![rpc1, rpc2, rpc3];
 
-        let (rpc, index) = pick(&mut rpc_list);
+        let (rpc, index) = pick(&rpc_list, &WeightedRoundRobin::default(), 0);
         println!("rpc: {:?}", rpc);
-        assert_eq!(rpc.status.latency, 3.0);
+        assert_eq!(rpc.status.latency(), 3.0);
         assert_eq!(index, Some(0));
 
-        rpc_list[0].status.latency = 10000.0;
+        rpc_list[0].status.set_latency(10000.0);
 
-        let (rpc, index) = pick(&mut rpc_list);
+        let (rpc, index) = pick(&rpc_list, &WeightedRoundRobin::default(), 0);
         println!("rpc index: {:?}", index);
-        assert_eq!(rpc.status.latency, 5.0);
+        assert_eq!(rpc.status.latency(), 5.0);
         assert_eq!(index, Some(2));
 
-        rpc_list[2].status.latency = 100000.0;
+        rpc_list[2].status.set_latency(100000.0);
 
-        let (rpc, index) = pick(&mut rpc_list);
-        assert_eq!(rpc.status.latency, 7.0);
+        let (rpc, index) = pick(&rpc_list, &WeightedRoundRobin::default(), 0);
+        assert_eq!(rpc.status.latency(), 7.0);
         assert_eq!(index, Some(1));
     }
 
@@ -170,34 +259,217 @@ mod tests {
         let mut rpc2 = Rpc::default();
         let mut rpc3 = Rpc::default();
 
-        rpc1.status.latency = 3.0;
+        rpc1.status.set_latency(3.0);
         rpc1.max_consecutive = 10;
         rpc1.min_time_delta = 1701357164371770;
-        rpc1.last_used = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Failed to get current time")
-            .as_micros();
-
-        rpc2.status.latency = 7.0;
+        rpc1.status.set_last_used(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Failed to get current time")
+                .as_micros(),
+        );
+
+        rpc2.status.set_latency(7.0);
         rpc2.max_consecutive = 10;
         rpc2.min_time_delta = 1;
 
-        rpc3.status.latency = 5.0;
+        rpc3.status.set_latency(5.0);
         rpc3.max_consecutive = 10;
         rpc3.min_time_delta = 10000000;
 
-        let mut rpc_list = vec![rpc1, rpc2, rpc3];
+        let rpc_list = vec![rpc1, rpc2, rpc3];
 
         // Pick rpc3 becauese rpc1 does not meet last used requirements
-        let (rpc, index) = pick(&mut rpc_list);
+        let (rpc, index) = pick(&rpc_list, &WeightedRoundRobin::default(), 0);
         println!("rpc: {:?}", rpc);
-        assert_eq!(rpc.status.latency, 5.0);
+        assert_eq!(rpc.status.latency(), 5.0);
         assert_eq!(index, Some(2));
 
         // pick rpc2 because rpc3 was just used
-        let (rpc, index) = pick(&mut rpc_list);
+        let (rpc, index) = pick(&rpc_list, &WeightedRoundRobin::default(), 0);
         println!("rpc index: {:?}", index);
-        assert_eq!(rpc.status.latency, 7.0);
+        assert_eq!(rpc.status.latency(), 7.0);
         assert_eq!(index, Some(1));
     }
+
+    // RPCs with an open circuit breaker should never get picked
+    #[test]
+    fn test_pick_skips_open_breaker() {
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+
+        rpc1.status.set_latency(3.0);
+        rpc1.max_consecutive = 10;
+        rpc2.status.set_latency(7.0);
+        rpc2.max_consecutive = 10;
+
+        for _ in 0..BreakerSettings::default().consecutive_failures {
+            rpc1.record_failure(crate::rpc::error::FailureClass::Timeout);
+        }
+
+        let rpc_list = vec![rpc1, rpc2];
+        for _ in 0..5 {
+            assert_eq!(
+                pick(&rpc_list, &WeightedRoundRobin::default(), 0).1,
+                Some(1)
+            );
+        }
+
+        // No RPCs available at all
+        let rpc_list = vec![rpc_list[0].clone()];
+        assert_eq!(pick(&rpc_list, &WeightedRoundRobin::default(), 0).1, None);
+    }
+
+    // Lower tiers only get picked when the higher ones can't take the request
+    #[test]
+    fn test_pick_tiers() {
+        let primary = Rpc::default();
+        let mut backup = Rpc::default();
+        let mut last_resort = Rpc::default();
+
+        primary.status.set_latency(50.0);
+        backup.status.set_latency(1.0);
+        backup.tier = 1;
+        last_resort.status.set_latency(1.0);
+        last_resort.tier = 2;
+
+        // Slower, but in a higher tier
+        let rpc_list = vec![backup, last_resort, primary];
+        for _ in 0..5 {
+            assert_eq!(
+                pick(&rpc_list, &WeightedRoundRobin::default(), 0).1,
+                Some(2)
+            );
+        }
+        assert_eq!(rpc_list[0].status.spilled(), 0);
+
+        // Open breaker
+        for _ in 0..BreakerSettings::default().consecutive_failures {
+            rpc_list[2].record_failure(crate::rpc::error::FailureClass::Timeout);
+        }
+        assert_eq!(
+            pick(&rpc_list, &WeightedRoundRobin::default(), 0).1,
+            Some(0)
+        );
+        assert_eq!(rpc_list[0].status.spilled(), 1);
+
+        // The primary is in poverty, so it's not in the list at all. Still spilling over.
+        let rpc_list = vec![rpc_list[1].clone(), rpc_list[0].clone()];
+        assert_eq!(
+            pick(&rpc_list, &WeightedRoundRobin::default(), 0).1,
+            Some(1)
+        );
+        assert_eq!(rpc_list[1].status.spilled(), 2);
+
+        // Unless the backup tier is the highest one we have
+        assert_eq!(
+            pick(&rpc_list, &WeightedRoundRobin::default(), 1).1,
+            Some(1)
+        );
+        assert_eq!(rpc_list[1].status.spilled(), 2);
+    }
+
+    #[test]
+    fn test_serving_tier() {
+        let mut backup = Rpc::default();
+        backup.tier = 1;
+        let mut last_resort = Rpc::default();
+        last_resort.tier = 2;
+        let rpc_list = vec![backup, last_resort];
+        let poverty_list = vec![Rpc::default()];
+
+        // Each list keeps its own
+        let serving_tier = ServingTier::default();
+        let other = ServingTier::default();
+        assert_eq!(serving_tier.get(), None);
+
+        // The top tier counts RPCs in poverty too
+        serving_tier.set_top(&rpc_list);
+        assert_eq!(serving_tier.top(), 1);
+        serving_tier.set_top(rpc_list.iter().chain(&poverty_list));
+        assert_eq!(serving_tier.top(), 0);
+
+        serving_tier.update(1);
+        assert_eq!(serving_tier.get(), Some(1));
+        serving_tier.update(2);
+        assert_eq!(serving_tier.get(), Some(2));
+        assert_eq!(other.get(), None);
+    }
+
+    // Rate limited tiers spill over, until every RPC is out of tokens
+    #[test]
+    fn test_pick_tiers_rate_limited() {
+        let mut primary = Rpc::default();
+        let mut backup = Rpc::default();
+
+        primary.min_time_delta = 10_000_000;
+        primary.burst = 2;
+        backup.min_time_delta = 10_000_000;
+        backup.tier = 1;
+
+        let rpc_list = vec![primary, backup];
+        assert_eq!(next_token_in(&rpc_list), Some(Duration::ZERO));
+        assert_eq!(
+            pick(&rpc_list, &WeightedRoundRobin::default(), 0).1,
+            Some(0)
+        );
+        assert_eq!(
+            pick(&rpc_list, &WeightedRoundRobin::default(), 0).1,
+            Some(0)
+        );
+        assert_eq!(
+            pick(&rpc_list, &WeightedRoundRobin::default(), 0).1,
+            Some(1)
+        );
+
+        // Never goes over the limit, even if everything is out of tokens
+        assert_eq!(pick(&rpc_list, &WeightedRoundRobin::default(), 0).1, None);
+        assert!(next_token_in(&rpc_list).unwrap() > Duration::from_secs(9));
+
+        // Unlimited RPCs always have a token
+        let rpc_list = vec![Rpc::default()];
+        for _ in 0..100 {
+            assert_eq!(
+                pick(&rpc_list, &WeightedRoundRobin::default(), 0).1,
+                Some(0)
+            );
+        }
+    }
+
+    // RPCs that spent their budget are skipped, and not waited on
+    #[test]
+    fn test_pick_skips_spent_budget() {
+        use crate::rpc::budget::{
+            BudgetWindow,
+            ComputeBudget,
+        };
+        use std::sync::Arc;
+
+        let mut rpc1 = Rpc::default();
+        let rpc2 = Rpc::default();
+        rpc1.status.set_latency(1.0);
+        rpc2.status.set_latency(10.0);
+        rpc1.budget = Some(Arc::new(ComputeBudget::new(
+            "rpc1".to_string(),
+            20,
+            BudgetWindow::Monthly,
+            10,
+            Default::default(),
+        )));
+
+        let rpc_list = vec![rpc1, rpc2];
+        for _ in 0..2 {
+            let (rpc, index) = pick(&rpc_list, &WeightedRoundRobin::default(), 0);
+            assert_eq!(index, Some(0));
+            rpc.charge("eth_call");
+        }
+        assert_eq!(
+            pick(&rpc_list, &WeightedRoundRobin::default(), 0).1,
+            Some(1)
+        );
+
+        let rpc_list = vec![rpc_list[0].clone()];
+        assert_eq!(pick(&rpc_list, &WeightedRoundRobin::default(), 0).1, None);
+        assert_eq!(next_token_in(&rpc_list), None);
+    }
 }
@@ -0,0 +1,370 @@
+//! Load balancing strategies used by `pick`.
+//!
+//! The strategy is chosen via the `selection_strategy` config option, and can
+//! be changed at runtime with the `blutgang_set_selection_strategy` admin method.
+//!
+//! Strategies that compare latencies use the stat set by the `latency_metric`
+//! config option, eg. `p90` to prefer RPCs with a good tail latency.
+//!
+//! To add your own strategy, implement `SelectionStrategy` for it and call
+//! `register_strategy` before the config gets parsed.
+
+use crate::{
+    balancer::selection::select::argsort,
+    rpc::latency::LatencyMetric,
+    Rpc,
+};
+
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    sync::{
+        Arc,
+        OnceLock,
+        RwLock,
+    },
+    time::SystemTime,
+};
+
+pub const DEFAULT_STRATEGY: &str = "weighted_round_robin";
+
+pub trait SelectionStrategy: Debug + Send + Sync {
+    /// Name the strategy is registered under.
+    fn name(&self) -> &'static str;
+
+    /// Return the index of the next RPC in `list` to send a request to.
+    ///
+    /// `available` contains the indices of RPCs that can be picked,
+    /// and always has at least 2 entries.
+    fn select(&self, list: &[Rpc], available: &[usize]) -> usize;
+}
+
+/// Builds a strategy that compares RPC latencies by the given metric.
+type StrategyConstructor = fn(LatencyMetric) -> Arc<dyn SelectionStrategy>;
+
+fn registry() -> &'static RwLock<BTreeMap<&'static str, StrategyConstructor>> {
+    static REGISTRY: OnceLock<RwLock<BTreeMap<&'static str, StrategyConstructor>>> =
+        OnceLock::new();
+
+    REGISTRY.get_or_init(|| {
+        let mut strategies: BTreeMap<&'static str, StrategyConstructor> = BTreeMap::new();
+        strategies.insert("weighted_round_robin", |metric| {
+            Arc::new(WeightedRoundRobin { metric })
+        });
+        strategies.insert("random", |_| Arc::new(Random));
+        strategies.insert("old_weighted_round_robin", |metric| {
+            Arc::new(OldWeightedRoundRobin { metric })
+        });
+        strategies.insert("least_outstanding", |metric| {
+            Arc::new(LeastOutstanding { metric })
+        });
+        strategies.insert("power_of_two", |metric| Arc::new(PowerOfTwo { metric }));
+        RwLock::new(strategies)
+    })
+}
+
+/// Make a strategy available under `name`. Replaces any existing strategy with the same name.
+#[allow(dead_code)]
+pub fn register_strategy(name: &'static str, constructor: StrategyConstructor) {
+    registry()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(name, constructor);
+}
+
+/// Get a new instance of the strategy registered under `name`.
+pub fn strategy_from_name(name: &str, metric: LatencyMetric) -> Option<Arc<dyn SelectionStrategy>> {
+    registry()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(name)
+        .map(|constructor| constructor(metric))
+}
+
+/// Names of all registered strategies.
+pub fn strategy_names() -> Vec<&'static str> {
+    registry()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .keys()
+        .copied()
+        .collect()
+}
+
+/// Indices of `available` RPCs sorted by latency.
+fn sorted_available(list: &[Rpc], available: &[usize], metric: LatencyMetric) -> Vec<usize> {
+    argsort(list, metric)
+        .into_iter()
+        .filter(|index| available.contains(index))
+        .collect()
+}
+
+/// Picks the fastest RPC that hasn't hit `max_consecutive` or `max_per_second`.
+#[derive(Debug, Default)]
+pub struct WeightedRoundRobin {
+    pub metric: LatencyMetric,
+}
+
+impl SelectionStrategy for WeightedRoundRobin {
+    fn name(&self) -> &'static str {
+        "weighted_round_robin"
+    }
+
+    fn select(&self, list: &[Rpc], available: &[usize]) -> usize {
+        // Sort by latency
+        let indices = sorted_available(list, available, self.metric);
+
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Failed to get current time")
+            .as_micros();
+
+        // Picks the fastest rpc that meets our requirements
+        // Also take into account min_delta_time
+        //
+        // Other requests might be picking at the same time, so checking and
+        // counting the pick happens in one step.
+        let mut choice = None;
+        for &i in &indices {
+            let rpc = &list[i];
+            if choice.is_none()
+                && rpc
+                    .status
+                    .try_claim(rpc.max_consecutive, rpc.min_time_delta, time)
+            {
+                choice = Some(i);
+                continue;
+            }
+
+            // remove consecutive
+            rpc.status.set_consecutive(0);
+        }
+
+        // If no RPC has been selected, fall back to the fastest RPC
+        choice.unwrap_or_else(|| {
+            let status = &list[indices[0]].status;
+            status.set_consecutive(1);
+            status.set_last_used(time);
+            indices[0]
+        })
+    }
+}
+
+/// Picks a random RPC.
+#[derive(Debug)]
+pub struct Random;
+
+impl SelectionStrategy for Random {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn select(&self, _list: &[Rpc], available: &[usize]) -> usize {
+        use rand::Rng;
+
+        let mut rng = rand::thread_rng();
+        available[rng.gen_range(0..available.len())]
+    }
+}
+
+/// Old algo, does not account for `max_per_second`.
+#[derive(Debug, Default)]
+pub struct OldWeightedRoundRobin {
+    pub metric: LatencyMetric,
+}
+
+impl SelectionStrategy for OldWeightedRoundRobin {
+    fn name(&self) -> &'static str {
+        "old_weighted_round_robin"
+    }
+
+    fn select(&self, list: &[Rpc], available: &[usize]) -> usize {
+        // Sort by latency
+        let indices = sorted_available(list, available, self.metric);
+
+        // Picks the second fastest one if the fastest one has maxed out
+        let fastest = &list[indices[0]].status;
+        if fastest.try_consecutive(list[indices[0]].max_consecutive) {
+            return indices[0];
+        }
+
+        list[indices[1]].status.set_consecutive(1);
+        fastest.set_consecutive(0);
+        indices[1]
+    }
+}
+
+/// Picks the RPC with the least requests in flight, breaking ties by latency.
+#[derive(Debug, Default)]
+pub struct LeastOutstanding {
+    pub metric: LatencyMetric,
+}
+
+impl SelectionStrategy for LeastOutstanding {
+    fn name(&self) -> &'static str {
+        "least_outstanding"
+    }
+
+    fn select(&self, list: &[Rpc], available: &[usize]) -> usize {
+        *available
+            .iter()
+            .min_by(|&&a, &&b| {
+                list[a].in_flight().cmp(&list[b].in_flight()).then(
+                    list[a]
+                        .status
+                        .latency_of(self.metric)
+                        .total_cmp(&list[b].status.latency_of(self.metric)),
+                )
+            })
+            .unwrap()
+    }
+}
+
+/// Picks 2 random RPCs and sends the request to the better one.
+///
+/// RPCs are scored by their latency, multiplied by the requests they'd have
+/// in flight if we picked them. Lower is better.
+#[derive(Debug, Default)]
+pub struct PowerOfTwo {
+    pub metric: LatencyMetric,
+}
+
+impl PowerOfTwo {
+    fn score(&self, rpc: &Rpc) -> f64 {
+        // Add 1 so RPCs without any latency data yet don't always win
+        (rpc.status.latency_of(self.metric) + 1.0) * (rpc.in_flight() + 1) as f64
+    }
+}
+
+impl SelectionStrategy for PowerOfTwo {
+    fn name(&self) -> &'static str {
+        "power_of_two"
+    }
+
+    fn select(&self, list: &[Rpc], available: &[usize]) -> usize {
+        let mut rng = rand::thread_rng();
+        let choices = rand::seq::index::sample(&mut rng, available.len(), 2);
+        let (a, b) = (available[choices.index(0)], available[choices.index(1)]);
+
+        if self.score(&list[b]) < self.score(&list[a]) {
+            b
+        } else {
+            a
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Always picks the last available RPC
+    #[derive(Debug)]
+    struct Last;
+
+    impl SelectionStrategy for Last {
+        fn name(&self) -> &'static str {
+            "last"
+        }
+
+        fn select(&self, _list: &[Rpc], available: &[usize]) -> usize {
+            *available.last().unwrap()
+        }
+    }
+
+    #[test]
+    fn test_builtin_strategies() {
+        for name in [
+            "weighted_round_robin",
+            "random",
+            "old_weighted_round_robin",
+            "least_outstanding",
+            "power_of_two",
+        ] {
+            assert_eq!(
+                strategy_from_name(name, LatencyMetric::Mean)
+                    .unwrap()
+                    .name(),
+                name
+            );
+        }
+        assert!(strategy_from_name("does_not_exist", LatencyMetric::Mean).is_none());
+    }
+
+    #[test]
+    fn test_register_strategy() {
+        register_strategy("last", |_| Arc::new(Last));
+        assert!(strategy_names().contains(&"last"));
+
+        let strategy = strategy_from_name("last", LatencyMetric::P99).unwrap();
+        let list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+        assert_eq!(strategy.select(&list, &[0, 2]), 2);
+    }
+
+    #[test]
+    fn test_random_only_picks_available() {
+        let list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+        for _ in 0..32 {
+            assert_ne!(Random.select(&list, &[0, 2]), 1);
+        }
+    }
+
+    #[test]
+    fn test_least_outstanding() {
+        let list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+        list[0].status.set_latency(1.0);
+        list[1].status.set_latency(5.0);
+        list[2].status.set_latency(3.0);
+
+        // Fastest one wins if nothing is in flight
+        assert_eq!(LeastOutstanding::default().select(&list, &[0, 1, 2]), 0);
+
+        let _busy = [list[0].track_in_flight(), list[2].track_in_flight()];
+        assert_eq!(LeastOutstanding::default().select(&list, &[0, 1, 2]), 1);
+        assert_eq!(LeastOutstanding::default().select(&list, &[0, 2]), 0);
+    }
+
+    #[test]
+    fn test_latency_metric() {
+        let list = vec![
+            Rpc::new("http://a".to_string(), None, 10, 0, 10.0),
+            Rpc::new("http://b".to_string(), None, 10, 0, 10.0),
+        ];
+        // Same mean, but the first one has a bad tail
+        for _ in 0..9 {
+            list[0].update_latency(1.0);
+            list[1].update_latency(11.0);
+        }
+        list[0].update_latency(91.0);
+        list[1].update_latency(1.0);
+
+        let p99 = LeastOutstanding {
+            metric: LatencyMetric::P99,
+        };
+        assert_eq!(p99.select(&list, &[0, 1]), 1);
+
+        let p50 = LeastOutstanding {
+            metric: LatencyMetric::P50,
+        };
+        assert_eq!(p50.select(&list, &[0, 1]), 0);
+    }
+
+    #[test]
+    fn test_power_of_two() {
+        let list = vec![Rpc::default(), Rpc::default()];
+        list[0].status.set_latency(10.0);
+        list[1].status.set_latency(10.0);
+
+        // With 2 RPCs both get sampled every time, so the less busy one always wins
+        let _busy = [list[0].track_in_flight(), list[0].track_in_flight()];
+        for _ in 0..16 {
+            assert_eq!(PowerOfTwo::default().select(&list, &[0, 1]), 1);
+        }
+
+        // Much faster RPCs can still win while busier
+        list[0].status.set_latency(1.0);
+        for _ in 0..16 {
+            assert_eq!(PowerOfTwo::default().select(&list, &[0, 1]), 0);
+        }
+    }
+}
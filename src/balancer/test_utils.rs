@@ -0,0 +1,147 @@
+//! Helpers shared by the tests of the `balancer` module.
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::{
+    io::{
+        AsyncReadExt,
+        AsyncWriteExt,
+    },
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+    time::sleep,
+};
+
+/// What a `MockRpc` answers a request with.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: &'static str,
+    // Extra header lines, each ending in `\r\n`
+    headers: &'static str,
+    body: String,
+    delay: Duration,
+}
+
+impl MockResponse {
+    /// `200 OK` with `body`, right away.
+    pub fn ok(body: impl ToString) -> Self {
+        Self {
+            status: "200 OK",
+            headers: "",
+            body: body.to_string(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn status(mut self, status: &'static str) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn headers(mut self, headers: &'static str) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// Fake RPC that answers every request with whatever `respond` returns for it,
+/// counting how many requests it got.
+pub struct MockRpc {
+    pub url: String,
+    requests: Arc<AtomicUsize>,
+}
+
+impl MockRpc {
+    pub async fn spawn(respond: impl Fn(&Value) -> MockResponse + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let respond = Arc::new(respond);
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let respond = respond.clone();
+                tokio::spawn(async move {
+                    let tx = read_request(&mut stream).await;
+                    let response = respond(&tx);
+                    sleep(response.delay).await;
+
+                    let response = format!(
+                        "HTTP/1.1 {}\r\n{}content-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        response.status,
+                        response.headers,
+                        response.body.len(),
+                        response.body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    /// Fake RPC that answers every request with `body`.
+    pub async fn fixed(body: &'static str) -> Self {
+        Self::spawn(move |_| MockResponse::ok(body)).await
+    }
+
+    /// Amount of requests the RPC got so far.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+}
+
+/// Url nothing is listening on, so connections to it get refused.
+pub async fn refused_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    format!("http://{}", listener.local_addr().unwrap())
+}
+
+// Read a whole request, since the body doesn't always come in with the headers.
+// Returns the JSON body, or `Value::Null` if there isn't one.
+async fn read_request(stream: &mut TcpStream) -> Value {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return Value::Null,
+            Ok(n) => n,
+        };
+        request.extend_from_slice(&buf[..n]);
+
+        let request = String::from_utf8_lossy(&request);
+        let Some((headers, body)) = request.split_once("\r\n\r\n") else {
+            continue;
+        };
+        let length = headers
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, length)| length.trim().parse().ok())
+            .unwrap_or(0);
+        if body.len() >= length {
+            return serde_json::from_str(body).unwrap_or(Value::Null);
+        }
+    }
+}
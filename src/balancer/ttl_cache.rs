@@ -0,0 +1,165 @@
+//! In-memory cache for methods whose results change, but slowly.
+//!
+//! Things like `eth_chainId` or `eth_gasPrice` can't go into the DB, since
+//! entries there only ever get removed when their block reorgs. Instead, their
+//! responses are kept here for the `ttl_ms` of their cache rule, regardless of
+//! what happens to the chain.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use blake3::Hash;
+
+// Don't bother dropping expired entries until we have at least this many
+const MIN_PURGE_LEN: usize = 1024;
+
+#[derive(Debug)]
+struct Entries {
+    map: HashMap<Hash, (Instant, String)>,
+    // Drop expired entries once we grow past this
+    purge_at: usize,
+}
+
+#[derive(Debug)]
+pub struct TtlCache {
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Default for TtlCache {
+    fn default() -> Self {
+        Self {
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                purge_at: MIN_PURGE_LEN,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+}
+
+impl TtlCache {
+    /// Response for `tx_hash`, if we have one that hasn't expired.
+    pub fn get(&self, tx_hash: &Hash, now: Instant) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        let response = match entries.map.get(tx_hash) {
+            Some((expires, response)) if *expires > now => Some(response.clone()),
+            Some(_) => {
+                entries.map.remove(tx_hash);
+                None
+            }
+            None => None,
+        };
+
+        let counter = match response {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        response
+    }
+
+    /// Keep `response` around for `ttl`.
+    pub fn insert(&self, tx_hash: Hash, response: String, ttl: Duration, now: Instant) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.map.insert(tx_hash, (now + ttl, response));
+
+        if entries.map.len() >= entries.purge_at {
+            entries.map.retain(|_, (expires, _)| *expires > now);
+            entries.purge_at = (entries.map.len() * 2).max(MIN_PURGE_LEN);
+        }
+    }
+
+    /// Amount of responses we have, including expired ones that weren't dropped yet.
+    pub fn entries(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .map
+            .len()
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blake3::hash;
+
+    #[test]
+    fn test_ttl_cache() {
+        let cache = TtlCache::default();
+        let tx_hash = hash(b"eth_gasPrice");
+        let now = Instant::now();
+
+        assert_eq!(cache.get(&tx_hash, now), None);
+        cache.insert(
+            tx_hash,
+            "0x3b9aca00".to_string(),
+            Duration::from_secs(3),
+            now,
+        );
+        assert_eq!(
+            cache.get(&tx_hash, now + Duration::from_secs(2)),
+            Some("0x3b9aca00".to_string())
+        );
+
+        // Expired entries are dropped
+        assert_eq!(cache.get(&tx_hash, now + Duration::from_secs(3)), None);
+        assert_eq!(cache.entries(), 0);
+
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 2);
+    }
+
+    #[test]
+    fn test_ttl_cache_purge() {
+        let cache = TtlCache::default();
+        let now = Instant::now();
+
+        for i in 0..MIN_PURGE_LEN {
+            cache.insert(
+                hash(&i.to_be_bytes()),
+                "0x1".to_string(),
+                Duration::from_millis(10),
+                now,
+            );
+        }
+        assert_eq!(cache.entries(), MIN_PURGE_LEN);
+
+        // Everything above expired by the time we grow past the limit again
+        let later = now + Duration::from_secs(1);
+        for i in 0..MIN_PURGE_LEN {
+            cache.insert(
+                hash(&(i + MIN_PURGE_LEN).to_be_bytes()),
+                "0x1".to_string(),
+                Duration::from_secs(10),
+                later,
+            );
+        }
+        assert_eq!(cache.entries(), MIN_PURGE_LEN);
+    }
+}
@@ -1,13 +1,53 @@
 use crate::{
+    balancer::normalize::{
+        normalize_request,
+        CACHE_KEY_VERSION,
+    },
     config::system::{
         TAGLINE,
         VERSION_STR,
     },
     log_err,
     log_info,
+    log_wrn,
+    rpc::budget::BUDGET_TREE,
+    Rpc,
 };
+use blake3::hash;
+use chrono::Utc;
+use serde_json::json;
 use sled::Db;
 
+/// Key we store the `CACHE_KEY_VERSION` cache keys were made with under.
+const CACHE_KEY_VERSION_KEY: &[u8] = b"cache_key_version";
+
+/// Clears `cache` if its keys were made from requests normalized differently
+/// than they are now, since none of them would ever get hit again.
+///
+/// Compute unit budgets live in their own tree, so they survive this.
+fn check_key_version(cache: &Db) {
+    let stored = cache
+        .get(CACHE_KEY_VERSION_KEY)
+        .ok()
+        .flatten()
+        .and_then(|version| Some(u64::from_be_bytes(version.as_ref().try_into().ok()?)));
+
+    // DBs from before we versioned keys don't have a version at all
+    if stored != Some(CACHE_KEY_VERSION) && !cache.is_empty() {
+        log_wrn!(
+            "Cache keys are from an older version of Blutgang ({}, expected {}), clearing the cache.",
+            stored.map_or("unversioned".to_string(), |version| version.to_string()),
+            CACHE_KEY_VERSION
+        );
+        if let Err(err) = cache.clear() {
+            log_err!("Could not clear the cache: {}", err);
+            return;
+        }
+    }
+
+    let _ = cache.insert(CACHE_KEY_VERSION_KEY, &CACHE_KEY_VERSION.to_be_bytes());
+}
+
 /// Sets up the cache with various basic data about our current blutgang instance.
 pub fn setup_data(cache: Db) {
     let version_json = format!(
@@ -17,24 +57,17 @@ pub fn setup_data(cache: Db) {
 
     log_info!("Starting {}", VERSION_STR);
 
-    // Insert kv pair `blutgang_is_lb` `true` to know what we're interacting with
-    // `blutgang_is_lb` is cached as a blake3 cache
-    let _ = cache.insert(
-        [
-            176, 76, 1, 109, 13, 127, 134, 25, 55, 111, 28, 182, 82, 155, 135, 143, 204, 161, 53,
-            4, 158, 140, 22, 219, 138, 5, 57, 150, 8, 154, 17, 252,
-        ],
-        version_json.as_bytes(),
-    );
-    // Insert kv pair `web3_clientVersion` `true` to know what we're interacting with
-    // `web3_clientVersion` is cached as a blake3 cache
-    let _ = cache.insert(
-        [
-            36, 20, 170, 125, 105, 107, 149, 148, 52, 126, 215, 218, 112, 55, 222, 60, 186, 44, 67,
-            121, 225, 160, 31, 209, 9, 99, 81, 233, 137, 37, 62, 79,
-        ],
-        version_json.as_bytes(),
-    );
+    check_key_version(&cache);
+
+    // Insert kv pairs for `blutgang_is_lb` and `web3_clientVersion` to know what we're interacting with.
+    // Both are cached as a blake3 cache, under the key of their canonical request.
+    for method in ["blutgang_is_lb", "web3_clientVersion"] {
+        let tx = normalize_request(&json!({"id": null, "jsonrpc": "2.0", "method": method}));
+        let _ = cache.insert(
+            hash(tx.to_string().as_bytes()).as_bytes(),
+            version_json.as_bytes(),
+        );
+    }
 
     // Insert which hashing algo we're using based on the selected features.
     // If `xxhash` is enabled we're using xxhash3, otherwise blake3.
@@ -56,3 +89,83 @@ pub fn setup_data(cache: Db) {
         }
     }
 }
+
+/// Restores the compute units RPCs used before we restarted,
+/// and has their budgets persist usage to the cache from now on.
+pub fn setup_budgets(rpc_list: &[Rpc], cache: &Db) {
+    let tree = match cache.open_tree(BUDGET_TREE) {
+        Ok(tree) => tree,
+        Err(err) => {
+            log_err!("Could not open compute unit budgets: {}", err);
+            return;
+        }
+    };
+
+    let now = Utc::now();
+    for budget in rpc_list.iter().filter_map(|rpc| rpc.budget.as_ref()) {
+        budget.load(tree.clone(), now);
+        log_info!(
+            "{} has {} of {} compute units left.",
+            budget.key,
+            budget.remaining(now),
+            budget.limit
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_check_key_version() {
+        // Fresh DBs just get the current version
+        let cache = temp_db();
+        check_key_version(&cache);
+        assert_eq!(cache.len(), 1);
+
+        // Entries made with the current version are kept
+        cache.insert(b"key", b"value").unwrap();
+        check_key_version(&cache);
+        assert!(cache.get(b"key").unwrap().is_some());
+
+        // Unversioned DBs or ones from another version are cleared
+        let cache = temp_db();
+        cache.insert(b"key", b"value").unwrap();
+        check_key_version(&cache);
+        assert!(cache.get(b"key").unwrap().is_none());
+
+        cache.insert(b"key", b"value").unwrap();
+        cache
+            .insert(
+                CACHE_KEY_VERSION_KEY,
+                &(CACHE_KEY_VERSION + 1).to_be_bytes(),
+            )
+            .unwrap();
+        check_key_version(&cache);
+        assert!(cache.get(b"key").unwrap().is_none());
+        assert_eq!(
+            cache.get(CACHE_KEY_VERSION_KEY).unwrap().unwrap().as_ref(),
+            CACHE_KEY_VERSION.to_be_bytes()
+        );
+    }
+
+    #[test]
+    fn test_setup_data_keys() {
+        let cache = temp_db();
+        setup_data(cache.clone());
+
+        // Both spellings of the request hit the same entry
+        for tx in [
+            json!({"id": 1, "jsonrpc": "2.0", "method": "web3_clientVersion", "params": []}),
+            json!({"id": 1, "jsonrpc": "2.0", "method": "blutgang_is_lb"}),
+        ] {
+            let key = hash(normalize_request(&tx).to_string().as_bytes());
+            assert!(cache.get(key.as_bytes()).unwrap().is_some());
+        }
+    }
+}
@@ -73,6 +73,16 @@ pub fn create_match() -> clap::Command {
             .long("health_check")
             .num_args(0..)
             .help("Enable health checking"))
+        .arg(Arg::new("selection_strategy")
+            .long("selection_strategy")
+            .num_args(1..)
+            .default_value("weighted_round_robin")
+            .help("Load balancing strategy. Can be weighted_round_robin/random/old_weighted_round_robin/least_outstanding/power_of_two"))
+        .arg(Arg::new("latency_metric")
+            .long("latency_metric")
+            .num_args(1..)
+            .default_value("mean")
+            .help("Latency stat used to compare RPCs. Can be mean/p50/p90/p99"))
         .arg(Arg::new("ttl")
             .long("ttl")
             .num_args(1..)
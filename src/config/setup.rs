@@ -14,7 +14,7 @@ enum StartingLatencyResp {
 
 /// Get the average latency for a RPC
 async fn set_starting_latency(
-    mut rpc: Rpc,
+    rpc: Rpc,
     ma_length: f64,
     tx: mpsc::Sender<StartingLatencyResp>,
 ) -> Result<(), ConfigError> {
@@ -44,7 +44,7 @@ async fn set_starting_latency(
     let avg_latency = latencies.iter().sum::<f64>() / latencies.len() as f64;
     rpc.update_latency(avg_latency);
 
-    println!("{}: {}ns", rpc.name, rpc.status.latency);
+    println!("{}: {}ns", rpc.name, rpc.status.latency());
 
     tx.send(StartingLatencyResp::Ok(rpc)).await?;
 
@@ -81,9 +81,9 @@ pub async fn sort_by_latency(
     while let Some(rpc) = rx.recv().await {
         let rpc = match rpc {
             StartingLatencyResp::Ok(rax) => rax,
-            StartingLatencyResp::Error(mut rax, e) => {
+            StartingLatencyResp::Error(rax, e) => {
                 log_err!("Adding to poverty list: {}", e);
-                rax.status.is_erroring = true;
+                rax.status.set_erroring(true);
                 poverty_list.push(rax);
                 continue;
             }
@@ -92,7 +92,7 @@ pub async fn sort_by_latency(
     }
 
     // Sort the RPCs by latency
-    sorted_rpc_list.sort_by(|a, b| a.status.latency.partial_cmp(&b.status.latency).unwrap());
+    sorted_rpc_list.sort_by(|a, b| a.status.latency().partial_cmp(&b.status.latency()).unwrap());
 
     Ok((sorted_rpc_list, poverty_list))
 }
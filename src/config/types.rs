@@ -1,7 +1,54 @@
 use crate::{
+    balancer::{
+        coalesce::InFlightCalls,
+        latest_cache::LatestCache,
+        response_errors::{
+            ErrorKind,
+            ErrorStatus,
+        },
+        selection::{
+            cache_rules::{
+                CacheRule,
+                CacheRules,
+            },
+            error_rules::{
+                ErrorAction,
+                ErrorRule,
+                ErrorRules,
+            },
+            method_policy::{
+                HedgeAfter,
+                MethodPolicies,
+                MethodPolicy,
+                RequestPolicy,
+            },
+            queue::{
+                RateLimitQueue,
+                RateLimitSettings,
+            },
+            strategy::{
+                strategy_from_name,
+                strategy_names,
+                SelectionStrategy,
+                DEFAULT_STRATEGY,
+            },
+        },
+        ttl_cache::TtlCache,
+    },
     config::setup::sort_by_latency,
     log_info,
     log_wrn,
+    rpc::{
+        breaker::{
+            BreakerSettings,
+            CircuitBreaker,
+        },
+        budget::{
+            BudgetWindow,
+            ComputeBudget,
+        },
+        latency::LatencyMetric,
+    },
     Rpc,
 };
 use clap::{
@@ -13,6 +60,7 @@ use jsonwebtoken::DecodingKey;
 use sled::Config;
 
 use std::{
+    collections::HashMap,
     fmt,
     fmt::Debug,
     fs::{
@@ -20,10 +68,25 @@ use std::{
     },
     net::SocketAddr,
     println,
+    sync::Arc,
+    time::Duration,
 };
 
 use toml::Value;
 
+/// Top level config tables that are not RPCs.
+const RESERVED_TABLES: [&str; 9] = [
+    "blutgang",
+    "sled",
+    "admin",
+    "error_status",
+    "upstream_errors",
+    "circuit_breaker",
+    "rate_limit",
+    "method_policies",
+    "cache_rules",
+];
+
 #[derive(Clone)]
 pub struct AdminSettings {
     pub enabled: bool,
@@ -72,6 +135,20 @@ pub struct Settings {
     pub health_check_ttl: u64,
     pub sled_config: Config,
     pub admin: AdminSettings,
+    pub error_status: ErrorStatus,
+    pub error_rules: Arc<ErrorRules>,
+    pub breaker: BreakerSettings,
+    pub selection: Arc<dyn SelectionStrategy>,
+    pub latency_metric: LatencyMetric,
+    pub rate_limit: Arc<RateLimitQueue>,
+    pub method_policies: Arc<MethodPolicies>,
+    pub cache_rules: Arc<CacheRules>,
+    // Responses of methods with a `ttl_ms` cache rule
+    pub ttl_cache: Arc<TtlCache>,
+    // Responses for the current head, dropped on every new block
+    pub latest_cache: Arc<LatestCache>,
+    // Cache misses currently being sent upstream, so identical ones can wait on them
+    pub in_flight: Arc<InFlightCalls>,
 }
 
 impl Default for Settings {
@@ -91,11 +168,28 @@ impl Default for Settings {
             health_check_ttl: 1000,
             sled_config: sled::Config::default(),
             admin: AdminSettings::default(),
+            error_status: ErrorStatus::default(),
+            error_rules: Arc::new(ErrorRules::default()),
+            breaker: BreakerSettings::default(),
+            selection: strategy_from_name(DEFAULT_STRATEGY, LatencyMetric::default()).unwrap(),
+            latency_metric: LatencyMetric::default(),
+            rate_limit: Arc::new(RateLimitQueue::default()),
+            method_policies: Arc::new(MethodPolicies::default()),
+            cache_rules: Arc::new(CacheRules::default()),
+            ttl_cache: Arc::new(TtlCache::default()),
+            latest_cache: Arc::new(LatestCache::default()),
+            in_flight: Arc::new(InFlightCalls::default()),
         }
     }
 }
 
 impl Settings {
+    /// Timeout and retries for requests calling `method`.
+    pub fn request_policy(&self, method: &str) -> RequestPolicy {
+        self.method_policies
+            .get(method, RequestPolicy::new(self.ttl, self.max_retries))
+    }
+
     pub async fn new(matches: Command) -> Settings {
         let matches = matches.get_matches();
 
@@ -220,6 +314,29 @@ impl Settings {
             .as_bool()
             .expect("\x1b[31mErr:\x1b[0m Could not parse supress_rpc_check as bool!");
 
+        let latency_metric = match blutgang_table.get("latency_metric") {
+            Some(latency_metric) => {
+                parse_latency_metric(
+                    latency_metric
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse latency_metric as str!"),
+                )
+            }
+            None => LatencyMetric::default(),
+        };
+
+        let selection = match blutgang_table.get("selection_strategy") {
+            Some(selection) => {
+                parse_selection_strategy(
+                    selection
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse selection_strategy as str!"),
+                    latency_metric,
+                )
+            }
+            None => strategy_from_name(DEFAULT_STRATEGY, latency_metric).unwrap(),
+        };
+
         // Parse `sled` table
         let sled_table = parsed_toml
             .get("sled")
@@ -274,13 +391,61 @@ impl Settings {
             .print_profile_on_drop(print_profile)
             .use_compression(compression);
 
+        // Parse the optional `circuit_breaker` table
+        let breaker = match parsed_toml.get("circuit_breaker") {
+            Some(breaker_table) => {
+                parse_breaker_settings(
+                    breaker_table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse circuit_breaker table!"),
+                )
+            }
+            None => BreakerSettings::default(),
+        };
+
+        // Parse the optional `rate_limit` table
+        let rate_limit = match parsed_toml.get("rate_limit") {
+            Some(rate_limit_table) => {
+                parse_rate_limit_settings(
+                    rate_limit_table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse rate_limit table!"),
+                )
+            }
+            None => RateLimitSettings::default(),
+        };
+
+        // Parse the optional `method_policies` table
+        let method_policies = match parsed_toml.get("method_policies") {
+            Some(method_policies_table) => {
+                parse_method_policies(
+                    method_policies_table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse method_policies table!"),
+                )
+            }
+            None => MethodPolicies::default(),
+        };
+
+        // Parse the optional `cache_rules` table
+        let cache_rules = match parsed_toml.get("cache_rules") {
+            Some(cache_rules_table) => {
+                parse_cache_rules(
+                    cache_rules_table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse cache_rules table!"),
+                )
+            }
+            None => CacheRules::default(),
+        };
+
         // Parse all the other tables as RPCs and put them in a Vec<Rpc>
         //
         // Sort RPCs by latency if enabled
 
         let mut rpc_list: Vec<Rpc> = Vec::new();
         for table_name in table_names {
-            if table_name != "blutgang" && table_name != "sled" && table_name != "admin" {
+            if !RESERVED_TABLES.contains(&table_name.as_str()) {
                 let rpc_table = parsed_toml.get(table_name).unwrap().as_table().unwrap();
 
                 let max_consecutive = rpc_table
@@ -299,9 +464,7 @@ impl Settings {
 
                 // If the delta time isnt 0, we need to get how many microsecond need to pass
                 // before we can send a new request
-                if delta != 0 {
-                    delta = 1_000_000 / delta;
-                }
+                delta = 1_000_000u64.checked_div(delta).unwrap_or(0);
 
                 let url = rpc_table
                     .get("url")
@@ -328,7 +491,39 @@ impl Settings {
                     }
                 };
 
-                let rpc = Rpc::new(url, ws_url, max_consecutive, delta.into(), ma_length);
+                let tier = match rpc_table.get("tier") {
+                    Some(tier) => {
+                        tier.as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse tier as int!")
+                            as u32
+                    }
+                    None => 0,
+                };
+
+                let burst = match rpc_table.get("burst") {
+                    Some(burst) => {
+                        burst
+                            .as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse burst as int!")
+                            as u32
+                    }
+                    None => 1,
+                };
+
+                let budget = rpc_table.get("budget").map(|budget| {
+                    Arc::new(parse_budget(
+                        table_name,
+                        budget
+                            .as_table()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse budget table!"),
+                    ))
+                });
+
+                let mut rpc = Rpc::new(url, ws_url, max_consecutive, delta.into(), ma_length);
+                rpc.tier = tier;
+                rpc.burst = burst;
+                rpc.budget = budget;
+                rpc.status.set_breaker(CircuitBreaker::new(breaker));
                 rpc_list.push(rpc);
             }
         }
@@ -395,6 +590,44 @@ impl Settings {
             }
         };
 
+        // Parse the optional `error_status` table
+        let mut error_status = ErrorStatus::default();
+        if let Some(error_status_table) = parsed_toml.get("error_status") {
+            let error_status_table = error_status_table
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse error_status table!");
+            for (name, status) in error_status_table {
+                let kind = ErrorKind::from_name(name).unwrap_or_else(|| {
+                    panic!(
+                        "\x1b[31mErr:\x1b[0m Unknown error in error_status: {}",
+                        name
+                    )
+                });
+                let status = status
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse error status as int!");
+                if !(100..=599).contains(&status) {
+                    panic!(
+                        "\x1b[31mErr:\x1b[0m Invalid HTTP status for {}: {}",
+                        name, status
+                    );
+                }
+                error_status.set(kind, status as u16);
+            }
+        }
+
+        // Parse the optional `upstream_errors` table
+        let error_rules = match parsed_toml.get("upstream_errors") {
+            Some(upstream_errors_table) => {
+                parse_error_rules(
+                    upstream_errors_table
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse upstream_errors table!"),
+                )
+            }
+            None => ErrorRules::default(),
+        };
+
         let mut poverty_list = Vec::new();
         if sort_on_startup {
             println!("Sorting RPCs by latency...");
@@ -407,6 +640,11 @@ impl Settings {
                 };
         }
 
+        let rate_limit = RateLimitQueue::new(rate_limit);
+        rate_limit
+            .serving_tier
+            .set_top(rpc_list.iter().chain(&poverty_list));
+
         Settings {
             rpc_list,
             poverty_list,
@@ -422,6 +660,17 @@ impl Settings {
             supress_rpc_check,
             sled_config,
             admin,
+            error_status,
+            error_rules: Arc::new(error_rules),
+            breaker,
+            selection,
+            latency_metric,
+            rate_limit: Arc::new(rate_limit),
+            method_policies: Arc::new(method_policies),
+            cache_rules: Arc::new(cache_rules),
+            ttl_cache: Arc::new(TtlCache::default()),
+            latest_cache: Arc::new(LatestCache::default()),
+            in_flight: Arc::new(InFlightCalls::default()),
         }
     }
 
@@ -442,9 +691,7 @@ impl Settings {
             .expect("Invalid max_per_second")
             .to_owned();
 
-        if delta != 0 {
-            delta = 1_000_000 / delta;
-        }
+        delta = 1_000_000u64.checked_div(delta).unwrap_or(0);
 
         // Turn the rpc_list into a csv vec
         let rpc_list: Vec<&str> = rpc_list.split(',').collect();
@@ -530,6 +777,19 @@ impl Settings {
             .get_one::<bool>("supress_rpc_check")
             .expect("Invalid supress_rpc_check");
 
+        let latency_metric = parse_latency_metric(
+            matches
+                .get_one::<String>("latency_metric")
+                .expect("Invalid latency_metric"),
+        );
+
+        let selection = parse_selection_strategy(
+            matches
+                .get_one::<String>("selection_strategy")
+                .expect("Invalid selection_strategy"),
+            latency_metric,
+        );
+
         // Admin thing setup
         let enabled = matches.get_occurrences::<String>("admin").is_some();
         let admin = if enabled {
@@ -572,6 +832,342 @@ impl Settings {
             health_check_ttl,
             sled_config,
             admin,
+            error_status: ErrorStatus::default(),
+            error_rules: Arc::new(ErrorRules::default()),
+            breaker: BreakerSettings::default(),
+            selection,
+            latency_metric,
+            rate_limit: Arc::new(RateLimitQueue::default()),
+            method_policies: Arc::new(MethodPolicies::default()),
+            cache_rules: Arc::new(CacheRules::default()),
+            ttl_cache: Arc::new(TtlCache::default()),
+            latest_cache: Arc::new(LatestCache::default()),
+            in_flight: Arc::new(InFlightCalls::default()),
         }
     }
 }
+
+fn parse_latency_metric(name: &str) -> LatencyMetric {
+    LatencyMetric::from_name(name).unwrap_or_else(|| {
+        panic!(
+            "\x1b[31mErr:\x1b[0m Unknown latency_metric: {}. Available: {}",
+            name,
+            LatencyMetric::ALL.map(|metric| metric.name()).join(", ")
+        )
+    })
+}
+
+fn parse_selection_strategy(name: &str, metric: LatencyMetric) -> Arc<dyn SelectionStrategy> {
+    strategy_from_name(name, metric).unwrap_or_else(|| {
+        panic!(
+            "\x1b[31mErr:\x1b[0m Unknown selection_strategy: {}. Available: {}",
+            name,
+            strategy_names().join(", ")
+        )
+    })
+}
+
+/// Parse the `circuit_breaker` table. Anything not specified is left at its default.
+fn parse_breaker_settings(table: &toml::value::Table) -> BreakerSettings {
+    let mut breaker = BreakerSettings::default();
+
+    if let Some(enabled) = table.get("enabled") {
+        breaker.enabled = enabled
+            .as_bool()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse circuit_breaker enabled as bool!");
+    }
+    if let Some(consecutive_failures) = table.get("consecutive_failures") {
+        breaker.consecutive_failures = consecutive_failures
+            .as_integer()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse consecutive_failures as int!")
+            as u32;
+    }
+    if let Some(error_rate) = table.get("error_rate") {
+        breaker.error_rate = error_rate
+            .as_float()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse error_rate as float!");
+    }
+    if let Some(min_requests) = table.get("min_requests") {
+        breaker.min_requests = min_requests
+            .as_integer()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse min_requests as int!")
+            as u32;
+    }
+    if let Some(window_ms) = table.get("window_ms") {
+        breaker.window = Duration::from_millis(
+            window_ms
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse window_ms as int!") as u64,
+        );
+    }
+    if let Some(cooldown_ms) = table.get("cooldown_ms") {
+        breaker.cooldown = Duration::from_millis(
+            cooldown_ms
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse cooldown_ms as int!")
+                as u64,
+        );
+    }
+
+    breaker
+}
+
+/// Parse the `budget` table of the RPC named `name`.
+fn parse_budget(name: &str, table: &toml::value::Table) -> ComputeBudget {
+    let limit = table
+        .get("limit")
+        .expect("\x1b[31mErr:\x1b[0m Missing limit from a budget!")
+        .as_integer()
+        .expect("\x1b[31mErr:\x1b[0m Could not parse budget limit as int!") as u64;
+
+    let window = match table.get("window") {
+        Some(window) => {
+            let window = window
+                .as_str()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse budget window as str!");
+            BudgetWindow::from_name(window).unwrap_or_else(|| {
+                panic!(
+                    "\x1b[31mErr:\x1b[0m Unknown budget window: {}. Available: daily, monthly",
+                    window
+                )
+            })
+        }
+        None => BudgetWindow::Monthly,
+    };
+
+    let default_cost = match table.get("default_cost") {
+        Some(default_cost) => {
+            default_cost
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse default_cost as int!")
+                as u64
+        }
+        None => 1,
+    };
+
+    let mut costs = HashMap::new();
+    if let Some(costs_table) = table.get("costs") {
+        for (method, cost) in costs_table
+            .as_table()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse budget costs as table!")
+        {
+            let cost = cost
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse method cost as int!");
+            costs.insert(method.clone(), cost as u64);
+        }
+    }
+
+    ComputeBudget::new(name.to_string(), limit, window, default_cost, costs)
+}
+
+/// Parse the `method_policies` table, where each key is a method or a prefix ending in `*`.
+fn parse_method_policies(table: &toml::value::Table) -> MethodPolicies {
+    let mut method_policies = MethodPolicies::default();
+
+    for (pattern, policy_table) in table {
+        let policy_table = policy_table.as_table().unwrap_or_else(|| {
+            panic!(
+                "\x1b[31mErr:\x1b[0m Could not parse method policy for {} as table!",
+                pattern
+            )
+        });
+
+        let mut policy = MethodPolicy::default();
+        if let Some(ttl) = policy_table.get("ttl") {
+            policy.ttl = Some(Duration::from_millis(
+                ttl.as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse method policy ttl as int!")
+                    as u64,
+            ));
+        }
+        if let Some(max_retries) = policy_table.get("max_retries") {
+            policy.max_retries = Some(
+                max_retries
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse method policy max_retries as int!")
+                    as u32,
+            );
+        }
+        if let Some(retry) = policy_table.get("retry") {
+            policy.retry = Some(
+                retry
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse method policy retry as bool!"),
+            );
+        }
+        if let Some(retry_backoff_ms) = policy_table.get("retry_backoff_ms") {
+            policy.retry_backoff = Some(Duration::from_millis(
+                retry_backoff_ms
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse retry_backoff_ms as int!")
+                    as u64,
+            ));
+        }
+        if let Some(jitter) = policy_table.get("jitter") {
+            policy.jitter = Some(
+                jitter
+                    .as_float()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse jitter as float!"),
+            );
+        }
+        // Either a delay in ms, or a latency metric of the RPC we're waiting on
+        if let Some(hedge_after) = policy_table.get("hedge_after") {
+            policy.hedge_after = Some(match hedge_after {
+                toml::Value::Integer(delay) => {
+                    HedgeAfter::Delay(Duration::from_millis(*delay as u64))
+                }
+                toml::Value::String(metric) => HedgeAfter::Latency(parse_latency_metric(metric)),
+                _ => {
+                    panic!("\x1b[31mErr:\x1b[0m Could not parse hedge_after as int or str!")
+                }
+            });
+        }
+
+        method_policies.insert(pattern, policy);
+    }
+
+    method_policies
+}
+
+/// Parse the `cache_rules` table, where each key is a method.
+///
+/// Rules are added on top of the default ones unless `use_defaults` is false.
+/// Anything a rule doesn't specify is taken from the default rule for the method.
+fn parse_cache_rules(table: &toml::value::Table) -> CacheRules {
+    let use_defaults = match table.get("use_defaults") {
+        Some(use_defaults) => {
+            use_defaults
+                .as_bool()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse use_defaults as bool!")
+        }
+        None => true,
+    };
+    let mut cache_rules = match use_defaults {
+        true => CacheRules::default(),
+        false => CacheRules::empty(),
+    };
+
+    for (method, rule_table) in table.iter().filter(|(key, _)| *key != "use_defaults") {
+        let rule_table = rule_table.as_table().unwrap_or_else(|| {
+            panic!(
+                "\x1b[31mErr:\x1b[0m Could not parse cache rule for {} as table!",
+                method
+            )
+        });
+
+        let mut rule = cache_rules
+            .rules
+            .get(method)
+            .copied()
+            .unwrap_or(CacheRule::default());
+        for (key, setting) in [
+            ("cache", &mut rule.cache),
+            ("block_resolved", &mut rule.block_resolved),
+            ("non_null", &mut rule.non_null),
+            ("no_error", &mut rule.no_error),
+        ] {
+            if let Some(value) = rule_table.get(key) {
+                *setting = value.as_bool().unwrap_or_else(|| {
+                    panic!("\x1b[31mErr:\x1b[0m Could not parse {} as bool!", key)
+                });
+            }
+        }
+        if let Some(ttl_ms) = rule_table.get("ttl_ms") {
+            rule.ttl = Some(Duration::from_millis(
+                ttl_ms
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse ttl_ms as int!")
+                    as u64,
+            ));
+        }
+
+        cache_rules.rules.insert(method.to_string(), rule);
+    }
+
+    cache_rules
+}
+
+/// Parse the `rate_limit` table. Anything not specified is left at its default.
+fn parse_rate_limit_settings(table: &toml::value::Table) -> RateLimitSettings {
+    let mut rate_limit = RateLimitSettings::default();
+
+    if let Some(queue_size) = table.get("queue_size") {
+        rate_limit.queue_size = queue_size
+            .as_integer()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse queue_size as int!")
+            as usize;
+    }
+
+    // All of the other settings are durations in ms
+    for (key, setting) in [
+        ("queue_timeout_ms", &mut rate_limit.queue_timeout),
+        ("default_backoff_ms", &mut rate_limit.default_backoff),
+        ("max_backoff_ms", &mut rate_limit.max_backoff),
+    ] {
+        if let Some(value) = table.get(key) {
+            *setting =
+                Duration::from_millis(value.as_integer().unwrap_or_else(|| {
+                    panic!("\x1b[31mErr:\x1b[0m Could not parse {} as int!", key)
+                }) as u64);
+        }
+    }
+
+    rate_limit
+}
+
+fn parse_error_action(action: &Value) -> ErrorAction {
+    let action = action
+        .as_str()
+        .expect("\x1b[31mErr:\x1b[0m Could not parse error action as str!");
+    ErrorAction::from_name(action).unwrap_or_else(|| {
+        panic!(
+            "\x1b[31mErr:\x1b[0m Invalid error action: {}. Can be pass/retry/degrade/throttle/split",
+            action
+        )
+    })
+}
+
+/// Parse the `upstream_errors` table. Anything not specified is left at its default.
+fn parse_error_rules(table: &toml::value::Table) -> ErrorRules {
+    let mut error_rules = ErrorRules::default();
+
+    if let Some(invalid_response) = table.get("invalid_response") {
+        error_rules.invalid_response = parse_error_action(invalid_response);
+    }
+
+    if let Some(rules) = table.get("rules") {
+        let rules = rules
+            .as_array()
+            .expect("\x1b[31mErr:\x1b[0m Could not parse upstream error rules as array!");
+
+        error_rules.rules = rules
+            .iter()
+            .map(|rule| {
+                let rule = rule
+                    .as_table()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse upstream error rule!");
+                let code = rule.get("code").map(|code| {
+                    code.as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse error code as int!")
+                });
+                let message = rule.get("message").map(|message| {
+                    message
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse error message as str!")
+                });
+                if code.is_none() && message.is_none() {
+                    panic!("\x1b[31mErr:\x1b[0m Upstream error rules need a code or message!");
+                }
+                let action = parse_error_action(
+                    rule.get("action")
+                        .expect("\x1b[31mErr:\x1b[0m Missing action from upstream error rule!"),
+                );
+
+                ErrorRule::new(code, message, action)
+            })
+            .collect();
+    }
+
+    error_rules
+}
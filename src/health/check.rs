@@ -237,7 +237,9 @@ fn make_poverty(
     for head in heads {
         if head.reported_head < highest_head || head.is_syncing {
             // Mark the RPC as erroring
-            rpc_list_guard[head.rpc_list_index].status.is_erroring = true;
+            rpc_list_guard[head.rpc_list_index]
+                .status
+                .set_erroring(true);
             log_wrn!(
                 "{} is falling behind! Removing froma active RPC pool.",
                 rpc_list_guard[head.rpc_list_index].name
@@ -249,7 +251,7 @@ fn make_poverty(
     }
 
     // Go over rpc_list_guard and remove all erroring rpcs
-    rpc_list_guard.retain(|rpc| !rpc.status.is_erroring);
+    rpc_list_guard.retain(|rpc| !rpc.status.is_erroring());
 
     Ok(highest_head)
 }
@@ -269,8 +271,8 @@ fn escape_poverty(
 
     for head_result in poverty_heads {
         if head_result.reported_head >= agreed_head && !head_result.is_syncing {
-            let mut rpc = poverty_list_guard[head_result.rpc_list_index].clone();
-            rpc.status.is_erroring = false;
+            let rpc = poverty_list_guard[head_result.rpc_list_index].clone();
+            rpc.status.set_erroring(false);
             log_info!(
                 "{} is following the head again! Added to active RPC pool.",
                 rpc.name
@@ -282,12 +284,12 @@ fn escape_poverty(
             // Remove the RPC from the poverty list
             poverty_list_guard[head_result.rpc_list_index]
                 .status
-                .is_erroring = false;
+                .set_erroring(false);
         }
     }
 
     // Only retain erroring RPCs
-    poverty_list_guard.retain(|rpc| rpc.status.is_erroring);
+    poverty_list_guard.retain(|rpc| rpc.status.is_erroring());
 
     //todo: i dont like this but its whatever
     let to_send;
@@ -427,12 +429,12 @@ mod tests {
     #[test]
     fn test_escape() {
         // Create a mock RPC list and poverty list
-        let mut rpc1 = Rpc::default();
-        rpc1.status.is_erroring = true;
+        let rpc1 = Rpc::default();
+        rpc1.status.set_erroring(true);
 
         let rpc2 = Rpc::default();
-        let mut rpc3 = Rpc::default();
-        rpc3.status.is_erroring = true;
+        let rpc3 = Rpc::default();
+        rpc3.status.set_erroring(true);
 
         let rpc_list = Arc::new(RwLock::new(vec![rpc2.clone()]));
         let poverty_list = Arc::new(RwLock::new(vec![rpc1.clone(), rpc3.clone()]));
@@ -468,12 +470,12 @@ mod tests {
     #[test]
     fn test_escape_sync() {
         // Create a mock RPC list and poverty list
-        let mut rpc1 = Rpc::default();
-        rpc1.status.is_erroring = true;
+        let rpc1 = Rpc::default();
+        rpc1.status.set_erroring(true);
 
         let rpc2 = Rpc::default();
-        let mut rpc3 = Rpc::default();
-        rpc3.status.is_erroring = true;
+        let rpc3 = Rpc::default();
+        rpc3.status.set_erroring(true);
 
         let rpc_list = Arc::new(RwLock::new(vec![rpc2.clone()]));
         let poverty_list = Arc::new(RwLock::new(vec![rpc1.clone(), rpc3.clone()]));
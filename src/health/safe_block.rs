@@ -1,5 +1,9 @@
 use crate::{
-    balancer::processing::CacheArgs,
+    balancer::{
+        latest_cache::Head,
+        processing::CacheArgs,
+        selection::method_policy::RequestPolicy,
+    },
     config::system::WS_HEALTH_CHECK_USER_ID,
     log_err,
     log_info,
@@ -166,6 +170,8 @@ async fn send_newheads_sub_message(
         outgoing_rx.resubscribe(),
         sub_data,
         cache_args,
+        // We can't do anything without newHeads, so wait for as long as it takes
+        RequestPolicy::new(u64::MAX.into(), 1),
     )
     .await
     {
@@ -223,6 +229,11 @@ pub async fn subscribe_to_new_heads(
                         .clone_into(&mut subscription_id);
                     log_info!("New chain head: {}", a);
                     let _ = blocknum_tx.send(a);
+                    // Responses for the old head are stale now
+                    cache_args.latest_cache.advance(Head::new(
+                        a,
+                        sub["params"]["result"]["hash"].as_str().unwrap_or_default(),
+                    ));
                     nn_rwlock.latest = a;
                 }
             }
@@ -240,6 +251,7 @@ pub async fn subscribe_to_new_heads(
                         e.into_inner()
                     });
                     nn_rwlock.latest = 0;
+                    cache_args.latest_cache.advance(Head::default());
                     match incoming_tx.send(WsconnMessage::Reconnect()) {
                         Ok(_) => {}
                         Err(_) => {
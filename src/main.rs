@@ -104,7 +104,10 @@ use crate::{
         processing::CacheArgs,
     },
     config::{
-        cache_setup::setup_data,
+        cache_setup::{
+            setup_budgets,
+            setup_data,
+        },
         cli_args::create_match,
         types::Settings,
     },
@@ -200,6 +203,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     //
     // Print any relevant warnings about a misconfigured DB. Check docs for more
     setup_data(cache.clone());
+    {
+        let config_guard = config.read().unwrap();
+        setup_budgets(&config_guard.rpc_list, &cache);
+        setup_budgets(&config_guard.poverty_list, &cache);
+    }
 
     // We create a TcpListener and bind it to 127.0.0.1:3000
     let listener = TcpListener::bind(addr).await?;
@@ -294,6 +302,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let ws_error_tx_ws = ws_error_tx.clone();
 
         let sub_dispatcher = Arc::clone(&sub_data);
+        let config_ws = Arc::clone(&config);
 
         tokio::task::spawn(async move {
             tokio::task::spawn(async move {
@@ -307,6 +316,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 incoming_rx,
                 outgoing_tx,
                 ws_error_tx_ws,
+                config_ws,
             )
             .await;
         });
@@ -334,11 +344,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let heads_rx = outgoing_rx.resubscribe();
             let heads_sub_data = sub_data.clone();
 
-            let cache_args = CacheArgs {
-                finalized_rx: finalized_rx.clone(),
-                named_numbers: named_blocknumbers.clone(),
-                cache: cache.clone(),
-                head_cache: head_cache.clone(),
+            let cache_args = {
+                let config_guard = config.read().unwrap();
+                CacheArgs {
+                    finalized_rx: finalized_rx.clone(),
+                    named_numbers: named_blocknumbers.clone(),
+                    cache: cache.clone(),
+                    head_cache: head_cache.clone(),
+                    in_flight: config_guard.in_flight.clone(),
+                    cache_rules: config_guard.cache_rules.clone(),
+                    ttl_cache: config_guard.ttl_cache.clone(),
+                    latest_cache: config_guard.latest_cache.clone(),
+                }
             };
 
             tokio::task::spawn(async move {
@@ -0,0 +1,271 @@
+//! Passive circuit breaker for RPCs, driven by the outcome of live requests.
+//!
+//! - `Closed`: requests flow normally. Trips to `Open` on too many
+//!   consecutive failures, or when the error rate inside of a window gets too high.
+//! - `Open`: the RPC is skipped during selection until `cooldown` passes.
+//! - `HalfOpen`: a single probe request is let through. If it succeeds we go
+//!   back to `Closed`, otherwise we go back to `Open`.
+
+use std::{
+    fmt,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BreakerState {
+    #[default]
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakerState::Closed => write!(f, "closed"),
+            BreakerState::Open => write!(f, "open"),
+            BreakerState::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakerSettings {
+    pub enabled: bool,
+    // Trip after this many failures in a row
+    pub consecutive_failures: u32,
+    // Trip if the share of failed requests in a window is at least this
+    pub error_rate: f64,
+    // Don't look at the error rate until a window has at least this many requests
+    pub min_requests: u32,
+    pub window: Duration,
+    // How long to wait before probing an open RPC
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            consecutive_failures: 5,
+            error_rate: 0.5,
+            min_requests: 20,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CircuitBreaker {
+    pub settings: BreakerSettings,
+    state: BreakerState,
+    consecutive_failures: u32,
+    window_start: Option<Instant>,
+    window_requests: u32,
+    window_failures: u32,
+    // When we opened, or when we let the probe through if half-open
+    changed_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: BreakerSettings) -> Self {
+        Self {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    fn cooldown_passed(&self, now: Instant) -> bool {
+        self.changed_at.map_or(true, |changed_at| {
+            now.duration_since(changed_at) >= self.settings.cooldown
+        })
+    }
+
+    /// Returns true if the RPC can be selected.
+    ///
+    /// Half-open RPCs are only available if the last probe got lost, eg. if it
+    /// was sent over WS and we never heard back from it.
+    pub fn can_pick(&self, now: Instant) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::Open | BreakerState::HalfOpen => {
+                !self.settings.enabled || self.cooldown_passed(now)
+            }
+        }
+    }
+
+    /// Called when the RPC got selected. Returns the new state if it changed.
+    pub fn on_pick(&mut self, now: Instant) -> Option<BreakerState> {
+        match self.state {
+            BreakerState::Closed => None,
+            BreakerState::Open => {
+                self.state = BreakerState::HalfOpen;
+                self.changed_at = Some(now);
+                Some(BreakerState::HalfOpen)
+            }
+            BreakerState::HalfOpen => {
+                self.changed_at = Some(now);
+                None
+            }
+        }
+    }
+
+    /// Roll the window over if it expired, and count a request in it.
+    fn count_request(&mut self, now: Instant, failed: bool) {
+        let expired = self.window_start.map_or(true, |start| {
+            now.duration_since(start) >= self.settings.window
+        });
+        if expired {
+            self.window_start = Some(now);
+            self.window_requests = 0;
+            self.window_failures = 0;
+        }
+
+        self.window_requests += 1;
+        if failed {
+            self.window_failures += 1;
+        }
+    }
+
+    pub fn on_success(&mut self, now: Instant) -> Option<BreakerState> {
+        self.consecutive_failures = 0;
+        self.count_request(now, false);
+
+        match self.state {
+            // Could be a request sent before we opened, so wait for the probe
+            BreakerState::Closed | BreakerState::Open => None,
+            BreakerState::HalfOpen => {
+                self.state = BreakerState::Closed;
+                self.window_start = None;
+                Some(BreakerState::Closed)
+            }
+        }
+    }
+
+    pub fn on_failure(&mut self, now: Instant) -> Option<BreakerState> {
+        self.consecutive_failures += 1;
+        self.count_request(now, true);
+
+        if !self.settings.enabled {
+            return None;
+        }
+
+        let should_open = match self.state {
+            BreakerState::Closed => {
+                self.consecutive_failures >= self.settings.consecutive_failures
+                    || (self.window_requests >= self.settings.min_requests
+                        && self.window_failures as f64
+                            >= self.settings.error_rate * self.window_requests as f64)
+            }
+            // The probe failed
+            BreakerState::HalfOpen => true,
+            BreakerState::Open => false,
+        };
+
+        if should_open {
+            self.state = BreakerState::Open;
+            self.changed_at = Some(now);
+            return Some(BreakerState::Open);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_settings() -> BreakerSettings {
+        BreakerSettings {
+            enabled: true,
+            consecutive_failures: 3,
+            error_rate: 0.5,
+            min_requests: 4,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn test_trip_on_consecutive_failures() {
+        let mut breaker = CircuitBreaker::new(test_settings());
+        let now = Instant::now();
+
+        assert_eq!(breaker.on_failure(now), None);
+        assert_eq!(breaker.on_failure(now), None);
+        assert_eq!(breaker.on_failure(now), Some(BreakerState::Open));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.can_pick(now));
+    }
+
+    #[test]
+    fn test_trip_on_error_rate() {
+        let mut breaker = CircuitBreaker::new(test_settings());
+        let now = Instant::now();
+
+        // 2 out of 4 failed, never more than 1 in a row
+        assert_eq!(breaker.on_success(now), None);
+        assert_eq!(breaker.on_failure(now), None);
+        assert_eq!(breaker.on_success(now), None);
+        assert_eq!(breaker.on_failure(now), Some(BreakerState::Open));
+
+        // Old windows don't count
+        let mut breaker = CircuitBreaker::new(test_settings());
+        breaker.on_failure(now);
+        breaker.on_failure(now);
+        breaker.on_success(now);
+        let later = now + Duration::from_secs(11);
+        breaker.on_success(later);
+        breaker.on_success(later);
+        breaker.on_success(later);
+        assert_eq!(breaker.on_failure(later), None);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let mut breaker = CircuitBreaker::new(test_settings());
+        let now = Instant::now();
+        for _ in 0..3 {
+            breaker.on_failure(now);
+        }
+
+        // Probe after the cooldown, and only let one through
+        let later = now + Duration::from_secs(5);
+        assert!(breaker.can_pick(later));
+        assert_eq!(breaker.on_pick(later), Some(BreakerState::HalfOpen));
+        assert!(!breaker.can_pick(later));
+
+        // Failed probe opens the breaker again
+        assert_eq!(breaker.on_failure(later), Some(BreakerState::Open));
+        assert!(!breaker.can_pick(later + Duration::from_secs(1)));
+
+        // Successful probe closes it
+        let even_later = later + Duration::from_secs(5);
+        assert_eq!(breaker.on_pick(even_later), Some(BreakerState::HalfOpen));
+        assert_eq!(breaker.on_success(even_later), Some(BreakerState::Closed));
+        assert!(breaker.can_pick(even_later));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut breaker = CircuitBreaker::new(BreakerSettings {
+            enabled: false,
+            ..test_settings()
+        });
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(breaker.on_failure(now), None);
+        }
+        assert!(breaker.can_pick(now));
+    }
+}
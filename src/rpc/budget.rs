@@ -0,0 +1,267 @@
+//! Compute unit budgets for RPCs billed per request.
+//!
+//! Every request costs its method's units from `costs`, or `default_cost` for
+//! methods not in there. Once `limit` units have been used in the current
+//! window, the RPC doesn't get picked until the next window starts.
+//!
+//! Usage is persisted in its own sled tree, so restarting blutgang or flushing
+//! the cache doesn't reset it.
+
+use crate::{
+    log_info,
+    log_wrn,
+};
+use chrono::{
+    DateTime,
+    Datelike,
+    Days,
+    Months,
+    NaiveTime,
+    Utc,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{
+            AtomicI64,
+            AtomicU64,
+            Ordering,
+        },
+        OnceLock,
+    },
+};
+
+/// Name of the sled tree budget usage is stored in.
+pub const BUDGET_TREE: &str = "compute_budgets";
+
+/// How often a budget resets. Windows start at midnight UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetWindow {
+    Daily,
+    Monthly,
+}
+
+impl BudgetWindow {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BudgetWindow::Daily => "daily",
+            BudgetWindow::Monthly => "monthly",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BudgetWindow> {
+        match name {
+            "daily" => Some(BudgetWindow::Daily),
+            "monthly" => Some(BudgetWindow::Monthly),
+            _ => None,
+        }
+    }
+
+    /// Start of the window `now` falls in.
+    pub fn start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let day = now.date_naive();
+        let day = match self {
+            BudgetWindow::Daily => day,
+            BudgetWindow::Monthly => day.with_day(1).unwrap(),
+        };
+        day.and_time(NaiveTime::MIN).and_utc()
+    }
+
+    /// Start of the window after the one `now` falls in.
+    pub fn end(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let start = self.start(now);
+        match self {
+            BudgetWindow::Daily => start + Days::new(1),
+            BudgetWindow::Monthly => start + Months::new(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ComputeBudget {
+    // Usage is persisted under this key, the name of the RPC's table in the config
+    pub key: String,
+    pub limit: u64,
+    pub window: BudgetWindow,
+    pub default_cost: u64,
+    pub costs: HashMap<String, u64>,
+
+    // Start of the window `used` is for, as a unix timestamp
+    window_start: AtomicI64,
+    used: AtomicU64,
+    tree: OnceLock<sled::Tree>,
+}
+
+impl ComputeBudget {
+    pub fn new(
+        key: String,
+        limit: u64,
+        window: BudgetWindow,
+        default_cost: u64,
+        costs: HashMap<String, u64>,
+    ) -> Self {
+        Self {
+            key,
+            limit,
+            window,
+            default_cost,
+            costs,
+            window_start: AtomicI64::new(0),
+            used: AtomicU64::new(0),
+            tree: OnceLock::new(),
+        }
+    }
+
+    /// Units a request calling `method` costs.
+    pub fn cost(&self, method: &str) -> u64 {
+        self.costs.get(method).copied().unwrap_or(self.default_cost)
+    }
+
+    /// Start over if a new window started since we last checked.
+    fn roll(&self, now: DateTime<Utc>) {
+        let start = self.window.start(now).timestamp();
+        let current = self.window_start.load(Ordering::Relaxed);
+        // Only log if the budget was spent, so we don't spam on every reset
+        if start > current
+            && self
+                .window_start
+                .compare_exchange(current, start, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+            && self.used.swap(0, Ordering::Relaxed) >= self.limit
+        {
+            log_info!("{} compute unit budget reset.", self.key);
+        }
+    }
+
+    pub fn used(&self, now: DateTime<Utc>) -> u64 {
+        self.roll(now);
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn remaining(&self, now: DateTime<Utc>) -> u64 {
+        self.limit.saturating_sub(self.used(now))
+    }
+
+    pub fn is_spent(&self, now: DateTime<Utc>) -> bool {
+        self.used(now) >= self.limit
+    }
+
+    /// Count a request calling `method` against the budget.
+    pub fn charge(&self, method: &str, now: DateTime<Utc>) {
+        self.roll(now);
+
+        let cost = self.cost(method);
+        let used = self.used.fetch_add(cost, Ordering::Relaxed);
+        if used < self.limit && used + cost >= self.limit {
+            log_wrn!(
+                "{} used up its compute unit budget, skipping it until {}.",
+                self.key,
+                self.window.end(now)
+            );
+        }
+
+        self.persist();
+    }
+
+    /// Stored as the window start followed by the units used, both big endian.
+    fn persist(&self) {
+        if let Some(tree) = self.tree.get() {
+            let mut value = [0u8; 16];
+            value[..8].copy_from_slice(&self.window_start.load(Ordering::Relaxed).to_be_bytes());
+            value[8..].copy_from_slice(&self.used.load(Ordering::Relaxed).to_be_bytes());
+            let _ = tree.insert(self.key.as_bytes(), &value);
+        }
+    }
+
+    /// Restore usage from `tree` if it's for the current window, and persist to it from now on.
+    pub fn load(&self, tree: sled::Tree, now: DateTime<Utc>) {
+        if let Ok(Some(value)) = tree.get(self.key.as_bytes()) {
+            if value.len() == 16 {
+                let start = i64::from_be_bytes(value[..8].try_into().unwrap());
+                let used = u64::from_be_bytes(value[8..].try_into().unwrap());
+                if start == self.window.start(now).timestamp() {
+                    self.window_start.store(start, Ordering::Relaxed);
+                    self.used.store(used, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let _ = self.tree.set(tree);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(date)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn test_budget() -> ComputeBudget {
+        ComputeBudget::new(
+            "alchemy".to_string(),
+            100,
+            BudgetWindow::Daily,
+            10,
+            HashMap::from([("eth_getLogs".to_string(), 75)]),
+        )
+    }
+
+    #[test]
+    fn test_budget_windows() {
+        let now = date("2024-02-29T13:37:00Z");
+        assert_eq!(BudgetWindow::Daily.start(now), date("2024-02-29T00:00:00Z"));
+        assert_eq!(BudgetWindow::Daily.end(now), date("2024-03-01T00:00:00Z"));
+        assert_eq!(
+            BudgetWindow::Monthly.start(now),
+            date("2024-02-01T00:00:00Z")
+        );
+        assert_eq!(BudgetWindow::Monthly.end(now), date("2024-03-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_budget_charge() {
+        let budget = test_budget();
+        let now = date("2024-02-29T13:37:00Z");
+
+        budget.charge("eth_getLogs", now);
+        budget.charge("eth_call", now);
+        assert_eq!(budget.remaining(now), 15);
+        assert!(!budget.is_spent(now));
+
+        budget.charge("eth_getLogs", now);
+        assert_eq!(budget.remaining(now), 0);
+        assert!(budget.is_spent(now));
+
+        // Resets once the next window starts
+        let tomorrow = date("2024-03-01T00:00:01Z");
+        assert!(!budget.is_spent(tomorrow));
+        assert_eq!(budget.used(tomorrow), 0);
+    }
+
+    #[test]
+    fn test_budget_persistence() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let now = date("2024-02-29T13:37:00Z");
+
+        let budget = test_budget();
+        budget.load(db.open_tree(BUDGET_TREE).unwrap(), now);
+        budget.charge("eth_getLogs", now);
+
+        // Survives a restart
+        let restarted = test_budget();
+        restarted.load(db.open_tree(BUDGET_TREE).unwrap(), now);
+        assert_eq!(restarted.used(now), 75);
+
+        // Usage from a previous window is ignored
+        let restarted = test_budget();
+        restarted.load(
+            db.open_tree(BUDGET_TREE).unwrap(),
+            date("2024-03-02T00:00:00Z"),
+        );
+        assert_eq!(restarted.used(date("2024-03-02T00:00:00Z")), 0);
+    }
+}
@@ -1,5 +1,9 @@
 //! RPC type errors
-use std::error::Error;
+use std::{
+    error::Error,
+    io,
+    time::Duration,
+};
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -8,6 +12,99 @@ pub enum RpcError {
     //InvalidHexFormat,
     OutOfBounds,
     InvalidResponse(String),
+    Transport(FailureClass),
+    // The RPC is rate limiting us, and wants us to wait this long if it said so
+    Throttled(Option<Duration>),
+}
+
+/// Why a request to an upstream RPC failed.
+///
+/// All of these are treated as retryable, so the request gets sent to another RPC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureClass {
+    Timeout,
+    ConnectionRefused,
+    Dns,
+    Tls,
+    ConnectionReset,
+    HttpStatus(u16),
+    InvalidBody,
+    // The RPC answered with an error it shouldn't have, see `ErrorRules`
+    ErrorResponse,
+    Other,
+}
+
+impl FailureClass {
+    /// Classify a `reqwest` error by its kind and the errors that caused it.
+    pub fn from_reqwest(err: &reqwest::Error) -> Self {
+        if err.is_timeout() {
+            return FailureClass::Timeout;
+        }
+        if let Some(status) = err.status() {
+            return FailureClass::HttpStatus(status.as_u16());
+        }
+        if err.is_body() || err.is_decode() {
+            return FailureClass::InvalidBody;
+        }
+
+        // The interesting bits are hidden in hyper and io errors further down the chain
+        let mut source = err.source();
+        while let Some(cause) = source {
+            if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+                match io_err.kind() {
+                    io::ErrorKind::ConnectionRefused => return FailureClass::ConnectionRefused,
+                    io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof => return FailureClass::ConnectionReset,
+                    io::ErrorKind::TimedOut => return FailureClass::Timeout,
+                    _ => {}
+                }
+            }
+
+            let msg = cause.to_string().to_lowercase();
+            if msg.contains("dns error") || msg.contains("failed to lookup address") {
+                return FailureClass::Dns;
+            }
+            if msg.contains("tls") || msg.contains("ssl") || msg.contains("certificate") {
+                return FailureClass::Tls;
+            }
+            if msg.contains("connection closed") || msg.contains("connection reset") {
+                return FailureClass::ConnectionReset;
+            }
+
+            source = cause.source();
+        }
+
+        if err.is_connect() {
+            return FailureClass::ConnectionRefused;
+        }
+
+        FailureClass::Other
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FailureClass::Timeout => "timeout",
+            FailureClass::ConnectionRefused => "connection_refused",
+            FailureClass::Dns => "dns",
+            FailureClass::Tls => "tls",
+            FailureClass::ConnectionReset => "connection_reset",
+            FailureClass::HttpStatus(_) => "http_status",
+            FailureClass::InvalidBody => "invalid_body",
+            FailureClass::ErrorResponse => "error_response",
+            FailureClass::Other => "transport",
+        }
+    }
+}
+
+impl std::fmt::Display for FailureClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FailureClass::HttpStatus(status) => write!(f, "{} {}", self.name(), status),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
 }
 
 impl std::fmt::Display for RpcError {
@@ -21,6 +118,8 @@ impl std::fmt::Display for RpcError {
                 )
             }
             RpcError::InvalidResponse(reason) => write!(f, "Invalid RPC response: {}", reason),
+            RpcError::Transport(class) => write!(f, "RPC request failed: {}", class),
+            RpcError::Throttled(_) => write!(f, "RPC is rate limiting us"),
         }
     }
 }
@@ -31,4 +130,35 @@ impl From<simd_json::Error> for RpcError {
     }
 }
 
+impl From<reqwest::Error> for RpcError {
+    fn from(err: reqwest::Error) -> Self {
+        RpcError::Transport(FailureClass::from_reqwest(&err))
+    }
+}
+
 impl Error for RpcError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_classify_connection_refused() {
+        // Nothing should be listening on port 1
+        let err = reqwest::Client::new()
+            .post("http://127.0.0.1:1")
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(
+            FailureClass::from_reqwest(&err),
+            FailureClass::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn test_failure_class_display() {
+        assert_eq!(FailureClass::HttpStatus(503).to_string(), "http_status 503");
+        assert_eq!(FailureClass::Dns.to_string(), "dns");
+    }
+}
@@ -0,0 +1,205 @@
+//! Latency stats of an RPC over its last `ma_length` requests.
+//!
+//! Samples are kept in a fixed size ring buffer, so adding one and updating the
+//! mean is O(1), without ever allocating. Percentiles are O(n), so they're only
+//! worked out when someone asks for them.
+
+use std::fmt;
+
+/// Which latency stat to use when comparing RPCs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LatencyMetric {
+    #[default]
+    Mean,
+    P50,
+    P90,
+    P99,
+}
+
+impl LatencyMetric {
+    pub const ALL: [LatencyMetric; 4] = [
+        LatencyMetric::Mean,
+        LatencyMetric::P50,
+        LatencyMetric::P90,
+        LatencyMetric::P99,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LatencyMetric::Mean => "mean",
+            LatencyMetric::P50 => "p50",
+            LatencyMetric::P90 => "p90",
+            LatencyMetric::P99 => "p99",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<LatencyMetric> {
+        LatencyMetric::ALL
+            .into_iter()
+            .find(|metric| metric.name() == name)
+    }
+}
+
+impl fmt::Display for LatencyMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Snapshot of the latency stats, in nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LatencyStats {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+impl LatencyStats {
+    pub fn get(&self, metric: LatencyMetric) -> f64 {
+        match metric {
+            LatencyMetric::Mean => self.mean,
+            LatencyMetric::P50 => self.p50,
+            LatencyMetric::P90 => self.p90,
+            LatencyMetric::P99 => self.p99,
+        }
+    }
+}
+
+/// Ring buffer holding the last `capacity` latency samples.
+#[derive(Debug)]
+pub struct LatencyWindow {
+    samples: Box<[f64]>,
+    // Scratch space for finding percentiles, so we don't have to allocate
+    scratch: Box<[f64]>,
+    // Index the next sample gets written to
+    next: usize,
+    len: usize,
+    sum: f64,
+}
+
+impl Default for LatencyWindow {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl LatencyWindow {
+    /// Keeps at least 1 sample, even if `capacity` is 0.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            samples: vec![0.0; capacity].into_boxed_slice(),
+            scratch: vec![0.0; capacity].into_boxed_slice(),
+            next: 0,
+            len: 0,
+            sum: 0.0,
+        }
+    }
+
+    /// Add a sample, replacing the oldest one if we're full. Returns the new mean.
+    pub fn push(&mut self, sample: f64) -> f64 {
+        if self.len == self.samples.len() {
+            self.sum -= self.samples[self.next];
+        } else {
+            self.len += 1;
+        }
+
+        self.samples[self.next] = sample;
+        self.sum += sample;
+        self.next = (self.next + 1) % self.samples.len();
+
+        // Recompute the sum once per lap so float errors don't pile up
+        if self.next == 0 {
+            self.sum = self.samples[..self.len].iter().sum();
+        }
+
+        self.mean()
+    }
+
+    pub fn mean(&self) -> f64 {
+        match self.len {
+            0 => 0.0,
+            len => self.sum / len as f64,
+        }
+    }
+
+    pub fn stats(&mut self) -> LatencyStats {
+        if self.len == 0 {
+            return LatencyStats::default();
+        }
+
+        LatencyStats {
+            mean: self.mean(),
+            p50: self.percentile(0.5),
+            p90: self.percentile(0.9),
+            p99: self.percentile(0.99),
+        }
+    }
+
+    /// Nearest-rank percentile of the samples.
+    fn percentile(&mut self, percentile: f64) -> f64 {
+        let rank = ((percentile * self.len as f64).ceil() as usize).clamp(1, self.len);
+
+        let scratch = &mut self.scratch[..self.len];
+        scratch.copy_from_slice(&self.samples[..self.len]);
+        *scratch.select_nth_unstable_by(rank - 1, f64::total_cmp).1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_mean() {
+        let mut window = LatencyWindow::new(3);
+        assert_eq!(window.stats(), LatencyStats::default());
+
+        assert_eq!(window.push(10.0), 10.0);
+        assert_eq!(window.push(20.0), 15.0);
+        assert_eq!(window.push(30.0), 20.0);
+
+        // Oldest sample gets dropped
+        assert_eq!(window.push(60.0), 110.0 / 3.0);
+        assert_eq!(window.push(60.0), 50.0);
+        assert_eq!(window.stats().mean, 50.0);
+
+        // 0 still keeps the last sample
+        let mut window = LatencyWindow::new(0);
+        window.push(1.0);
+        assert_eq!(window.push(5.0), 5.0);
+    }
+
+    #[test]
+    fn test_window_percentiles() {
+        let mut window = LatencyWindow::new(100);
+        for sample in (1..=100).rev() {
+            window.push(sample as f64);
+        }
+        let stats = window.stats();
+
+        assert_eq!(stats.p50, 50.0);
+        assert_eq!(stats.p90, 90.0);
+        assert_eq!(stats.p99, 99.0);
+        assert_eq!(stats.get(LatencyMetric::Mean), 50.5);
+
+        // A single slow request shows up in the tail, not in the median
+        let mut window = LatencyWindow::new(10);
+        for _ in 0..9 {
+            window.push(1.0);
+        }
+        window.push(1000.0);
+        let stats = window.stats();
+        assert_eq!(stats.p50, 1.0);
+        assert_eq!(stats.p99, 1000.0);
+    }
+
+    #[test]
+    fn test_metric_names() {
+        for metric in LatencyMetric::ALL {
+            assert_eq!(LatencyMetric::from_name(metric.name()), Some(metric));
+        }
+        assert_eq!(LatencyMetric::from_name("p42"), None);
+    }
+}
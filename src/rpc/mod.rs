@@ -1,2 +1,6 @@
+pub mod breaker;
+pub mod budget;
 pub mod error;
+pub mod latency;
+pub mod rate_limit;
 pub mod types;
@@ -0,0 +1,236 @@
+//! Token bucket enforcing `max_per_second` on an RPC.
+//!
+//! Implemented as a GCRA, so the whole bucket fits in a single atomic.
+//! A bucket with a `burst` of n lets n requests through back to back,
+//! and refills one token every `interval`.
+//!
+//! When an RPC tells us to back off, eg. with a 429, the bucket gets paused
+//! and hands out no tokens until the pause is over.
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+use std::{
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    start: Instant,
+    // Theoretical arrival time of the next request, in nanoseconds since `start`
+    tat: AtomicU64,
+    // No tokens until this many nanoseconds since `start`
+    paused_until: AtomicU64,
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            tat: AtomicU64::new(0),
+            paused_until: AtomicU64::new(0),
+        }
+    }
+}
+
+impl TokenBucket {
+    /// Returns the new arrival time if we can take a token, or how long to wait for one.
+    fn check(&self, tat: u64, now: u64, interval: u64, burst: u32) -> Result<u64, Duration> {
+        let tolerance = interval.saturating_mul(burst.max(1) as u64 - 1);
+        let tat = tat.max(now);
+
+        if tat - now > tolerance {
+            return Err(Duration::from_nanos(tat - now - tolerance));
+        }
+
+        Ok(tat + interval)
+    }
+
+    fn nanos_since_start(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.start).as_nanos() as u64
+    }
+
+    /// Returns how long the pause has left, if any.
+    fn paused_for(&self, now: u64) -> Option<Duration> {
+        let paused_until = self.paused_until.load(Ordering::Relaxed);
+        (paused_until > now).then(|| Duration::from_nanos(paused_until - now))
+    }
+
+    /// Don't hand out tokens for `duration`. Only ever extends an existing pause.
+    pub fn pause(&self, now: Instant, duration: Duration) {
+        let until = self
+            .nanos_since_start(now)
+            .saturating_add(duration.as_nanos() as u64);
+        self.paused_until.fetch_max(until, Ordering::Relaxed);
+    }
+
+    /// Take a token, or return how long until one is available.
+    ///
+    /// An `interval` of 0 means there is no limit.
+    pub fn try_acquire(
+        &self,
+        now: Instant,
+        interval: Duration,
+        burst: u32,
+    ) -> Result<(), Duration> {
+        let now = self.nanos_since_start(now);
+        if let Some(wait) = self.paused_for(now) {
+            return Err(wait);
+        }
+
+        let interval = interval.as_nanos() as u64;
+        if interval == 0 {
+            return Ok(());
+        }
+
+        let mut tat = self.tat.load(Ordering::Relaxed);
+        loop {
+            let next = self.check(tat, now, interval, burst)?;
+            match self
+                .tat
+                .compare_exchange_weak(tat, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return Ok(()),
+                Err(current) => tat = current,
+            }
+        }
+    }
+
+    /// How long until a token is available, without taking it. Zero if we have one now.
+    pub fn wait_time(&self, now: Instant, interval: Duration, burst: u32) -> Duration {
+        let now = self.nanos_since_start(now);
+        if let Some(wait) = self.paused_for(now) {
+            return wait;
+        }
+
+        let interval = interval.as_nanos() as u64;
+        if interval == 0 {
+            return Duration::ZERO;
+        }
+
+        match self.check(self.tat.load(Ordering::Relaxed), now, interval, burst) {
+            Ok(_) => Duration::ZERO,
+            Err(wait) => wait,
+        }
+    }
+}
+
+/// Parse a `Retry-After` header, which is either in seconds or an HTTP date.
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // Dates in the past mean we can retry right away
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::default();
+        let interval = Duration::from_millis(100);
+        let now = bucket.start;
+
+        // Burst goes through back to back
+        for _ in 0..3 {
+            assert_eq!(bucket.try_acquire(now, interval, 3), Ok(()));
+        }
+        assert_eq!(
+            bucket.try_acquire(now, interval, 3),
+            Err(Duration::from_millis(100))
+        );
+        assert_eq!(bucket.wait_time(now, interval, 3), interval);
+
+        // One token per interval after that
+        let later = now + Duration::from_millis(150);
+        assert_eq!(bucket.wait_time(later, interval, 3), Duration::ZERO);
+        assert_eq!(bucket.try_acquire(later, interval, 3), Ok(()));
+        assert_eq!(
+            bucket.try_acquire(later, interval, 3),
+            Err(Duration::from_millis(50))
+        );
+
+        // Refills up to the burst
+        let much_later = now + Duration::from_secs(10);
+        for _ in 0..3 {
+            assert_eq!(bucket.try_acquire(much_later, interval, 3), Ok(()));
+        }
+        assert!(bucket.try_acquire(much_later, interval, 3).is_err());
+    }
+
+    #[test]
+    fn test_token_bucket_unlimited() {
+        let bucket = TokenBucket::default();
+        for _ in 0..1000 {
+            assert_eq!(
+                bucket.try_acquire(Instant::now(), Duration::ZERO, 1),
+                Ok(())
+            );
+        }
+        assert_eq!(
+            bucket.wait_time(Instant::now(), Duration::ZERO, 1),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_token_bucket_pause() {
+        let bucket = TokenBucket::default();
+        let now = bucket.start;
+
+        bucket.pause(now, Duration::from_secs(30));
+        assert_eq!(
+            bucket.try_acquire(now, Duration::ZERO, 1),
+            Err(Duration::from_secs(30))
+        );
+
+        // Shorter pauses don't cut a longer one short
+        bucket.pause(now, Duration::from_secs(1));
+        assert_eq!(
+            bucket.wait_time(now + Duration::from_secs(10), Duration::ZERO, 1),
+            Duration::from_secs(20)
+        );
+
+        let later = now + Duration::from_secs(30);
+        assert_eq!(bucket.try_acquire(later, Duration::ZERO, 1), Ok(()));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
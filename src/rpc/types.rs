@@ -1,5 +1,53 @@
-use crate::rpc::error::RpcError;
-use reqwest::Client;
+use crate::{
+    log_info,
+    log_wrn,
+    rpc::{
+        breaker::{
+            BreakerState,
+            CircuitBreaker,
+        },
+        budget::ComputeBudget,
+        error::{
+            FailureClass,
+            RpcError,
+        },
+        latency::{
+            LatencyMetric,
+            LatencyStats,
+            LatencyWindow,
+        },
+        rate_limit::{
+            parse_retry_after,
+            TokenBucket,
+        },
+    },
+};
+use chrono::Utc;
+use reqwest::{
+    header::RETRY_AFTER,
+    Client,
+    StatusCode,
+};
+use std::{
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU32,
+            AtomicU64,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+        MutexGuard,
+        TryLockError,
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
+};
 use url::Url;
 
 use serde_json::{
@@ -7,35 +55,253 @@ use serde_json::{
     Value,
 };
 
-// All as floats so we have an easier time getting averages, stats and terminology copied from flood.
-#[derive(Debug, Clone, Default)]
+/// Stats of an RPC, shared between every clone of it.
+///
+/// Everything in here is either atomic or behind a lock that only covers this RPC,
+/// so updating it only ever needs a read lock on the `rpc_list`.
+#[derive(Debug, Default)]
 pub struct Status {
     // Set this to true in case the RPC becomes unavailable
     // Also set the last time it was called, so we can check again later
-    pub is_erroring: bool,
-    pub last_error: u64,
-
-    // The latency is a moving average of the last n calls
-    pub latency: f64,
-    pub latency_data: Vec<f64>,
-    ma_length: f64,
+    is_erroring: AtomicBool,
+    last_error: AtomicU64,
+
+    // Failed requests, and why the last one failed
+    failures: AtomicU64,
+    last_failure: Mutex<Option<FailureClass>>,
+    breaker: Mutex<CircuitBreaker>,
+    // Mirrors `breaker`, so we don't have to lock it while it's closed
+    breaker_closed: AtomicBool,
+
+    // Requests sent to the RPC that we're still waiting on
+    in_flight: AtomicUsize,
+    // Requests we sent here because no RPC of a higher tier was available
+    spilled: AtomicU64,
+    // Times the RPC told us to back off
+    throttled: AtomicU64,
+    // Duplicate requests sent here because another RPC was slow, and how many of them answered first
+    hedged: AtomicU64,
+    hedge_wins: AtomicU64,
+
+    // Enforces max_per_second
+    bucket: TokenBucket,
+
+    // For max_consecutive and max_per_second
+    consecutive: AtomicU32,
+    last_used: AtomicU64, // last time we sent a querry to this node
+
+    // Latency stats of the last n calls, indexed by `LatencyMetric`.
+    // Stored as the bits of an f64.
+    latency: [AtomicU64; LatencyMetric::ALL.len()],
+    latency_data: Mutex<LatencyWindow>,
+    // Set when there are samples the stored percentiles don't account for yet
+    stale_percentiles: AtomicBool,
     // ???
     // pub throughput: f64,
 }
 
+impl Status {
+    fn new(ma_length: f64) -> Self {
+        Self {
+            breaker_closed: AtomicBool::new(true),
+            latency_data: Mutex::new(LatencyWindow::new(ma_length as usize)),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_erroring(&self) -> bool {
+        self.is_erroring.load(Ordering::Relaxed)
+    }
+
+    pub fn set_erroring(&self, is_erroring: bool) {
+        self.is_erroring.store(is_erroring, Ordering::Relaxed);
+    }
+
+    /// Unix timestamp of the last failure in milliseconds.
+    pub fn last_error(&self) -> u64 {
+        self.last_error.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn last_failure(&self) -> Option<FailureClass> {
+        *self.last_failure.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        if self.breaker_closed.load(Ordering::Relaxed) {
+            return BreakerState::Closed;
+        }
+        self.lock_breaker().state()
+    }
+
+    pub fn set_breaker(&self, breaker: CircuitBreaker) {
+        let mut guard = self.lock_breaker();
+        *guard = breaker;
+        self.sync_breaker(&guard);
+    }
+
+    /// Returns true if the circuit breaker lets us select the RPC.
+    pub fn can_pick(&self, now: Instant) -> bool {
+        self.breaker_closed.load(Ordering::Relaxed) || self.lock_breaker().can_pick(now)
+    }
+
+    fn lock_breaker(&self) -> MutexGuard<'_, CircuitBreaker> {
+        self.breaker.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sync_breaker(&self, breaker: &CircuitBreaker) {
+        self.breaker_closed
+            .store(breaker.state() == BreakerState::Closed, Ordering::Relaxed);
+    }
+
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    pub fn spilled(&self) -> u64 {
+        self.spilled.load(Ordering::Relaxed)
+    }
+
+    pub fn hedged(&self) -> u64 {
+        self.hedged.load(Ordering::Relaxed)
+    }
+
+    pub fn hedge_wins(&self) -> u64 {
+        self.hedge_wins.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub fn consecutive(&self) -> u32 {
+        self.consecutive.load(Ordering::Relaxed)
+    }
+
+    pub fn set_consecutive(&self, consecutive: u32) {
+        self.consecutive.store(consecutive, Ordering::Relaxed);
+    }
+
+    /// Count another request in a row, unless the RPC already got `max_consecutive` of them.
+    pub fn try_consecutive(&self, max_consecutive: u32) -> bool {
+        self.consecutive
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |consecutive| {
+                (consecutive < max_consecutive).then_some(consecutive + 1)
+            })
+            .is_ok()
+    }
+
+    /// Like `try_consecutive`, but also only if the RPC wasn't used within
+    /// the last `min_time_delta` microseconds, in which case `time` becomes its last use.
+    pub fn try_claim(&self, max_consecutive: u32, min_time_delta: u128, time: u128) -> bool {
+        if !self.try_consecutive(max_consecutive) {
+            return false;
+        }
+
+        let claimed = self
+            .last_used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last_used| {
+                (time.saturating_sub(last_used as u128) > min_time_delta).then_some(time as u64)
+            })
+            .is_ok();
+        if !claimed {
+            // Used too recently, so give the request we counted back
+            let _ = self.consecutive.fetch_update(
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+                |consecutive| Some(consecutive.saturating_sub(1)),
+            );
+        }
+        claimed
+    }
+
+    /// Last time we sent a request to the RPC, in microseconds.
+    #[cfg(test)]
+    pub fn last_used(&self) -> u128 {
+        self.last_used.load(Ordering::Relaxed) as u128
+    }
+
+    pub fn set_last_used(&self, last_used: u128) {
+        self.last_used.store(last_used as u64, Ordering::Relaxed);
+    }
+
+    /// Moving average of the latency in nanoseconds.
+    pub fn latency(&self) -> f64 {
+        self.latency_of(LatencyMetric::Mean)
+    }
+
+    /// Latency in nanoseconds, as measured by `metric`.
+    pub fn latency_of(&self, metric: LatencyMetric) -> f64 {
+        if metric != LatencyMetric::Mean {
+            self.refresh_percentiles();
+        }
+        f64::from_bits(self.latency[metric as usize].load(Ordering::Relaxed))
+    }
+
+    // Work out the percentiles again if there are new samples.
+    //
+    // If someone else holds the window they're either adding a sample or doing
+    // this already, so we make do with what's stored instead of waiting on them.
+    fn refresh_percentiles(&self) {
+        if !self.stale_percentiles.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let mut latency_data = match self.latency_data.try_lock() {
+            Ok(latency_data) => latency_data,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                self.stale_percentiles.store(true, Ordering::Relaxed);
+                return;
+            }
+        };
+        self.set_latency_stats(latency_data.stats());
+    }
+
+    pub fn latency_stats(&self) -> LatencyStats {
+        LatencyStats {
+            mean: self.latency_of(LatencyMetric::Mean),
+            p50: self.latency_of(LatencyMetric::P50),
+            p90: self.latency_of(LatencyMetric::P90),
+            p99: self.latency_of(LatencyMetric::P99),
+        }
+    }
+
+    fn set_latency_stats(&self, stats: LatencyStats) {
+        for metric in LatencyMetric::ALL {
+            self.latency[metric as usize].store(stats.get(metric).to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Set every latency stat to `latency`.
+    #[cfg(test)]
+    pub fn set_latency(&self, latency: f64) {
+        self.set_latency_stats(LatencyStats {
+            mean: latency,
+            p50: latency,
+            p90: latency,
+            p99: latency,
+        });
+    }
+}
+
+/// Handle to an RPC. Cheap to clone, and clones share their `Status`.
 #[derive(Debug, Clone)]
 pub struct Rpc {
-    pub name: String,           // sanitized name for appearing in logs
-    url: String,                // url of the rpc we're forwarding requests to.
-    client: Client,             // Reqwest client
-    pub ws_url: Option<String>, // url of the websocket we're forwarding requests to.
-    pub status: Status,         // stores stats related to the rpc.
+    pub name: Arc<str>,           // sanitized name for appearing in logs
+    url: Arc<str>,                // url of the rpc we're forwarding requests to.
+    client: Client,               // Reqwest client
+    pub ws_url: Option<Arc<str>>, // url of the websocket we're forwarding requests to.
+    pub status: Arc<Status>,      // stores stats related to the rpc.
     // For max_consecutive
     pub max_consecutive: u32, // max times we can call an rpc in a row
-    pub consecutive: u32,
     // For max_per_second
-    pub last_used: u128,      // last time we sent a querry to this node
     pub min_time_delta: u128, // microseconds
+    pub burst: u32,           // requests we can send back to back
+    // Lower tiers get picked first, 0 being the primary tier
+    pub tier: u32,
+    // Compute units we can spend on the RPC, if it's billed per request
+    pub budget: Option<Arc<ComputeBudget>>,
 }
 
 /// Sanitizes URLs so secrets don't get outputed.
@@ -62,15 +328,16 @@ fn sanitize_url(url: &str) -> Result<String, url::ParseError> {
 impl Default for Rpc {
     fn default() -> Self {
         Self {
-            name: "".to_string(),
-            url: "".to_string(),
+            name: "".into(),
+            url: "".into(),
             ws_url: None,
             client: Client::new(),
-            status: Status::default(),
+            status: Arc::new(Status::new(0.0)),
             max_consecutive: 0,
-            consecutive: 0,
-            last_used: 0,
             min_time_delta: 0,
+            burst: 1,
+            tier: 0,
+            budget: None,
         }
     }
 }
@@ -85,50 +352,65 @@ impl Rpc {
         ma_length: f64,
     ) -> Self {
         Self {
-            name: sanitize_url(&url).unwrap_or(url.clone()),
-            url,
+            name: sanitize_url(&url).unwrap_or(url.clone()).into(),
+            url: url.into(),
             client: Client::new(),
-            ws_url,
-            status: Status {
-                ma_length,
-                ..Default::default()
-            },
+            ws_url: ws_url.map(Into::into),
+            status: Arc::new(Status::new(ma_length)),
             max_consecutive,
-            consecutive: 0,
-            last_used: 0,
             min_time_delta,
+            burst: 1,
+            tier: 0,
+            budget: None,
         }
     }
 
     /// Explicitly get the url of the Rpc, potentially dangerous as it can expose basic auth
     #[cfg(test)]
     pub fn get_url(&self) -> String {
-        self.url.clone()
+        self.url.to_string()
     }
 
     /// Generic fn to send rpc
+    ///
+    /// Every request sent is charged against the RPC's budget, including health checks
+    /// and requests that get dropped before they're answered, like a losing hedge.
     pub async fn send_request(&self, tx: Value) -> Result<String, crate::rpc::types::RpcError> {
         #[cfg(feature = "debug-verbose")]
         println!("Sending request: {}", tx.clone());
 
-        let response = match self.client.post(&self.url).json(&tx).send().await {
-            Ok(response) => response,
-            Err(err) => {
-                return Err(crate::rpc::types::RpcError::InvalidResponse(
-                    err.to_string(),
-                ))
-            }
-        };
+        self.charge(tx["method"].as_str().unwrap_or_default());
+        let response = self.client.post(&*self.url).json(&tx).send().await?;
+
+        // The RPC wants us to back off, either because we're over its rate limit
+        // or because it's overloaded
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, Utc::now()));
+        if response.status() == StatusCode::TOO_MANY_REQUESTS
+            || (!response.status().is_success() && retry_after.is_some())
+        {
+            return Err(RpcError::Throttled(retry_after));
+        }
+
+        // Anything other than a 2xx means the RPC is not able to serve us
+        if !response.status().is_success() {
+            return Err(RpcError::Transport(FailureClass::HttpStatus(
+                response.status().as_u16(),
+            )));
+        }
 
         #[cfg(feature = "debug-verbose")]
         {
-            let a = response.text().await.unwrap();
+            let a = response.text().await?;
             println!("response: {}", a);
             return Ok(a);
         }
 
         #[cfg(not(feature = "debug-verbose"))]
-        Ok(response.text().await.unwrap())
+        Ok(response.text().await?)
     }
 
     /// Request blocknumber and return its value
@@ -191,18 +473,170 @@ impl Rpc {
         Ok(return_number)
     }
 
+    /// Number of requests we're currently waiting on.
+    pub fn in_flight(&self) -> usize {
+        self.status.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Count a request as in flight until the returned guard gets dropped.
+    pub fn track_in_flight(&self) -> InFlightGuard {
+        self.status.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(Arc::clone(&self.status))
+    }
+
+    /// Record a failed request against this RPC.
+    pub fn record_failure(&self, class: FailureClass) {
+        self.status.failures.fetch_add(1, Ordering::Relaxed);
+        *self
+            .status
+            .last_failure
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(class);
+        self.status.last_error.store(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Failed to get current time")
+                .as_millis() as u64,
+            Ordering::Relaxed,
+        );
+
+        let transition = {
+            let mut breaker = self.status.lock_breaker();
+            let transition = breaker.on_failure(Instant::now());
+            self.status.sync_breaker(&breaker);
+            transition
+        };
+        self.log_breaker_transition(transition);
+    }
+
+    /// Record a request this RPC answered.
+    pub fn record_success(&self) {
+        let transition = {
+            let mut breaker = self.status.lock_breaker();
+            let transition = breaker.on_success(Instant::now());
+            self.status.sync_breaker(&breaker);
+            transition
+        };
+        self.log_breaker_transition(transition);
+    }
+
+    /// Returns true if neither the circuit breaker nor the compute unit budget
+    /// keep us from selecting the RPC.
+    pub fn can_pick(&self, now: Instant) -> bool {
+        self.status.can_pick(now)
+            && !self
+                .budget
+                .as_ref()
+                .is_some_and(|budget| budget.is_spent(Utc::now()))
+    }
+
+    /// Count a request calling `method` against the RPC's budget, if it has one.
+    pub fn charge(&self, method: &str) {
+        if let Some(budget) = &self.budget {
+            budget.charge(method, Utc::now());
+        }
+    }
+
+    fn token_interval(&self) -> Duration {
+        Duration::from_micros(self.min_time_delta as u64)
+    }
+
+    /// Returns how long until we can send a request without going over
+    /// `max_per_second`. Zero if we can send one now.
+    pub fn token_wait(&self, now: Instant) -> Duration {
+        self.status
+            .bucket
+            .wait_time(now, self.token_interval(), self.burst)
+    }
+
+    /// Take a token from the RPC's bucket. Returns false if there are none left.
+    pub fn try_acquire_token(&self, now: Instant) -> bool {
+        self.status
+            .bucket
+            .try_acquire(now, self.token_interval(), self.burst)
+            .is_ok()
+    }
+
+    /// Called when we send the RPC a hedged request, and when that request answers first.
+    pub fn record_hedge(&self, won: bool) {
+        let counter = match won {
+            true => &self.status.hedge_wins,
+            false => &self.status.hedged,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The RPC is rate limiting us, so don't send it anything for `backoff`.
+    pub fn record_throttle(&self, backoff: Duration) {
+        self.status.throttled.fetch_add(1, Ordering::Relaxed);
+        self.status.bucket.pause(Instant::now(), backoff);
+        log_wrn!(
+            "{} is rate limiting us, backing off for {:?}.",
+            self.name,
+            backoff
+        );
+    }
+
+    /// Called by `pick` when this RPC gets selected, `spilled` if it
+    /// was only because RPCs in higher tiers weren't available.
+    pub fn record_pick(&self, now: Instant, now_micros: u128, spilled: bool) {
+        self.status.set_last_used(now_micros);
+        if spilled {
+            self.status.spilled.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Picking only does something else if we're probing the RPC
+        if self.status.breaker_closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let transition = {
+            let mut breaker = self.status.lock_breaker();
+            let transition = breaker.on_pick(now);
+            self.status.sync_breaker(&breaker);
+            transition
+        };
+        self.log_breaker_transition(transition);
+    }
+
+    fn log_breaker_transition(&self, transition: Option<BreakerState>) {
+        match transition {
+            Some(BreakerState::Open) => {
+                log_wrn!("{} circuit breaker opened, skipping it.", self.name);
+            }
+            Some(BreakerState::HalfOpen) => {
+                log_info!("{} circuit breaker half-open, probing.", self.name);
+            }
+            Some(BreakerState::Closed) => {
+                log_info!("{} circuit breaker closed.", self.name);
+            }
+            None => {}
+        }
+    }
+
     /// Update the latency of the last n calls.
     /// We don't do it within send_request because we might kill it if it times out.
-    pub fn update_latency(&mut self, latest: f64) {
-        // If we have data >= to ma_length, remove the first one in line
-        if self.status.latency_data.len() >= self.status.ma_length as usize {
-            self.status.latency_data.remove(0);
-        }
+    pub fn update_latency(&self, latest: f64) {
+        let mut latency_data = self
+            .status
+            .latency_data
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        // Store while holding the lock so concurrent updates can't go back in time
+        let mean = latency_data.push(latest);
+        self.status.latency[LatencyMetric::Mean as usize].store(mean.to_bits(), Ordering::Relaxed);
+        self.status.stale_percentiles.store(true, Ordering::Relaxed);
+    }
+}
 
-        // Update latency
-        self.status.latency_data.push(latest);
-        self.status.latency =
-            self.status.latency_data.iter().sum::<f64>() / self.status.latency_data.len() as f64;
+/// Decrements the in-flight counter of an `Rpc` when dropped.
+#[derive(Debug)]
+pub struct InFlightGuard(Arc<Status>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
@@ -260,9 +694,139 @@ pub fn hex_to_decimal(hex_string: &str) -> Result<u64, std::num::ParseIntError>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        balancer::test_utils::{
+            refused_url,
+            MockRpc,
+        },
+        rpc::{
+            breaker::BreakerSettings,
+            budget::BudgetWindow,
+        },
+    };
     use serde_json::json;
     use simd_json::serde::to_string;
 
+    #[test]
+    fn test_in_flight() {
+        let rpc = Rpc::default();
+        let clone = rpc.clone();
+
+        let first = rpc.track_in_flight();
+        let second = clone.track_in_flight();
+        assert_eq!(rpc.in_flight(), 2);
+
+        drop(first);
+        assert_eq!(clone.in_flight(), 1);
+        drop(second);
+        assert_eq!(rpc.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_try_consecutive_concurrent() {
+        let rpc = Rpc::default();
+
+        let claimed: usize = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| (0..100).filter(|_| rpc.status.try_consecutive(50)).count())
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .sum()
+        });
+        assert_eq!(claimed, 50);
+        assert_eq!(rpc.status.consecutive(), 50);
+    }
+
+    #[test]
+    fn test_try_claim() {
+        let rpc = Rpc::default();
+
+        assert!(rpc.status.try_claim(2, 100, 1000));
+        assert_eq!(rpc.status.last_used(), 1000);
+
+        // Too soon, so it doesn't count towards `max_consecutive` either
+        assert!(!rpc.status.try_claim(2, 100, 1050));
+        assert_eq!(rpc.status.consecutive(), 1);
+        assert_eq!(rpc.status.last_used(), 1000);
+
+        assert!(rpc.status.try_claim(2, 100, 1200));
+        assert!(!rpc.status.try_claim(2, 100, 5000));
+        assert_eq!(rpc.status.consecutive(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_request_charges_budget() {
+        let budget = Arc::new(ComputeBudget::new(
+            "test".to_string(),
+            1000,
+            BudgetWindow::Daily,
+            10,
+            [("eth_blockNumber".to_string(), 1)].into(),
+        ));
+
+        let upstream = MockRpc::fixed(r#"{"jsonrpc":"2.0","id":1,"result":"0x10"}"#).await;
+        let mut rpc = Rpc::new(upstream.url.clone(), None, 0, 0, 2.0);
+        rpc.budget = Some(budget.clone());
+
+        // Health checks are charged
+        assert_eq!(rpc.block_number().await.unwrap(), 16);
+        assert_eq!(budget.used(Utc::now()), 1);
+
+        // So are requests that never got an answer
+        let mut rpc = Rpc::new(refused_url().await, None, 0, 0, 2.0);
+        rpc.budget = Some(budget.clone());
+        assert!(rpc
+            .send_request(json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call"}))
+            .await
+            .is_err());
+        assert_eq!(budget.used(Utc::now()), 11);
+    }
+
+    #[test]
+    fn test_percentiles_on_read() {
+        let rpc = Rpc::new("http://localhost:8545".to_string(), None, 0, 0, 10.0);
+        for latency in [10.0, 20.0, 30.0] {
+            rpc.update_latency(latency);
+        }
+        assert_eq!(rpc.status.latency_of(LatencyMetric::P50), 20.0);
+
+        // Reads don't wait on whoever holds the samples, they get what was stored last
+        rpc.update_latency(100.0);
+        {
+            let _latency_data = rpc.status.latency_data.lock().unwrap();
+            assert_eq!(rpc.status.latency_of(LatencyMetric::P99), 30.0);
+        }
+        assert_eq!(rpc.status.latency_of(LatencyMetric::P99), 100.0);
+        assert_eq!(rpc.status.latency_stats().mean, 40.0);
+    }
+
+    #[test]
+    fn test_status_shared_between_clones() {
+        let rpc = Rpc::new("http://localhost:8545".to_string(), None, 0, 0, 2.0);
+        let clone = rpc.clone();
+
+        clone.update_latency(10.0);
+        clone.update_latency(20.0);
+        rpc.update_latency(40.0);
+        assert_eq!(clone.status.latency(), 30.0);
+        assert_eq!(rpc.status.latency_of(LatencyMetric::P90), 40.0);
+
+        rpc.record_failure(FailureClass::Timeout);
+        assert_eq!(clone.status.failures(), 1);
+        assert_eq!(clone.status.last_failure(), Some(FailureClass::Timeout));
+
+        // Breaker state is mirrored when it changes
+        for _ in 1..BreakerSettings::default().consecutive_failures {
+            clone.record_failure(FailureClass::Timeout);
+        }
+        assert_eq!(rpc.status.breaker_state(), BreakerState::Open);
+        assert!(!rpc.status.can_pick(Instant::now()));
+    }
+
     #[test]
     fn test_extract_sync_syncing() {
         let input = json!({
@@ -276,7 +840,7 @@ mod tests {
         });
         let input_str = to_string(&input).unwrap();
         let result = extract_sync(&input_str);
-        assert_eq!(result.unwrap(), true);
+        assert!(result.unwrap());
     }
 
     #[test]
@@ -288,7 +852,7 @@ mod tests {
         });
         let input_str = to_string(&input).unwrap();
         let result = extract_sync(&input_str);
-        assert_eq!(result.unwrap(), false);
+        assert!(!result.unwrap());
     }
 
     #[test]
@@ -1,16 +1,40 @@
 use crate::{
     balancer::{
+        coalesce::{
+            can_coalesce,
+            wait_for_leader,
+            Flight,
+        },
         format::replace_block_tags,
+        normalize::{
+            fill_default_params,
+            normalize_request,
+        },
         processing::{
+            cache_lookup,
             cache_querry,
             update_rpc_latency,
             CacheArgs,
         },
-        selection::select::pick,
+        response_errors::{
+            ErrorKind,
+            JsonRpcError,
+        },
+        selection::{
+            method_policy::RequestPolicy,
+            select::{
+                next_token_in,
+                pick,
+            },
+        },
     },
     log_err,
     log_info,
-    rpc::types::Rpc,
+    log_wrn,
+    rpc::types::{
+        InFlightGuard,
+        Rpc,
+    },
     websocket::{
         error::WsError,
         types::{
@@ -20,11 +44,14 @@ use crate::{
             WsconnMessage,
         },
     },
+    Settings,
 };
 
 use std::{
+    collections::HashMap,
     sync::{
         Arc,
+        Mutex,
         RwLock,
     },
     time::Instant,
@@ -35,14 +62,17 @@ use futures_util::{
     StreamExt,
 };
 use serde_json::Value;
-use simd_json::{
-    from_slice,
-    from_str,
-};
+use simd_json::from_str;
 
-use tokio::sync::{
-    broadcast,
-    mpsc,
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc,
+    },
+    time::{
+        sleep,
+        timeout,
+    },
 };
 use tokio_tungstenite::{
     connect_async,
@@ -65,6 +95,7 @@ pub async fn ws_conn_manager(
     mut incoming_rx: mpsc::UnboundedReceiver<WsconnMessage>,
     broadcast_tx: broadcast::Sender<IncomingResponse>,
     ws_error_tx: mpsc::UnboundedSender<WsChannelErr>,
+    config: Arc<RwLock<Settings>>,
 ) {
     // Initialize WebSocket connections
     update_ws_connections(&rpc_list, &ws_handles, &broadcast_tx, &ws_error_tx).await;
@@ -78,15 +109,24 @@ pub async fn ws_conn_manager(
                 handle_incoming_message(
                     &ws_handles,
                     &rpc_list,
+                    &broadcast_tx,
                     incoming,
                     specified_index,
                     &mut ws_buffer,
+                    &config,
                 )
                 .await;
             }
             WsconnMessage::Reconnect() => {
                 update_ws_connections(&rpc_list, &ws_handles, &broadcast_tx, &ws_error_tx).await;
-                unload_buffer(&rpc_list, &ws_handles, &mut ws_buffer).await;
+                unload_buffer(
+                    &rpc_list,
+                    &ws_handles,
+                    &broadcast_tx,
+                    &mut ws_buffer,
+                    &config,
+                )
+                .await;
             }
         }
     }
@@ -112,11 +152,22 @@ async fn update_ws_connections(
 async fn unload_buffer(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     ws_handles: &Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
+    broadcast_tx: &broadcast::Sender<IncomingResponse>,
     ws_buffer: &mut Vec<Value>,
+    config: &Arc<RwLock<Settings>>,
 ) {
     for i in 0..ws_buffer.len() {
         let incoming = ws_buffer[i].clone();
-        handle_incoming_message(ws_handles, rpc_list, incoming, None, ws_buffer).await;
+        handle_incoming_message(
+            ws_handles,
+            rpc_list,
+            broadcast_tx,
+            incoming,
+            None,
+            ws_buffer,
+            config,
+        )
+        .await;
     }
     ws_buffer.clear();
 }
@@ -124,25 +175,44 @@ async fn unload_buffer(
 /// Sends an incoming request to a WS connection.
 ///
 /// Indexes can be specified via the `specified_index` param.
+///
+/// If no RPC can take the request, the error gets sent back through
+/// `broadcast_tx` so whoever is waiting on it doesn't wait forever.
 async fn handle_incoming_message(
     ws_handles: &Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    broadcast_tx: &broadcast::Sender<IncomingResponse>,
     incoming: Value,
     specified_index: Option<usize>,
     ws_buffer: &mut Vec<Value>,
+    config: &Arc<RwLock<Settings>>,
 ) {
     let rpc_position = if let Some(index) = specified_index {
         index
     } else {
-        let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
+        let (strategy, rate_limit) = {
+            let config_guard = config.read().unwrap();
+            (
+                config_guard.selection.clone(),
+                config_guard.rate_limit.clone(),
+            )
+        };
+        let rpc_list_guard = rpc_list.read().unwrap_or_else(|e| {
             // Handle the case where the rpc_list RwLock is poisoned
             log_err!("handle_incoming_message poison: {}", e);
             e.into_inner()
         });
 
-        match pick(&mut rpc_list_guard).1 {
-            Some(position) => position,
-            None => {
+        match pick(
+            &rpc_list_guard,
+            strategy.as_ref(),
+            rate_limit.serving_tier.top(),
+        ) {
+            (rpc, Some(position)) => {
+                rate_limit.serving_tier.update(rpc.tier);
+                position
+            }
+            (_, None) => {
                 // Check if the incoming content is a subscription.
                 //
                 // We do this because we want to send it to a buffer
@@ -151,6 +221,17 @@ async fn handle_incoming_message(
                 {
                     println!("in none: {:?}", incoming);
                     ws_buffer.push(incoming);
+                } else {
+                    // Only rate limits are keeping us from sending it if an RPC gets a token later
+                    let kind = match next_token_in(&rpc_list_guard) {
+                        Some(_) => ErrorKind::RateLimited,
+                        None => ErrorKind::NoRpcAvailable,
+                    };
+                    // Not from any RPC, so it doesn't get a real node id
+                    let _ = broadcast_tx.send(IncomingResponse {
+                        node_id: usize::MAX,
+                        content: JsonRpcError::new(kind, incoming["id"].clone()).to_value(),
+                    });
                 }
                 log_err!("No RPC position available");
                 return;
@@ -217,6 +298,9 @@ pub async fn create_ws_vec(
 /// In case of an error where the connection is forced to close,
 /// a message will be sent via the `ws_error_tx` channel alerting
 /// the health check module.
+///
+/// Requests count as in flight on the RPC from when they're sent
+/// until a response with their id comes back, or the connection closes.
 pub async fn ws_conn(
     rpc: Rpc,
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
@@ -225,18 +309,30 @@ pub async fn ws_conn(
     ws_error_tx: mpsc::UnboundedSender<WsChannelErr>,
     index: usize,
 ) {
-    let (ws_stream, _) = connect_async(&rpc.ws_url.unwrap())
+    let (ws_stream, _) = connect_async(rpc.ws_url.as_deref().unwrap())
         .await
         .expect("Failed to connect to WS");
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
+    // Requests we haven't seen a response to yet, by id
+    let pending: Arc<Mutex<HashMap<String, InFlightGuard>>> = Default::default();
+
     // Thread for sending messages
     let sender_error_tx = ws_error_tx.clone();
+    let sender_pending = pending.clone();
     tokio::spawn(async move {
         while let Some(incoming) = incoming_rx.recv().await {
             #[cfg(feature = "debug-verbose")]
             println!("ws_conn[{}], send: {:?}", index, incoming);
 
+            // Charged like requests over HTTP, as they get sent
+            rpc.charge(incoming["method"].as_str().unwrap_or_default());
+            if !incoming["id"].is_null() {
+                sender_pending
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(incoming["id"].to_string(), rpc.track_in_flight());
+            }
             if ws_sender
                 .send(Message::Text(incoming.to_string()))
                 .await
@@ -266,7 +362,7 @@ pub async fn ws_conn(
                         }
                     };
 
-                    let rax = match unsafe { from_str(&mut ws_message) } {
+                    let rax: Value = match unsafe { from_str(&mut ws_message) } {
                         Ok(rax) => rax,
                         Err(_e) => {
                             #[cfg(feature = "debug-verbose")]
@@ -279,6 +375,12 @@ pub async fn ws_conn(
                         }
                     };
 
+                    // Subscription notifications don't have an id, so they never match
+                    pending
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .remove(&rax["id"].to_string());
+
                     let incoming = IncomingResponse {
                         node_id: index,
                         content: rax,
@@ -306,9 +408,10 @@ pub async fn execute_ws_call(
     mut call: Value,
     user_id: u32,
     incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
-    broadcast_rx: broadcast::Receiver<IncomingResponse>,
+    mut broadcast_rx: broadcast::Receiver<IncomingResponse>,
     sub_data: &Arc<SubscriptionData>,
     cache_args: &CacheArgs,
+    policy: RequestPolicy,
 ) -> Result<String, WsError> {
     #[cfg(feature = "debug-verbose")]
     println!(
@@ -317,19 +420,23 @@ pub async fn execute_ws_call(
     );
 
     let id = call["id"].take();
+
+    // Replace block tags if applicable, before hashing so we don't serve stale heads
+    fill_default_params(&mut call);
+    call = replace_block_tags(&mut call, &cache_args.named_numbers);
+    let canonical = normalize_request(&call).to_string();
     let tx_hash = {
         #[cfg(not(feature = "xxhash"))]
         {
-            hash(call.to_string().as_bytes())
+            hash(canonical.as_bytes())
         }
         #[cfg(feature = "xxhash")]
         {
-            xxh3_64(call.to_string().as_bytes())
+            xxh3_64(canonical.as_bytes())
         }
     };
 
-    if let Ok(Some(mut rax)) = cache_args.cache.get(tx_hash.as_bytes()) {
-        let mut cached: Value = from_slice(&mut rax).unwrap();
+    if let Ok(Some(mut cached)) = cache_lookup(&call, tx_hash, cache_args) {
         cached["id"] = id;
         return Ok(cached.to_string());
     }
@@ -340,20 +447,20 @@ pub async fn execute_ws_call(
         let subscription_id = match call["params"][0].as_str() {
             Some(subscription_id) => subscription_id.to_string(),
             None => {
-                return Ok(format!(
-                    "{{\"jsonrpc\":\"2.0\", \"id\":{}, \"error\": \"Bad Subscription ID!\"}}",
-                    id
-                ));
+                return Ok(JsonRpcError::new(ErrorKind::InvalidParams, id)
+                    .with_data("Bad Subscription ID!")
+                    .to_value()
+                    .to_string());
             }
         };
         // we have to get the id of the subsctiption and what node is subscribed and send the message
         let index = match sub_data.get_node_from_id(&subscription_id) {
             Some(rax) => Some(rax),
             None => {
-                return Ok(format!(
-                    "{{\"jsonrpc\":\"2.0\", \"id\":{}, \"error\": \"false\"}}",
-                    id
-                ));
+                return Ok(JsonRpcError::new(ErrorKind::InvalidParams, id)
+                    .with_data("Subscription does not exist!")
+                    .to_value()
+                    .to_string());
             }
         };
         println!("execute_ws_call: index: {:?}", index);
@@ -367,6 +474,7 @@ pub async fn execute_ws_call(
     }
 
     let is_subscription = call["method"] == "eth_subscribe";
+    let mut flight = None;
     if is_subscription {
         // Check if we're already subscribed to this
         // if so return the subscription id and add this user to the dispatch
@@ -378,14 +486,54 @@ pub async fn execute_ws_call(
                 id, rax
             ));
         }
-    } else {
-        // Replace block tags if applicable
-        call = replace_block_tags(&mut call, &cache_args.named_numbers);
+    } else if can_coalesce(
+        call["method"].as_str().unwrap_or_default(),
+        &cache_args.cache_rules,
+    ) {
+        // Identical requests that miss the cache at the same time only go upstream once
+        flight = loop {
+            match cache_args.in_flight.join(tx_hash) {
+                Flight::Leader(flight) => break Some(flight),
+                Flight::Follower(rx) => {
+                    if let Some(result) = wait_for_leader(rx, id.clone()).await {
+                        return Ok(result.unwrap_or_else(|err| err.to_value().to_string()));
+                    }
+                    // The leader gave up without a response, so try again ourselves
+                }
+            }
+        };
     }
 
     call["id"] = user_id.into();
-    incoming_tx.send(WsconnMessage::Message(call.clone(), None))?;
-    let mut response = listen_for_response(user_id, broadcast_rx).await?;
+    let sent_at = cache_args.latest_cache.head();
+
+    // Subscriptions are only ever sent once, since every resend would
+    // open another subscription upstream that we don't keep track of
+    let mut response = if is_subscription {
+        incoming_tx.send(WsconnMessage::Message(call.clone(), None))?;
+        listen_for_response(user_id, &mut broadcast_rx).await?
+    } else {
+        // Resend the call to the next RPC in line if we don't hear back in time
+        let mut retries = 0;
+        loop {
+            incoming_tx.send(WsconnMessage::Message(call.clone(), None))?;
+            match timeout(policy.ttl, listen_for_response(user_id, &mut broadcast_rx)).await {
+                Ok(response) => break response?,
+                Err(_) => {
+                    retries += 1;
+                    if retries >= policy.max_retries {
+                        let err = JsonRpcError::new(ErrorKind::TimedOut, id);
+                        if let Some(flight) = flight {
+                            flight.finish(&Err(err.clone()));
+                        }
+                        return Ok(err.to_value().to_string());
+                    }
+                    log_wrn!("WS request timed out, retrying.");
+                    sleep(policy.retry_delay(retries)).await;
+                }
+            }
+        }
+    };
 
     if is_subscription {
         #[cfg(feature = "debug-verbose")]
@@ -396,10 +544,10 @@ pub async fn execute_ws_call(
         let sub_id = match response.content["result"].as_str() {
             Some(sub_id) => sub_id.to_string(),
             None => {
-                return Ok(format!(
-                    "\"jsonrpc\":\"2.0\", \"id\":{}, \"error\": \"Bad Subscription ID!\"",
-                    id
-                ));
+                return Ok(JsonRpcError::new(ErrorKind::InternalError, id)
+                    .with_data("Bad Subscription ID!")
+                    .to_value()
+                    .to_string());
             }
         };
 
@@ -407,7 +555,17 @@ pub async fn execute_ws_call(
         sub_data.register_subscription(call.clone(), sub_id.clone(), response.node_id);
         sub_data.subscribe_user(user_id, call)?;
     } else {
-        cache_querry(&mut response.content.to_string(), call, tx_hash, cache_args);
+        cache_querry(
+            &mut response.content.to_string(),
+            call,
+            tx_hash,
+            cache_args,
+            &sent_at,
+        );
+    }
+
+    if let Some(flight) = flight {
+        flight.finish(&Ok(response.content.to_string()));
     }
 
     response.content["id"] = id;
@@ -417,7 +575,7 @@ pub async fn execute_ws_call(
 /// Listens for a respond corresponding to our internal `user_id`.
 async fn listen_for_response(
     user_id: u32,
-    mut broadcast_rx: broadcast::Receiver<IncomingResponse>,
+    broadcast_rx: &mut broadcast::Receiver<IncomingResponse>,
 ) -> Result<IncomingResponse, WsError> {
     while let Ok(response) = broadcast_rx.recv().await {
         if response.content["id"].as_u64().unwrap_or(u32::MAX.into()) as u32 == user_id {
@@ -445,7 +603,7 @@ mod tests {
     }
 
     async fn create_mock_rpc_list() -> Arc<RwLock<Vec<Rpc>>> {
-        let rpc_list = Arc::new(RwLock::new(vec![
+        Arc::new(RwLock::new(vec![
             Rpc::new(
                 "http://test1".to_string(),
                 Some("ws://test1".to_string()),
@@ -460,11 +618,11 @@ mod tests {
                 0,
                 0.0,
             ),
-        ]));
-        rpc_list
+        ]))
     }
 
     // Helper function to setup the environment for ws_conn_manager tests
+    #[allow(clippy::type_complexity)]
     fn setup_ws_conn_manager_test() -> (
         Arc<RwLock<Vec<Rpc>>>,
         mpsc::UnboundedSender<WsconnMessage>,
@@ -497,12 +655,16 @@ mod tests {
         let incoming = json!({"type": "test"});
         let mut ws_buffer: Vec<Value> = Vec::new();
 
+        let (broadcast_tx, _) = broadcast::channel(10);
+
         handle_incoming_message(
             &ws_handles,
             &rpc_list,
+            &broadcast_tx,
             incoming.clone(),
             Some(0),
             &mut ws_buffer,
+            &Arc::new(RwLock::new(Settings::default())),
         )
         .await;
 
@@ -511,6 +673,93 @@ mod tests {
         assert_eq!(received, Some(incoming));
     }
 
+    #[tokio::test]
+    async fn test_handle_incoming_message_no_rpc() {
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let ws_handles = Arc::new(RwLock::new(Vec::new()));
+        let (broadcast_tx, mut broadcast_rx) = broadcast::channel(10);
+        let mut ws_buffer: Vec<Value> = Vec::new();
+
+        // Calls get an error back instead of waiting forever
+        handle_incoming_message(
+            &ws_handles,
+            &rpc_list,
+            &broadcast_tx,
+            json!({"jsonrpc": "2.0", "id": 7, "method": "eth_blockNumber"}),
+            None,
+            &mut ws_buffer,
+            &Arc::new(RwLock::new(Settings::default())),
+        )
+        .await;
+        let response = listen_for_response(7, &mut broadcast_rx).await.unwrap();
+        assert_eq!(
+            response.content,
+            JsonRpcError::new(ErrorKind::NoRpcAvailable, json!(7)).to_value()
+        );
+
+        // Subscriptions wait in the buffer for an RPC to come back
+        let subscribe =
+            json!({"jsonrpc": "2.0", "id": 8, "method": "eth_subscribe", "params": ["newHeads"]});
+        handle_incoming_message(
+            &ws_handles,
+            &rpc_list,
+            &broadcast_tx,
+            subscribe.clone(),
+            None,
+            &mut ws_buffer,
+            &Arc::new(RwLock::new(Settings::default())),
+        )
+        .await;
+        assert_eq!(ws_buffer, vec![subscribe]);
+        assert!(broadcast_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ws_conn_in_flight() {
+        // WS RPC that answers every request after 200ms, sending a notification first
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(Message::Text(request))) = ws.next().await {
+                let request: Value = serde_json::from_str(&request).unwrap();
+                let notification = json!({"jsonrpc": "2.0", "method": "eth_subscription"});
+                ws.send(Message::Text(notification.to_string()))
+                    .await
+                    .unwrap();
+
+                sleep(Duration::from_millis(200)).await;
+                let response = json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"});
+                ws.send(Message::Text(response.to_string())).await.unwrap();
+            }
+        });
+
+        let rpc = mock_rpc(&addr.to_string());
+        let rpc_list = Arc::new(RwLock::new(vec![rpc.clone()]));
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let (broadcast_tx, mut broadcast_rx) = broadcast::channel(10);
+        let (ws_error_tx, _ws_error_rx) = mpsc::unbounded_channel();
+        ws_conn(
+            rpc.clone(),
+            rpc_list,
+            incoming_rx,
+            broadcast_tx,
+            ws_error_tx,
+            0,
+        )
+        .await;
+
+        incoming_tx
+            .send(json!({"jsonrpc": "2.0", "id": 3, "method": "eth_blockNumber"}))
+            .unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(rpc.in_flight(), 1);
+
+        listen_for_response(3, &mut broadcast_rx).await.unwrap();
+        assert_eq!(rpc.in_flight(), 0);
+    }
+
     #[tokio::test]
     async fn test_ws_conn_handling_error() {
         let (_rpc_list, incoming_tx, mut incoming_rx, _broadcast_tx, _ws_error_tx) =
@@ -568,6 +817,7 @@ mod tests {
             broadcast_rx.resubscribe(),
             &sub_data,
             &cache_args,
+            RequestPolicy::new(1000, 1),
         )
         .await;
 
@@ -601,8 +851,16 @@ mod tests {
             broadcast_tx.send(response).unwrap();
         });
 
-        let result =
-            execute_ws_call(call, 1, &incoming_tx, broadcast_rx, &sub_data, &cache_args).await;
+        let result = execute_ws_call(
+            call,
+            1,
+            &incoming_tx,
+            broadcast_rx,
+            &sub_data,
+            &cache_args,
+            RequestPolicy::new(1000, 1),
+        )
+        .await;
 
         assert!(result.is_ok());
         assert_eq!(
@@ -612,8 +870,58 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_listen_for_response() {
+    async fn test_execute_ws_subscription_sent_once() {
+        let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel();
         let (broadcast_tx, broadcast_rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let cache_args = CacheArgs::default();
+
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_subscribe",
+            "params": ["newHeads"]
+        });
+
+        // Answer way past the ttl of the policy
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let response = IncomingResponse {
+                content: json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "result": "0x1a2b3c"
+                }),
+                node_id: 0,
+            };
+            broadcast_tx.send(response).unwrap();
+        });
+
+        let result = execute_ws_call(
+            call,
+            1,
+            &incoming_tx,
+            broadcast_rx,
+            &sub_data,
+            &cache_args,
+            RequestPolicy::new(10, 32),
+        )
+        .await;
+        assert_eq!(
+            result.unwrap(),
+            "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"0x1a2b3c\"}"
+        );
+
+        let mut sent = 0;
+        while incoming_rx.try_recv().is_ok() {
+            sent += 1;
+        }
+        assert_eq!(sent, 1);
+    }
+
+    #[tokio::test]
+    async fn test_listen_for_response() {
+        let (broadcast_tx, mut broadcast_rx) = broadcast::channel(10);
 
         // Simulate a response
         tokio::spawn(async move {
@@ -629,7 +937,7 @@ mod tests {
             broadcast_tx.send(response).unwrap();
         });
 
-        let result = listen_for_response(1, broadcast_rx).await;
+        let result = listen_for_response(1, &mut broadcast_rx).await;
         assert!(result.is_ok());
         assert_eq!(
             result.unwrap().content,
@@ -1,7 +1,17 @@
-use std::sync::Arc;
+use std::sync::{
+    Arc,
+    RwLock,
+};
 
 use crate::{
-    balancer::processing::CacheArgs,
+    balancer::{
+        format::str_to_value,
+        processing::CacheArgs,
+        response_errors::{
+            ErrorKind,
+            JsonRpcError,
+        },
+    },
     log_info,
     websocket::{
         client::execute_ws_call,
@@ -13,17 +23,17 @@ use crate::{
             WsconnMessage,
         },
     },
+    Settings,
 };
 
 use rand::random;
+use serde_json::Value;
 
 use tokio::sync::{
     broadcast,
     mpsc,
 };
 
-use simd_json::from_str;
-
 use futures::{
     sink::SinkExt,
     stream::StreamExt,
@@ -42,6 +52,7 @@ pub async fn serve_websocket(
     outgoing_rx: broadcast::Receiver<IncomingResponse>,
     sub_data: Arc<SubscriptionData>,
     cache_args: CacheArgs,
+    config: Arc<RwLock<Settings>>,
 ) -> Result<(), WsError> {
     let websocket = websocket.await?;
 
@@ -71,6 +82,13 @@ pub async fn serve_websocket(
             // If we received a subscription, just send it to the client
             match msg {
                 RequestResult::Call(call) => {
+                    // Notifications get forwarded, but must not be answered
+                    let id = call.get("id").cloned();
+                    let policy = config
+                        .read()
+                        .unwrap()
+                        .request_policy(call["method"].as_str().unwrap_or_default());
+
                     let resp = match execute_ws_call(
                         call,
                         user_id,
@@ -78,13 +96,26 @@ pub async fn serve_websocket(
                         outgoing_rx.resubscribe(),
                         &sub_data_clone,
                         &cache_args,
+                        policy,
                     )
                     .await
                     {
                         Ok(rax) => rax,
-                        Err(e) => format!("{{\"error\": \"{}\"}}", e),
+                        Err(e) => {
+                            JsonRpcError::new(
+                                ErrorKind::InternalError,
+                                id.clone().unwrap_or(Value::Null),
+                            )
+                            .with_data(e.to_string())
+                            .to_value()
+                            .to_string()
+                        }
                     };
 
+                    if id.is_none() {
+                        continue;
+                    }
+
                     match websocket_sink.send(Message::text::<String>(resp)).await {
                         Ok(_) => {}
                         Err(e) => {
@@ -115,12 +146,12 @@ pub async fn serve_websocket(
 
     while let Some(message) = websocket_stream.next().await {
         match message {
-            Ok(Message::Text(mut msg)) => {
+            Ok(Message::Text(msg)) => {
                 log_info!("Received WS text message: {}", msg);
                 // Send message to the channel
-                let rax = match unsafe { from_str(&mut msg) } {
-                    Ok(rax) => rax,
-                    Err(_) => continue,
+                let rax = match str_to_value(&msg) {
+                    Some(rax) => rax,
+                    None => continue,
                 };
 
                 tx.send(RequestResult::Call(rax)).unwrap();
@@ -242,8 +242,8 @@ impl SubscriptionData {
             .unwrap_or_else(|e| e.into_inner());
 
         incoming_subscriptions
-            .iter()
-            .filter_map(|(_, node_sub_info)| {
+            .values()
+            .filter_map(|node_sub_info| {
                 if node_sub_info.node_id == node_id {
                     Some(node_sub_info.subscription_id.to_owned())
                 } else {
@@ -809,8 +809,8 @@ mod tests {
         // Ensure there are no subscribers to the moved subscription
         let subscriptions = subscription_data.subscriptions.read().unwrap();
         assert!(
-            subscriptions.get(&node_sub_info).is_none()
-                || subscriptions.get(&node_sub_info).unwrap().is_empty()
+            subscriptions.get(node_sub_info).is_none()
+                || subscriptions.get(node_sub_info).unwrap().is_empty()
         );
     }
 